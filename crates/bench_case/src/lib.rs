@@ -0,0 +1,59 @@
+use proc_macro::{TokenStream, TokenTree};
+
+/*
+ * The #[bench_case] attribute of the kernel.
+ * The custom test framework only collects items marked with #[test_case],
+ * thus the attribute registers a static perf_kernel::bench::BenchCase
+ * next to the annotated function. The test runner tells benchmarks and
+ * tests apart through the Testable trait.
+ * Like #[test_case] the function only exists in test builds.
+ *
+ * #[bench_case]
+ * fn alloc_small(bench: Bench) -> Result<Report, PmuError> {
+ *     bench.run(|| Box::new(0u64))
+ * }
+ */
+
+/// Name of the function an item defines
+fn function_name(item: &TokenStream) -> Option<String> {
+    let mut tokens = item.clone().into_iter();
+    while let Some(token) = tokens.next() {
+        if let TokenTree::Ident(ident) = token {
+            if ident.to_string() == "fn" {
+                return match tokens.next() {
+                    Some(TokenTree::Ident(name)) => Some(name.to_string()),
+                    _ => None,
+                };
+            }
+        }
+    }
+    None
+}
+
+fn error(msg: &str) -> TokenStream {
+    format!("compile_error!({:?});", msg).parse().unwrap()
+}
+
+#[proc_macro_attribute]
+pub fn bench_case(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return error("#[bench_case] takes no arguments");
+    }
+    let name = match function_name(&item) {
+        Some(name) => name,
+        None => return error("#[bench_case] only works on functions"),
+    };
+
+    let case = format!(
+        "#[cfg(test)]
+        #[test_case]
+        static __BENCH_CASE_{upper}: ::perf_kernel::bench::BenchCase =
+            ::perf_kernel::bench::BenchCase::new(concat!(module_path!(), \"::\", \"{name}\"), {name});",
+        upper = name.to_uppercase(),
+        name = name
+    );
+    let mut out: TokenStream = "#[cfg(test)]".parse().unwrap();
+    out.extend(item);
+    out.extend(case.parse::<TokenStream>().unwrap());
+    out
+}
@@ -137,8 +137,10 @@ impl LapicIter {
     } // end fn init
 }
 
+/// Returns the xAPIC and x2APIC entries of the MADT.
+/// xAPIC entries are converted into the x2APIC layout.
 impl Iterator for LapicIter {
-    type Item = LocalApic;
+    type Item = LocalX2Apic;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
@@ -181,7 +183,7 @@ impl Iterator for LapicIter {
                     // a valid APIC
                     if (lapic.flags & APIC_ENABLED) != 0 || (lapic.flags & APIC_ONLINE_CAPABLE) != 0
                     {
-                        return Some(lapic);
+                        return Some(lapic.into());
                     }
                 }
                 // x2apic entry
@@ -191,13 +193,13 @@ impl Iterator for LapicIter {
                     }
 
                     // Read the struct
-                    let lapic: LocalApic = unsafe { read_phys(self.current) };
+                    let lapic: LocalX2Apic = unsafe { read_phys(self.current) };
                     // Go to the next ICS entry
                     self.current += len as u32;
+                    let flags = lapic.flags;
                     // If the processor is enabled, or can be enabled, log it as
                     // a valid APIC
-                    if (lapic.flags & APIC_ENABLED) != 0 || (lapic.flags & APIC_ONLINE_CAPABLE) != 0
-                    {
+                    if (flags & APIC_ENABLED) != 0 || (flags & APIC_ONLINE_CAPABLE) != 0 {
                         return Some(lapic);
                     }
                 }
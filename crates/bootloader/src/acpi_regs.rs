@@ -77,6 +77,37 @@ impl fmt::Debug for LocalApic {
     }
 }
 
+/// MADT type 9 entry, used for apic ids that do not fit into 8 bits
+#[derive(Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct LocalX2Apic {
+    pub typ: u8,
+    pub length: u8,
+    pub res0: u16,
+    pub id: u32,
+    pub flags: u32,
+    pub processor_uid: u32,
+}
+
+impl From<LocalApic> for LocalX2Apic {
+    fn from(lapic: LocalApic) -> Self {
+        LocalX2Apic {
+            typ: lapic.typ,
+            length: lapic.length,
+            res0: 0,
+            id: lapic.id as u32,
+            flags: lapic.flags,
+            processor_uid: lapic.processor_uid as u32,
+        }
+    }
+}
+
+impl fmt::Debug for LocalX2Apic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe { write!(f, "LApic id: {}", read_unaligned(addr_of!(self.id))) }
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct IntOverride {
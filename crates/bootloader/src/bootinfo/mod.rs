@@ -40,6 +40,12 @@ pub struct BootInfo {
     pub cores: Cores,
     /// The amount of physical memory available in bytes
     pub max_phys_memory: u64,
+    /// Start of the kernel ELF image, including its symbol table
+    pub kernel_image_addr: u32,
+    /// Size of the kernel ELF image in bytes
+    pub kernel_image_size: u32,
+    /// Command line of the multiboot2 information
+    pub command_line: CommandLine,
 }
 
 impl BootInfo {
@@ -59,14 +65,56 @@ impl BootInfo {
             kernel_entry_addr: 0,
             physical_memory_offset,
             cores: Cores::empty(),
+            kernel_image_addr: 0,
+            kernel_image_size: 0,
+            command_line: CommandLine::empty(),
         }
     }
 }
 
+/// Maximum length of the kernel command line in bytes
+pub const COMMAND_LINE_SIZE: usize = 255;
+
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+pub struct CommandLine {
+    bytes: [u8; COMMAND_LINE_SIZE],
+    len: u8,
+}
+
+impl CommandLine {
+    pub const fn empty() -> Self {
+        Self {
+            bytes: [0; COMMAND_LINE_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Stores `line`, cut off at the last character that fits
+    pub fn set(&mut self, line: &str) {
+        let mut len = core::cmp::min(line.len(), COMMAND_LINE_SIZE);
+        while !line.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.bytes[..len].copy_from_slice(&line.as_bytes()[..len]);
+        self.len = len as u8;
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("")
+    }
+}
+
+impl fmt::Debug for CommandLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
 #[derive(Copy, Clone)]
 #[repr(C, packed)]
 pub struct Cores {
-    cores: [Core; 256],
+    cores: [Core; crate::MAX_CORES],
     pub num_booted_cores: u16,
     pub num_cores: u32,
 }
@@ -74,15 +122,15 @@ pub struct Cores {
 impl Cores {
     pub const fn empty() -> Self {
         Self {
-            cores: [Core::empty(); 256],
+            cores: [Core::empty(); crate::MAX_CORES],
             num_cores: 0,
             num_booted_cores: 0,
         }
     }
 
-    pub fn get_by_apic_id(&self, id: u8) -> Option<(&Core, usize)> {
+    pub fn get_by_apic_id(&self, id: u32) -> Option<(&Core, usize)> {
         for (i, core) in self.cores.iter().take(self.num_cores as usize).enumerate() {
-            if core.apic_id == id.into() {
+            if core.apic_id == id {
                 return Some((core, i));
             }
         }
@@ -115,7 +163,8 @@ impl fmt::Debug for Cores {
 #[derive(Copy, Clone, PartialEq, Eq)]
 #[repr(C, packed)]
 pub struct Core {
-    apic_id: u16,
+    /// x2apic id, fits into 8 bits in xAPIC mode
+    apic_id: u32,
     /// Start address of stack for physical core
     stack_start_addr: u32,
     /// End address of stack for physical core
@@ -151,7 +200,7 @@ impl TSS {
 impl Core {
     pub const fn empty() -> Self {
         Self {
-            apic_id: u16::MAX,
+            apic_id: u32::MAX,
             stack_start_addr: 0,
             stack_end_addr: 0,
             tss: TSS {
@@ -161,15 +210,15 @@ impl Core {
         }
     }
 
-    pub fn set_apic_id(&mut self, addr: u8) {
-        self.apic_id = addr as u16;
+    pub fn set_apic_id(&mut self, id: u32) {
+        self.apic_id = id;
     }
 
-    pub fn get_apic_id(&self) -> Option<u8> {
-        if self.apic_id == u16::MAX {
+    pub fn get_apic_id(&self) -> Option<u32> {
+        if self.apic_id == u32::MAX {
             None
         } else {
-            Some(self.apic_id as u8)
+            Some(self.apic_id)
         }
     }
 
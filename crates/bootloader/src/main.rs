@@ -28,6 +28,9 @@ pub static STACK_SIZE: usize = core::mem::size_of::<StackT>();
 #[no_mangle]
 pub static mut STACK_ARRAY: StackT = [0; 0x1000 * 15];
 
+/// Machines with more cores get 2Mb instead of 8Mb kernel stacks
+const BIG_STACK_MAX_CORES: usize = 128;
+
 /*
  * Important: The variables defined below are NOT pointers
  * to the section but usize slices of the section data itself.
@@ -147,6 +150,11 @@ unsafe extern "C" fn bootloader_main(magic: u32, mboot2_info_ptr: u32) {
     log::info!("name: {}", parsed_multiboot_headers.boot_loader_name_tag().unwrap().name() );
     log::info!("cmd: {}", parsed_multiboot_headers.command_line_tag().unwrap().command_line());
 
+    // Pass the command line on to the kernel
+    if let Some(tag) = parsed_multiboot_headers.command_line_tag() {
+        BOOT_INFO.command_line.set(tag.command_line());
+    }
+
    for i in parsed_multiboot_headers.module_tags() {
        log::info!("boot module cmdline {}", i.cmdline());
    }
@@ -382,18 +390,30 @@ unsafe extern "C" fn bootloader_main(magic: u32, mboot2_info_ptr: u32) {
     // also id maps vga address to uncachable
     mmu::remap_first_2mb_with_4kb(&_p3, &_p1_tables_start, &BOOT_INFO);
 
-    // Allocate 8Mb stack space for every core
+    // Allocate 8Mb stack space for every core, 2Mb on big machines
     // + 2 mb guard page at the end
     {
         use core::convert::TryFrom;
+        // Generates an iterator to get Lapic structs on every next() call
+        let lapic_iter = acpi::LapicIter::new().expect("Couldn't find acpi table");
+        let num_lapics = acpi::LapicIter::new().unwrap().count();
+        if num_lapics > bootloader::MAX_CORES {
+            panic!(
+                "CPU has more then {} cores. Recompile with different MAX_CORES constant",
+                bootloader::MAX_CORES
+            );
+        }
+
+        // All stacks have to fit below 4Gb, big machines only get 2Mb per core
         let allocator = pagetable::BootInfoFrameAllocator::new(&BOOT_INFO.memory_map);
-        let stack_size = bootloader::TWO_MEG * 4;
+        let stack_size = if num_lapics > BIG_STACK_MAX_CORES {
+            bootloader::TWO_MEG
+        } else {
+            bootloader::TWO_MEG * 4
+        };
         let guard_page = bootloader::TWO_MEG;
         let mut iter = allocator.usable_xsize_frames(stack_size + guard_page, bootloader::TWO_MEG);
 
-        // Generates an iterator to get Lapic structs on every next() call
-        let lapic_iter = acpi::LapicIter::new().expect("Couldn't find acpi table");
-
         for (i, lapic) in lapic_iter.enumerate() {
             BOOT_INFO.cores.num_cores += 1;
 
@@ -403,13 +423,14 @@ unsafe extern "C" fn bootloader_main(magic: u32, mboot2_info_ptr: u32) {
             let stack_start = addr + stack_size + guard_page;
             BOOT_INFO.cores[i as usize].set_stack_start(stack_start.try_into().unwrap());
             BOOT_INFO.cores[i as usize].stack_end_addr = (addr + guard_page).try_into().unwrap();
-            BOOT_INFO.cores[i as usize].set_apic_id(lapic.id);
+            let apic_id = lapic.id;
+            BOOT_INFO.cores[i as usize].set_apic_id(apic_id);
             log::debug!(
                 "Core {} stack space from: {:#x} to {:#x} with apic id: {}",
                 i,
                 addr + guard_page,
                 stack_start,
-                lapic.id
+                apic_id
             );
 
             // Set 2Mb guard page to readable with NX bit set
@@ -446,13 +467,6 @@ unsafe extern "C" fn bootloader_main(magic: u32, mboot2_info_ptr: u32) {
         }
     }
 
-    if BOOT_INFO.cores.num_cores > bootloader::MAX_CORES.try_into().unwrap() {
-        panic!(
-            "CPU has more then {} cores. Recompile with different MAX_CORES constant",
-            bootloader::MAX_CORES
-        );
-    }
-
     if BOOT_INFO.cores.num_cores == 0 {
         panic!("Invalid value zero for MAX_CORES constant");
     }
@@ -594,6 +608,11 @@ unsafe extern "C" fn bootloader_main(magic: u32, mboot2_info_ptr: u32) {
     // Save entry point to BOOT_INFO
     BOOT_INFO.kernel_entry_addr = entry_addr;
 
+    // The kernel resolves symbols for stack traces from its own ELF image
+    BOOT_INFO.kernel_image_addr = &__kernel_start as *const _ as u32;
+    BOOT_INFO.kernel_image_size =
+        (&__kernel_end as *const _ as u32) - (&__kernel_start as *const _ as u32);
+
     log::debug!("Switching to long mode...");
 
     // Switch to long mode and jump to kernel entry point
@@ -15,10 +15,18 @@ extern "C" {
     ) -> !;
 }
 
-pub fn apic_id() -> u8 {
+/// Returns the x2apic id if cpuid leaf 0xb exists, else the 8 bit apic id
+pub fn apic_id() -> u32 {
     unsafe {
+        if __cpuid(0x0000_0000).eax >= 0x0000_000b {
+            let res = __cpuid(0x0000_000b);
+            // Leaf 0xb is only valid if ebx is non zero
+            if res.ebx != 0 {
+                return res.edx;
+            }
+        }
         let res = __cpuid(0x0000_0001);
-        (res.ebx >> 24) as u8
+        res.ebx >> 24
     }
 }
 
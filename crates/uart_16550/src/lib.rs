@@ -184,6 +184,15 @@ impl SerialPort {
         }
     }
 
+    /// Sends a byte on the serial port without translating backspace
+    /// and delete, for binary data.
+    pub fn send_raw(&mut self, data: u8) {
+        unsafe {
+            wait_for!(self.line_sts().contains(LineStsFlags::OUTPUT_EMPTY));
+            self.data.write(data);
+        }
+    }
+
     pub fn read(&mut self) -> u8 {
         unsafe {
         return self.data.read();
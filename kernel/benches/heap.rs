@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(bench_black_box)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, realloc, Layout};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::hint::black_box;
+use core::panic::PanicInfo;
+use perf_kernel::bench::{bench_case, Bench, Report};
+use perf_kernel::pmu::{self, Event, PmuError};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+/// Counted if the machine has performance counters
+fn events() -> &'static [Event] {
+    if pmu::is_available() {
+        &[Event::Instructions, Event::DtlbMisses]
+    } else {
+        &[]
+    }
+}
+
+#[bench_case]
+fn box_u64(bench: Bench) -> Result<Report, PmuError> {
+    bench.counters(events()).run(|| Box::new(black_box(0u64)))
+}
+
+#[bench_case]
+fn vec_resize(bench: Bench) -> Result<Report, PmuError> {
+    bench.counters(events()).run(|| {
+        let mut vec: Vec<usize> = Vec::new();
+        let mut i = 0;
+        vec.resize_with(0x10000, || {
+            i += 1;
+            i
+        });
+        vec.iter().sum::<usize>()
+    })
+}
+
+#[bench_case]
+fn vec_with_capacity(bench: Bench) -> Result<Report, PmuError> {
+    bench
+        .counters(events())
+        .run(|| Vec::<usize>::with_capacity(black_box(0x10000)))
+}
+
+#[bench_case]
+fn realloc_copy_grow(bench: Bench) -> Result<Report, PmuError> {
+    let layout = Layout::from_size_align(32, 16).unwrap();
+    let grown = Layout::from_size_align(640, 16).unwrap();
+    bench.counters(events()).run(|| unsafe {
+        let ptr = alloc(layout);
+        let new_ptr = realloc(black_box(ptr), layout, grown.size());
+        dealloc(new_ptr, grown);
+    })
+}
@@ -19,12 +19,14 @@ pub unsafe fn init() -> &'static Acpi {
 }
 
 pub struct Acpi {
-    pub apics: Option<Vec<LocalApic>>,
+    /// xAPIC entries are converted into the x2APIC layout
+    pub apics: Option<Vec<LocalX2Apic>>,
     pub ioapics: Option<Vec<IoApic>>,
     pub int_overrides: Option<Vec<IntOverride>>,
     pub nmis: Option<Vec<NonMaskableInts>>,
     pub apic_domains: Option<BTreeMap<u32, u32>>,
     pub memory_domains: Option<BTreeMap<u32, RangeSet>>,
+    pub hpet: Option<Hpet>,
     pub mask_pics: bool,
 }
 
@@ -37,6 +39,7 @@ impl fmt::Debug for Acpi {
         writeln!(f, "non maskable ints: {:?}", self.nmis).unwrap();
         writeln!(f, "apic domains: {:?}", self.apic_domains).unwrap();
         writeln!(f, "memory domains: {:?}", self.memory_domains).unwrap();
+        writeln!(f, "hpet: {:?}", self.hpet).unwrap();
         writeln!(f, "mask pics: {:?}", self.mask_pics)
     }
 }
@@ -51,6 +54,7 @@ impl Acpi {
             apic_domains: None,
             nmis: None,
             memory_domains: None,
+            hpet: None,
         }
     }
 
@@ -195,6 +199,14 @@ impl Acpi {
                 let (ad, md) = self.parse_srat(PhysAddr::new(table_ptr as u64));
                 self.apic_domains = Some(ad);
                 self.memory_domains = Some(md);
+
+            // Parse HPET
+            } else if &signature == b"HPET" {
+                if self.hpet.is_some() {
+                    log::warn!("Multiple HPET entries, only using the first one");
+                    continue;
+                }
+                self.hpet = Some(self.parse_hpet(PhysAddr::new(table_ptr as u64)));
             }
         } // enf for rsdt_entries
 
@@ -207,7 +219,7 @@ impl Acpi {
         &self,
         ptr: PhysAddr,
     ) -> (
-        Vec<LocalApic>,
+        Vec<LocalX2Apic>,
         Vec<IoApic>,
         Vec<IntOverride>,
         Vec<NonMaskableInts>,
@@ -270,7 +282,7 @@ impl Acpi {
                     // a valid APIC
                     if (lapic.flags & APIC_ENABLED) != 0 || (lapic.flags & APIC_ONLINE_CAPABLE) != 0
                     {
-                        lapics.push(lapic);
+                        lapics.push(lapic.into());
                     }
                 }
                 // I/O APIC
@@ -298,10 +310,9 @@ impl Acpi {
 
                     let int_override: IntOverride = read_phys(ics);
 
-                    // Filter out identity mappings
-                    if int_override.source as u32 != int_override.mapped_to {
-                        int_overrides.push(int_override);
-                    }
+                    // Keep identity mappings, they can still change
+                    // the polarity and trigger mode
+                    int_overrides.push(int_override);
                 }
                 // x2apic entry
                 9 => {
@@ -310,12 +321,12 @@ impl Acpi {
                     }
 
                     // Read the struct
-                    let lapic: LocalApic = read_phys(ics);
+                    let lapic: LocalX2Apic = read_phys(ics);
+                    let flags = lapic.flags;
 
                     // If the processor is enabled, or can be enabled, log it as
                     // a valid APIC
-                    if (lapic.flags & APIC_ENABLED) != 0 || (lapic.flags & APIC_ONLINE_CAPABLE) != 0
-                    {
+                    if (flags & APIC_ENABLED) != 0 || (flags & APIC_ONLINE_CAPABLE) != 0 {
                         lapics.push(lapic);
                     }
                 }
@@ -330,6 +341,17 @@ impl Acpi {
         (lapics, ioapcis, int_overrides, nmis, mask_pics)
     } // end function
 
+    /// Parse the HPET description table
+    unsafe fn parse_hpet(&self, ptr: PhysAddr) -> Hpet {
+        let (_header, payload, size) = self.parse_header(ptr);
+
+        if size < size_of::<Hpet>() {
+            panic!("Invalid HPET table size");
+        }
+
+        read_phys(payload)
+    }
+
     unsafe fn parse_srat(&self, ptr: PhysAddr) -> (BTreeMap<u32, u32>, BTreeMap<u32, RangeSet>) {
         // Parse the SRAT header
         let (_header, payload, size) = self.parse_header(ptr);
@@ -38,6 +38,44 @@ pub struct Header {
     pub creator_revision: u32,
 }
 
+/// In-memory representation of an ACPI generic address structure
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    /// 0 system memory, 1 system I/O
+    pub address_space_id: u8,
+    pub register_bit_width: u8,
+    pub register_bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+/// In-memory representation of the HPET description table
+/// without the ACPI header
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct Hpet {
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    pub min_tick: u16,
+    pub page_protection: u8,
+}
+
+impl fmt::Debug for Hpet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe {
+            write!(
+                f,
+                "Hpet number: {} address: {:#x} min tick: {}",
+                self.hpet_number,
+                read_unaligned(addr_of!(self.base_address.address)),
+                read_unaligned(addr_of!(self.min_tick))
+            )
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct IoApic {
@@ -77,6 +115,37 @@ impl fmt::Debug for LocalApic {
     }
 }
 
+/// MADT type 9 entry, used for apic ids that do not fit into 8 bits
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct LocalX2Apic {
+    pub typ: u8,
+    pub length: u8,
+    pub res0: u16,
+    pub id: u32,
+    pub flags: u32,
+    pub processor_uid: u32,
+}
+
+impl From<LocalApic> for LocalX2Apic {
+    fn from(lapic: LocalApic) -> Self {
+        LocalX2Apic {
+            typ: lapic.typ,
+            length: lapic.length,
+            res0: 0,
+            id: lapic.id as u32,
+            flags: lapic.flags,
+            processor_uid: lapic.processor_uid as u32,
+        }
+    }
+}
+
+impl fmt::Debug for LocalX2Apic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe { write!(f, "LApic id: {}", read_unaligned(addr_of!(self.id))) }
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct IntOverride {
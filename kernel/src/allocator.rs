@@ -1,50 +1,232 @@
+use crate::memory;
+use crate::numa::NumaFrameAllocator;
+use crate::percpu;
+use core::sync::atomic::Ordering;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size2MiB, Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageSize,
+        PageTableFlags, Size2MiB, Size4KiB,
     },
     VirtAddr,
 };
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 1000 * 1024; // 2Mib
-                                          // TODO: Bug arises when all 4KiB pages are exausted and then 4KiB pages are allocated in 2MiB
-                                          // pages? (i didn't understand it fully)
+/* Kernel heap
+ * A large virtual range starting at HEAP_START is reserved for the heap,
+ * but only HEAP_SIZE bytes get mapped at boot. Whenever the allocator
+ * runs out of pages it maps further 2MiB pages behind the current end.
+ * If no physical memory is left the pages mapped for the allocation so
+ * far are released again and the allocation fails with a null pointer,
+ * which fallible APIs like Vec::try_reserve report as an error.
+ * The numa node heaps grow the same way in their own ranges behind
+ * the kernel heap, see numa.rs.
+ */
 
-pub mod fixed_size_block;
+pub const HEAP_START: usize = 0x_4444_4440_0000;
 
-use fixed_size_block::FixedSizeBlockAllocator;
+/// Size of the heap mapped at boot
+pub const HEAP_SIZE: usize = 2 * 1024 * 1024; // 2Mib
+
+/// Size of the virtual range reserved for the heap
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024 * 1024; // 64Gib
+
+pub mod slab;
+
+use slab::{Backing, SlabAllocator};
 #[global_allocator]
-pub static ALLOCATOR: Locked<FixedSizeBlockAllocator> =
-    Locked::new(FixedSizeBlockAllocator::new(HEAP_START));
+pub static ALLOCATOR: SlabAllocator<HeapRange> =
+    SlabAllocator::new(HeapRange::new(HEAP_START, HEAP_MAX_SIZE, None));
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocation error: {:?}", layout)
+    panic!(
+        "allocation error: {:?}, heap size: {:#x}, bytes allocated: {:#x}",
+        layout,
+        heap_size(),
+        ALLOCATOR.num_bytes_allocated()
+    )
 }
 
-pub fn init_heap(
+/// Number of bytes currently mapped for the heap
+pub fn heap_size() -> usize {
+    ALLOCATOR.backing().size()
+}
+
+/// Number of heap allocations and deallocations done by all cores
+pub fn num_allocs() -> (usize, usize) {
+    (0..percpu::num_cores())
+        .map(percpu::get_by_core_index)
+        .fold((0, 0), |(allocs, deallocs), block| {
+            (
+                allocs + block.num_allocs.load(Ordering::Relaxed),
+                deallocs + block.num_deallocs.load(Ordering::Relaxed),
+            )
+        })
+}
+
+/// Virtual range of a heap that gets mapped in 2MiB steps
+pub struct HeapRange {
+    start: usize,
+    max_size: usize,
+    /// End of the mapped part
+    end: spin::Mutex<usize>,
+    /// Numa node the frames are taken from, None takes the local node
+    node: Option<usize>,
+}
+
+impl HeapRange {
+    pub const fn new(start: usize, max_size: usize, node: Option<usize>) -> Self {
+        HeapRange {
+            start,
+            max_size,
+            end: spin::Mutex::new(start),
+            node,
+        }
+    }
+
+    /// Number of bytes currently mapped
+    pub fn size(&self) -> usize {
+        x86_64::instructions::interrupts::without_interrupts(|| *self.end.lock() - self.start)
+    }
+
+    /// Maps enough 2MiB pages behind the end to fit `min_size` bytes.
+    /// Returns the newly mapped range. If memory runs out the pages
+    /// mapped so far get released and the range is empty.
+    fn map(
+        &self,
+        mapper: &mut impl Mapper<Size2MiB>,
+        frame_allocator: &mut NumaFrameAllocator,
+        min_size: usize,
+    ) -> (usize, usize) {
+        let mut end = self.end.lock();
+        let start = *end;
+        let step = Size2MiB::SIZE as usize;
+        let size = (min_size + step - 1) / step * step;
+        if start + size > self.start + self.max_size {
+            log::error!("Heap exceeds its reserved range");
+            return (start, 0);
+        }
+
+        let mut mapped = 0;
+        while mapped < size {
+            let res = match self.node {
+                Some(node) => map_heap_pages(
+                    mapper,
+                    &mut frame_allocator.on_node(node),
+                    start + mapped,
+                    step,
+                ),
+                None => map_heap_pages(mapper, frame_allocator, start + mapped, step),
+            };
+            if let Err(err) = res {
+                log::error!("Failed to grow heap: {:?}", err);
+                break;
+            }
+            mapped += step;
+        }
+        if mapped < size {
+            unmap_heap_pages(mapper, frame_allocator, start, mapped);
+            mapped = 0;
+        }
+        *end = start + mapped;
+        (start, mapped)
+    }
+}
+
+impl Backing for HeapRange {
+    /// Called by the allocator with interrupts disabled when it runs out of pages.
+    /// Fails instead of waiting if the current core holds the page table or
+    /// the frame allocator already, it would wait for itself forever.
+    /// Locks held by other cores are waited for.
+    fn grow(&self, min_size: usize) -> Option<(usize, usize)> {
+        let (page_table, frame_allocator) = memory::get()?;
+        if page_table.is_held_by_current_core() || frame_allocator.is_held_by_current_core() {
+            log::error!("Heap has to grow while this core holds the memory locks");
+            return None;
+        }
+        let mut mapper = page_table.lock();
+        let mut frame_allocator = frame_allocator.lock();
+
+        let (start, mapped) = self.map(&mut *mapper, &mut *frame_allocator, min_size);
+        if mapped == 0 {
+            return None;
+        }
+        Some((start, mapped))
+    }
+}
+
+fn map_heap_pages(
     mapper: &mut impl Mapper<Size2MiB>,
-    frame_allocator: &mut (impl FrameAllocator<Size2MiB> + FrameAllocator<Size4KiB>),
+    frame_allocator: &mut (impl FrameAllocator<Size2MiB>
+              + FrameAllocator<Size4KiB>
+              + FrameDeallocator<Size2MiB>),
+    start: usize,
+    size: usize,
 ) -> Result<(), MapToError<Size2MiB>> {
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
-
-        log::info!("Heap start: {:#x} heap end: {:#x}", heap_start, heap_end+1u64);
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
-    log::debug!("Start init heap");
     for page in page_range {
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        log::info!("Mapping virtual page: {:x?} to {:x?}", page, frame);
+        log::debug!("Mapping virtual page: {:x?} to {:x?}", page, frame);
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(err);
+            }
+        }
     }
+    Ok(())
+}
+
+/// Unmaps heap pages that have not been handed to the allocator yet
+/// and frees their frames
+fn unmap_heap_pages(
+    mapper: &mut impl Mapper<Size2MiB>,
+    frame_allocator: &mut NumaFrameAllocator,
+    start: usize,
+    size: usize,
+) {
+    for offset in (0..size).step_by(Size2MiB::SIZE as usize) {
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new((start + offset) as u64));
+        match mapper.unmap(page) {
+            // No other core knows the pages yet, a local flush is enough
+            Ok((frame, flush)) => {
+                flush.flush();
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+            Err(err) => log::error!("Failed to unmap heap page {:?}: {:?}", page, err),
+        }
+    }
+}
+
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size2MiB>,
+    frame_allocator: &mut NumaFrameAllocator,
+) -> Result<(), MapToError<Size2MiB>> {
+    log::info!(
+        "Heap start: {:#x} heap end: {:#x} reserved up to: {:#x}",
+        HEAP_START,
+        HEAP_START + HEAP_SIZE,
+        HEAP_START + HEAP_MAX_SIZE
+    );
+
+    log::debug!("Start init heap");
+    let (start, mapped) = ALLOCATOR.backing().map(mapper, frame_allocator, HEAP_SIZE);
+    if mapped < HEAP_SIZE {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+
+    // Hand the mapped range to the page allocator of the slab allocator
+    unsafe { ALLOCATOR.add_region(start, mapped) };
 
     log::debug!("Done init heap");
     Ok(())
@@ -0,0 +1,456 @@
+use crate::percpu;
+use alloc::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+/* Slab allocator with per core magazines
+ * Small allocations are served from power of two size classes between
+ * one cache line and half a page. Every object is aligned to its class size,
+ * thus objects never share a cache line.
+ * Every core keeps a magazine of free objects per class in its per cpu block.
+ * Only the owning core touches its magazines with interrupts disabled,
+ * so the fast path takes no lock. Empty magazines get refilled from
+ * the central free list of the class, full ones flush half of their
+ * objects back to it.
+ * Allocations bigger than the largest class are served by the page allocator,
+ * which keeps runs of free pages sorted by address and merges neighbours.
+ * If no run fits, the backing of the allocator maps new pages.
+ * Allocators without magazines serve every object from the central lists,
+ * they are used for heaps that are shared by all cores of a numa node.
+ * All free lists are stored inside the free memory itself.
+ *
+ * alloc fast path: O(1)
+ * dealloc fast path: O(1)
+ * page alloc / dealloc: O(number of free runs)
+ */
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Smallest class is one cache line
+const MIN_CLASS_SHIFT: usize = 6;
+
+/// Largest class is half a page, bigger allocations get whole pages
+const MAX_CLASS_SHIFT: usize = 11;
+
+pub const NUM_CLASSES: usize = MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1;
+
+/// Number of free objects a core caches per class
+const MAGAZINE_SIZE: usize = 32;
+
+/// Returns the size class of a layout or None if it needs whole pages
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = core::cmp::max(layout.size(), layout.align()).next_power_of_two();
+    let shift = core::cmp::max(size.trailing_zeros() as usize, MIN_CLASS_SHIFT);
+    if shift > MAX_CLASS_SHIFT {
+        None
+    } else {
+        Some(shift - MIN_CLASS_SHIFT)
+    }
+}
+
+fn class_size(class: usize) -> usize {
+    1 << (class + MIN_CLASS_SHIFT)
+}
+
+fn num_pages(layout: &Layout) -> usize {
+    (layout.size() + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Central free list of one size class
+struct FreeList {
+    head: *mut FreeObject,
+}
+
+unsafe impl Send for FreeList {}
+
+impl FreeList {
+    const fn new() -> Self {
+        FreeList {
+            head: ptr::null_mut(),
+        }
+    }
+
+    unsafe fn push(&mut self, obj: *mut u8) {
+        let obj = obj as *mut FreeObject;
+        (*obj).next = self.head;
+        self.head = obj;
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut u8> {
+        if self.head.is_null() {
+            return None;
+        }
+        let obj = self.head;
+        self.head = (*obj).next;
+        Some(obj as *mut u8)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    len: usize,
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Magazine {
+            objects: [ptr::null_mut(); MAGAZINE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == MAGAZINE_SIZE
+    }
+
+    fn push(&mut self, obj: *mut u8) {
+        self.objects[self.len] = obj;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.objects[self.len])
+    }
+}
+
+/// Per core object caches, one magazine per size class
+pub struct Magazines {
+    classes: UnsafeCell<[Magazine; NUM_CLASSES]>,
+}
+
+impl Magazines {
+    pub const fn new() -> Self {
+        Magazines {
+            classes: UnsafeCell::new([Magazine::new(); NUM_CLASSES]),
+        }
+    }
+
+    /// Only the owning core may call this with interrupts disabled
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn get(&self, class: usize) -> &mut Magazine {
+        &mut (*self.classes.get())[class]
+    }
+}
+
+struct FreeRun {
+    num_pages: usize,
+    next: *mut FreeRun,
+}
+
+/// Page granular allocator, free runs are kept sorted by address
+pub struct PageAllocator {
+    free: *mut FreeRun,
+    num_free_pages: usize,
+}
+
+unsafe impl Send for PageAllocator {}
+
+impl PageAllocator {
+    const fn new() -> Self {
+        PageAllocator {
+            free: ptr::null_mut(),
+            num_free_pages: 0,
+        }
+    }
+
+    /// Allocates `num_pages` contiguous pages aligned to `align` bytes
+    unsafe fn alloc_pages(&mut self, num_pages: usize, align: usize) -> *mut u8 {
+        let size = num_pages * PAGE_SIZE;
+        let align = core::cmp::max(align, PAGE_SIZE);
+        let mut prev: *mut *mut FreeRun = &mut self.free;
+
+        // First fit
+        while !(*prev).is_null() {
+            let run = *prev;
+            let start = run as usize;
+            let end = start + (*run).num_pages * PAGE_SIZE;
+            let aligned = align_up(start, align);
+
+            if aligned + size <= end {
+                *prev = (*run).next;
+                self.num_free_pages -= (*run).num_pages;
+
+                // Give back what is left in front of and behind the allocation
+                if aligned > start {
+                    self.free_pages(start as *mut u8, (aligned - start) / PAGE_SIZE);
+                }
+                if aligned + size < end {
+                    self.free_pages((aligned + size) as *mut u8, (end - aligned - size) / PAGE_SIZE);
+                }
+                return aligned as *mut u8;
+            }
+            prev = &mut (*run).next;
+        }
+        ptr::null_mut()
+    }
+
+    /// Returns pages and merges them with adjacent free runs
+    unsafe fn free_pages(&mut self, ptr: *mut u8, num_pages: usize) {
+        let start = ptr as usize;
+        let end = start + num_pages * PAGE_SIZE;
+        self.num_free_pages += num_pages;
+
+        let mut prev: *mut FreeRun = ptr::null_mut();
+        let mut next = self.free;
+        while !next.is_null() && (next as usize) < start {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let run = start as *mut FreeRun;
+        if !next.is_null() && next as usize == end {
+            (*run).num_pages = num_pages + (*next).num_pages;
+            (*run).next = (*next).next;
+        } else {
+            (*run).num_pages = num_pages;
+            (*run).next = next;
+        }
+
+        if prev.is_null() {
+            self.free = run;
+        } else if prev as usize + (*prev).num_pages * PAGE_SIZE == start {
+            (*prev).num_pages += (*run).num_pages;
+            (*prev).next = (*run).next;
+        } else {
+            (*prev).next = run;
+        }
+    }
+}
+
+/// Source of new memory once all pages are in use
+pub trait Backing {
+    /// Maps at least `min_size` new bytes and returns their start and size
+    fn grow(&self, min_size: usize) -> Option<(usize, usize)>;
+}
+
+pub struct SlabAllocator<B> {
+    pages: spin::Mutex<PageAllocator>,
+    classes: [spin::Mutex<FreeList>; NUM_CLASSES],
+    /// Bytes requested by live allocations
+    bytes_allocated: AtomicUsize,
+    /// Cache objects in the per core magazines
+    cached: bool,
+    backing: B,
+}
+
+impl<B> SlabAllocator<B> {
+    /// Creates an allocator that caches objects in the per core magazines.
+    /// Only one such allocator can exist, the magazines are not tagged.
+    pub const fn new(backing: B) -> Self {
+        Self::with_magazines(backing, true)
+    }
+
+    /// Creates an allocator that always takes the central lists
+    pub const fn uncached(backing: B) -> Self {
+        Self::with_magazines(backing, false)
+    }
+
+    const fn with_magazines(backing: B, cached: bool) -> Self {
+        // Arrays of non Copy types can only be created from a const
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: spin::Mutex<FreeList> = spin::Mutex::new(FreeList::new());
+        SlabAllocator {
+            pages: spin::Mutex::new(PageAllocator::new()),
+            classes: [EMPTY; NUM_CLASSES],
+            bytes_allocated: AtomicUsize::new(0),
+            cached,
+            backing,
+        }
+    }
+
+    pub fn backing(&self) -> &B {
+        &self.backing
+    }
+
+    /// Hands a page aligned memory region to the allocator
+    pub unsafe fn add_region(&self, start: usize, size: usize) {
+        let start_aligned = align_up(start, PAGE_SIZE);
+        let num_pages = (start + size).saturating_sub(start_aligned) / PAGE_SIZE;
+        if num_pages == 0 {
+            return;
+        }
+        interrupts::without_interrupts(|| {
+            self.pages
+                .lock()
+                .free_pages(start_aligned as *mut u8, num_pages)
+        });
+    }
+
+    pub fn num_bytes_allocated(&self) -> usize {
+        self.bytes_allocated.load(Ordering::Relaxed)
+    }
+
+    pub fn num_free_pages(&self) -> usize {
+        interrupts::without_interrupts(|| self.pages.lock().num_free_pages)
+    }
+}
+
+impl<B: Backing> SlabAllocator<B> {
+    /// Allocates pages and grows the heap until the allocation fits
+    unsafe fn alloc_pages(&self, num_pages: usize, align: usize) -> *mut u8 {
+        loop {
+            let ptr = self.pages.lock().alloc_pages(num_pages, align);
+            if !ptr.is_null() {
+                return ptr;
+            }
+            // Leave room to align the start of the allocation
+            let min_size = num_pages * PAGE_SIZE + align.saturating_sub(PAGE_SIZE);
+            match self.backing.grow(min_size) {
+                Some((start, size)) => self.add_region(start, size),
+                None => return ptr::null_mut(),
+            }
+        }
+    }
+
+    /// Carves a new page into objects if the central list is empty
+    unsafe fn carve(&self, class: usize, list: &mut FreeList) {
+        if !list.head.is_null() {
+            return;
+        }
+        let page = self.alloc_pages(1, PAGE_SIZE);
+        if page.is_null() {
+            return;
+        }
+        let size = class_size(class);
+        for offset in (0..PAGE_SIZE).step_by(size).rev() {
+            list.push(page.add(offset));
+        }
+    }
+
+    /// Moves half a magazine of objects from the central list into the magazine
+    unsafe fn refill(&self, class: usize, magazine: &mut Magazine) {
+        let mut list = self.classes[class].lock();
+        self.carve(class, &mut list);
+        while magazine.len < MAGAZINE_SIZE / 2 {
+            match list.pop() {
+                Some(obj) => magazine.push(obj),
+                None => break,
+            }
+        }
+    }
+
+    /// Moves half of a full magazine back to the central list
+    unsafe fn flush(&self, class: usize, magazine: &mut Magazine) {
+        let mut list = self.classes[class].lock();
+        while magazine.len > MAGAZINE_SIZE / 2 {
+            list.push(magazine.pop().unwrap());
+        }
+    }
+
+    unsafe fn alloc_object(&self, class: usize) -> *mut u8 {
+        if !self.cached {
+            let mut list = self.classes[class].lock();
+            self.carve(class, &mut list);
+            return list.pop().unwrap_or(ptr::null_mut());
+        }
+        let magazine = percpu!().magazines.get(class);
+        if let Some(obj) = magazine.pop() {
+            return obj;
+        }
+        self.refill(class, magazine);
+        magazine.pop().unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc_object(&self, class: usize, obj: *mut u8) {
+        if !self.cached {
+            self.classes[class].lock().push(obj);
+            return;
+        }
+        let magazine = percpu!().magazines.get(class);
+        if magazine.is_full() {
+            self.flush(class, magazine);
+        }
+        magazine.push(obj);
+    }
+}
+
+unsafe impl<B: Backing> GlobalAlloc for SlabAllocator<B> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        percpu!().num_allocs.fetch_add(1, Ordering::Relaxed);
+
+        // Interrupt handlers allocate as well, they must not
+        // interrupt a magazine or list update of this core
+        let ptr = interrupts::without_interrupts(|| match size_class(&layout) {
+            Some(class) => self.alloc_object(class),
+            None => self.alloc_pages(num_pages(&layout), layout.align()),
+        });
+
+        if !ptr.is_null() {
+            self.bytes_allocated
+                .fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        percpu!().num_deallocs.fetch_add(1, Ordering::Relaxed);
+        self.bytes_allocated
+            .fetch_sub(layout.size(), Ordering::Relaxed);
+
+        interrupts::without_interrupts(|| match size_class(&layout) {
+            Some(class) => self.dealloc_object(class, ptr),
+            None => self.pages.lock().free_pages(ptr, num_pages(&layout)),
+        });
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        // Keep the memory if the new size still needs the same class or page count
+        let same_block = match (size_class(&layout), size_class(&new_layout)) {
+            (Some(old), Some(new)) => old == new,
+            (None, None) => num_pages(&layout) == num_pages(&new_layout),
+            _ => false,
+        };
+        if same_block {
+            if new_size > layout.size() {
+                self.bytes_allocated
+                    .fetch_add(new_size - layout.size(), Ordering::Relaxed);
+            } else {
+                self.bytes_allocated
+                    .fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+            }
+            return ptr;
+        }
+
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, core::cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+unsafe impl<B: Backing> Allocator for SlabAllocator<B> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        // GlobalAlloc does not allow zero sized allocations
+        let ptr = if layout.size() == 0 {
+            layout.align() as *mut u8
+        } else {
+            unsafe { self.alloc(layout) }
+        };
+        NonNull::new(ptr::slice_from_raw_parts_mut(ptr, layout.size())).ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.dealloc(ptr.as_ptr(), layout);
+        }
+    }
+}
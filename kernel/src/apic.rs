@@ -4,16 +4,36 @@ use crate::acpi::Acpi;
 use crate::apic_regs::*;
 use crate::interrupts::InterruptIndex;
 use crate::interrupts::PICS;
+use crate::time::Duration;
+use core::convert::TryFrom;
 use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use x86_64::registers::model_specific::Msr;
-use x86_64::structures::paging::page_table::PageTableFlags;
-use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size2MiB, Size4KiB};
 use x86_64::PhysAddr;
 
 // Other constants
 const APIC_BASE: u64 = 0x0_0000_FEE0_0000;
 
-pub unsafe fn mp_init(apic_id: u8, trampoline: u32) {
+/// Length of the HPET measurement window used to calibrate the apic timer
+const APIC_CALIBRATION_NS: u64 = 10_000_000;
+
+/// MSR holding the TSC deadline of the apic timer
+const IA32_TSC_DEADLINE: u32 = 0x0000_06E0;
+
+/// First MSR of the x2APIC register space
+const X2APIC_MSR_BASE: u32 = 0x0000_0800;
+
+/// Apic timer ticks per second, measured once on the bsp
+static APIC_TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Set by the bsp if all cores access their apic through MSRs.
+/// In x2APIC mode apic ids are 32 bit wide and the ICR is a single 64 bit MSR.
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
+/// Virtual address the xAPIC registers are mapped to by the vmm
+static APIC_VIRT: AtomicU64 = AtomicU64::new(0);
+
+pub unsafe fn mp_init(apic_id: u32, trampoline: u32) {
     log::info!("Booting core {}", apic_id);
     // Create INIT IPI
     let low = InterCmdRegLow::new()
@@ -22,10 +42,9 @@ pub unsafe fn mp_init(apic_id: u8, trampoline: u32) {
             .with_msg_type(0b101) // INIT type
             .with_level(0) // 0 for INIT
             ;
-    let high = InterCmdRegHigh::new().with_dest(apic_id);
 
     // Sent INIT IPI
-    send_ipi(&low, &high);
+    send_ipi(&low, apic_id);
 
     // Convert func pointer to u64
     let trampoline = trampoline as u64;
@@ -48,7 +67,7 @@ pub unsafe fn mp_init(apic_id: u8, trampoline: u32) {
     }
 
     // Sleep 10 milliseconds as by spec
-    crate::time::sleep(10*1000);
+    crate::time::sleep(Duration::from_millis(10));
 
     // Create STARTUP IPI
     let low = InterCmdRegLow::new()
@@ -59,31 +78,48 @@ pub unsafe fn mp_init(apic_id: u8, trampoline: u32) {
             ;
 
     // Sent Startup IPI (SIPI)
-    send_ipi(&low, &high);
+    send_ipi(&low, apic_id);
 
     // Sent Startup IPI (SIPI)
-    send_ipi(&low, &high);
+    send_ipi(&low, apic_id);
 }
 
 #[inline]
 fn ipi_pending() -> bool {
+    // The x2APIC has no delivery status, the MSR write returns once the IPI is sent
+    if is_x2apic() {
+        return false;
+    }
     unsafe {
         let r = InterCmdRegLow::from_bytes(read_apic(Register::InterCmdRegLow).to_le_bytes());
         r.delivery_status() == 1
     }
 }
 
-unsafe fn send_ipi(low: &InterCmdRegLow, high: &InterCmdRegHigh) {
-    write_apic(
-        Register::InterCmdRegHigh,
-        u32::from_le_bytes(high.into_bytes()),
-    );
-    write_apic(
-        Register::InterCmdRegLow,
-        u32::from_le_bytes(low.into_bytes()),
-    );
+/// Writes the interrupt command register, the write of the low part sends the IPI
+unsafe fn write_icr(low: &InterCmdRegLow, dest: u32) {
+    let low = u32::from_le_bytes(low.into_bytes());
+    if is_x2apic() {
+        // The ICR MSR write is not serializing, make prior stores
+        // visible to the target before it gets the interrupt
+        core::arch::x86_64::_mm_mfence();
+        let icr = ((dest as u64) << 32) | low as u64;
+        Msr::new(x2apic_msr(Register::InterCmdRegLow)).write(icr);
+    } else {
+        let dest = u8::try_from(dest).expect("xAPIC can only address apic ids below 256");
+        let high = InterCmdRegHigh::new().with_dest(dest);
+        write_apic(
+            Register::InterCmdRegHigh,
+            u32::from_le_bytes(high.into_bytes()),
+        );
+        write_apic(Register::InterCmdRegLow, low);
+    }
+}
+
+unsafe fn send_ipi(low: &InterCmdRegLow, apic_id: u32) {
+    write_icr(low, apic_id);
     // Sleep 200 microseconds as by spec
-    crate::time::sleep(200);
+    crate::time::sleep(Duration::from_micros(200));
 
     // Check if ipi has been sent successfull
     if ipi_pending() {
@@ -91,6 +127,57 @@ unsafe fn send_ipi(low: &InterCmdRegLow, high: &InterCmdRegHigh) {
     }
 }
 
+/// Sends a fixed interrupt with the given vector to the core with the given apic id
+pub unsafe fn send_fixed_ipi(apic_id: u32, vector: u8) {
+    let low = InterCmdRegLow::new()
+            .with_vec(vector)
+            .with_trigger_mode(0) // edge-triggered
+            .with_msg_type(0b000) // Fixed type
+            .with_level(1)
+            ;
+
+    // An interrupt handler could send an IPI between
+    // writing the high and low part of the ICR
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write_icr(&low, apic_id);
+
+        while ipi_pending() {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Sends a NMI to every core except the current one.
+/// Does not wait for the delivery, the panic path must not hang here.
+pub unsafe fn send_nmi_to_others() {
+    let low = InterCmdRegLow::new()
+            .with_vec(0) // Ignored for NMIs
+            .with_trigger_mode(0) // edge-triggered
+            .with_msg_type(0b100) // NMI type
+            .with_level(1)
+            .with_dest_shorthand(0b11) // All excluding self
+            ;
+
+    write_icr(&low, 0);
+}
+
+/// Routes the AMD extended LVT entry `offset` of the current core
+/// to NMIs if `enable` is set, else masks it
+pub unsafe fn set_extended_lvt_nmi(offset: u8, enable: bool) {
+    let register = match offset {
+        0 => Register::ExtendedLvt0,
+        1 => Register::ExtendedLvt1,
+        2 => Register::ExtendedLvt2,
+        3 => Register::ExtendedLvt3,
+        _ => panic!("Invalid extended lvt offset {}", offset),
+    };
+    let entry = ExtendedLvtReg::new()
+        .with_vec(0) // Ignored for NMIs
+        .with_msg_type(0b100) // NMI type
+        .with_mask(!enable as u8);
+    write_apic(register, u32::from_le_bytes(entry.into_bytes()));
+}
+
 fn is_supported() -> bool {
     use core::arch::x86_64::__cpuid;
     let feature = unsafe { __cpuid(0x0000_0001) };
@@ -118,34 +205,23 @@ unsafe fn init_chained_pics(acpi: &Acpi) {
         imcr_low.write(0x70); // Select imcr register
         imcr_high.write(0x01); // go through apic
         PICS.lock().mask_all();
-        log::warn!("Redirecting PIC to io acpi this has not been tested");
+
+        // Deliver the legacy devices through the io apic to the bsp
+        crate::ioapic::route_legacy_irqs(acpi, crate::percpu!(apic_id));
     }
 }
 
-pub unsafe fn init(
-    mapper: &mut OffsetPageTable,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-    acpi: &Acpi,
-    boot_info: &'static bootloader::bootinfo::BootInfo,
-) {
+pub unsafe fn init(acpi: &Acpi, boot_info: &'static bootloader::bootinfo::BootInfo) {
     if !is_supported() {
         panic!("Apic is not available");
     }
 
-    let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(APIC_BASE));
-    // Map page for apic base address
-    crate::memory::id_map(
-        mapper,
-        frame_allocator,
-        frame,
-        Some(
-            PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::NO_EXECUTE
-                | PageTableFlags::HUGE_PAGE,
-        ),
-    )
-    .unwrap();
+    // Map page for apic base address, the bsp maps it for all cores
+    if APIC_VIRT.load(Ordering::SeqCst) == 0 {
+        let virt = crate::vmm::map_mmio(PhysAddr::new(APIC_BASE), 4096, "local apic")
+            .expect("failed to map the local apic");
+        APIC_VIRT.store(virt.as_u64(), Ordering::SeqCst);
+    }
 
     // Enable apic by writing MSR base reg
     let mut apic_base_reg = Msr::new(0x0000_001B);
@@ -172,11 +248,38 @@ pub unsafe fn init(
     let payload = u64::from_le_bytes(base_reg.into_bytes());
     apic_base_reg.write(payload);
 
+    // The bsp decides on the mode, the other cores follow.
+    // The firmware may already have switched to x2APIC mode,
+    // going back to xAPIC is not allowed without disabling the apic
+    if base_reg.bootstrap_core() == 1 {
+        X2APIC.store(
+            has_x2apic() || base_reg.x2apic_enable() == 1,
+            Ordering::SeqCst,
+        );
+    }
+
+    // Switching from xAPIC to x2APIC has to happen with the apic enabled
+    if is_x2apic() {
+        base_reg.set_x2apic_enable(1);
+        let payload = u64::from_le_bytes(base_reg.into_bytes());
+        apic_base_reg.write(payload);
+    }
+
     let id = apic_id();
+    if !is_x2apic() && id > u8::MAX as u32 {
+        panic!("Apic id {} needs x2APIC mode which is not supported", id);
+    }
 
     // Only execute if bootstrap core
     if base_reg.bootstrap_core() == 1 {
-        log::info!("BSP is apic id: {}", id);
+        log::info!(
+            "BSP is apic id: {} in {} mode",
+            id,
+            if is_x2apic() { "x2APIC" } else { "xAPIC" }
+        );
+
+        // Map the io apics and mask all of their entries
+        crate::ioapic::init(acpi);
 
         // Initialize or mask chained pics
         init_chained_pics(acpi);
@@ -211,49 +314,146 @@ pub unsafe fn init(
     init_timer();
 }
 
+/// Apic timer modes of the LVT timer register
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum TimerMode {
+    OneShot = 0b00,
+    Periodic = 0b01,
+    TscDeadline = 0b10,
+}
+
 unsafe fn init_timer() {
     // Divide by two
     let div = DivideConfReg::new().with_div(0).with_div2(0);
     let div = u32::from_le_bytes(div.into_bytes());
     write_apic(Register::DivideConfReg, div);
 
-    // clear mask & periodic timer
+    // Calibrate once on the bsp, all cores share the same bus frequency
+    if is_bsp() {
+        calibrate_timer();
+    }
+
+    crate::timer::init();
+}
+
+/// Calculate apic tics per second by measuring elapsed ticks
+/// through the HPET. Without HPET fall back to a TSC busy sleep
+unsafe fn calibrate_timer() {
+    // Count down masked in one-shot mode
     let timer = TimerLvtReg::new()
         .with_vec(InterruptIndex::Timer.as_u8())
-        .with_delivery_status(0)
-        .with_mask(0)
-        .with_timer_mode(1); // Periodic timer inters
-    let timer = u32::from_le_bytes(timer.into_bytes());
-    write_apic(Register::ApicTimer, timer);
-
-    //TODO: Do this only once, not for every core
-    // Calculate apic tics per second by measuring elapsed ticks
-    // through the PIT timer
+        .with_mask(1)
+        .with_timer_mode(TimerMode::OneShot as u8);
+    write_apic(Register::ApicTimer, u32::from_le_bytes(timer.into_bytes()));
     write_apic(Register::TimerInitialCount, u32::MAX);
 
-    // sleep 1s
-    crate::time::sleep(1000*1000);
+    let elapsed_ns = if crate::hpet::is_available() {
+        crate::hpet::busy_wait_ns(APIC_CALIBRATION_NS)
+    } else {
+        crate::time::sleep(Duration::from_secs(1));
+        1_000_000_000
+    };
 
     let ticks_elapsed  = u32::MAX - read_apic(Register::TimerCurrentCount);
+    let ticks_per_sec = (ticks_elapsed as u64 * 1_000_000_000) / elapsed_ns;
+    write_apic(Register::TimerInitialCount, 0);
+
+    APIC_TIMER_HZ.store(ticks_per_sec, Ordering::SeqCst);
+    log::info!("Apic timer runs at {} Hz", ticks_per_sec);
+}
+
+/// Apic timer ticks per second measured on the bsp
+pub fn timer_hz() -> u64 {
+    APIC_TIMER_HZ.load(Ordering::Relaxed)
+}
+
+/// Returns true if the apic timer supports the TSC-deadline mode
+pub fn has_tsc_deadline() -> bool {
+    use core::arch::x86_64::__cpuid;
+    let feature = unsafe { __cpuid(0x0000_0001) };
+    feature.ecx & (1 << 24) != 0
+}
+
+/// Unmasks the apic timer of the current core in the given mode
+pub unsafe fn set_timer_mode(mode: TimerMode) {
+    let timer = TimerLvtReg::new()
+        .with_vec(InterruptIndex::Timer.as_u8())
+        .with_delivery_status(0)
+        .with_mask(0)
+        .with_timer_mode(mode as u8);
+    write_apic(Register::ApicTimer, u32::from_le_bytes(timer.into_bytes()));
+
+    // Serialize the LVT write against the following deadline MSR write
+    // as recommended by the Intel SDM
+    if mode == TimerMode::TscDeadline {
+        core::arch::x86_64::_mm_mfence();
+    }
+}
 
-    write_apic(Register::TimerInitialCount, ticks_elapsed);
+/// Starts the one-shot or periodic timer, zero stops it
+pub unsafe fn set_timer_count(ticks: u32) {
+    write_apic(Register::TimerInitialCount, ticks);
 }
 
-fn apic_id_from_mem() -> u8 {
+/// Arms the timer in TSC-deadline mode, zero disarms it
+pub unsafe fn set_tsc_deadline(deadline: u64) {
+    Msr::new(IA32_TSC_DEADLINE).write(deadline);
+}
+
+fn apic_id_from_mem() -> u32 {
     let id_reg = unsafe { read_apic(Register::ApicId) };
+    // The x2APIC id register holds the full 32 bit id
+    if is_x2apic() {
+        return id_reg;
+    }
     let res = ApicId::from_bytes(id_reg.to_le_bytes());
-    res.aid()
+    res.aid() as u32
+}
+
+/// Returns true if the cpu supports the x2APIC mode
+pub fn has_x2apic() -> bool {
+    use core::arch::x86_64::__cpuid;
+    let feature = unsafe { __cpuid(0x0000_0001) };
+    feature.ecx & (1 << 21) != 0
+}
+
+/// Returns true if the apic registers are accessed through MSRs
+#[inline]
+pub fn is_x2apic() -> bool {
+    X2APIC.load(Ordering::Relaxed)
+}
+
+/// The x2APIC MSR index is the xAPIC MMIO offset divided by 16
+fn x2apic_msr(register: Register) -> u32 {
+    X2APIC_MSR_BASE + (register as u32 >> 4)
+}
+
+/// Address of the xAPIC registers, the identity mapping until apic::init
+#[inline]
+fn apic_virt() -> u64 {
+    match APIC_VIRT.load(Ordering::Relaxed) {
+        0 => APIC_BASE,
+        virt => virt,
+    }
 }
 
 unsafe fn read_apic(register: Register) -> u32 {
+    if is_x2apic() {
+        return Msr::new(x2apic_msr(register)).read() as u32;
+    }
     let offset = register as u64;
-    let ptr = (APIC_BASE + offset) as *mut u32;
+    let ptr = (apic_virt() + offset) as *mut u32;
     read_volatile(ptr)
 }
 
 unsafe fn write_apic(register: Register, value: u32) {
+    if is_x2apic() {
+        Msr::new(x2apic_msr(register)).write(value as u64);
+        return;
+    }
     let offset = register as u64;
-    let ptr = (APIC_BASE + offset) as *mut u32;
+    let ptr = (apic_virt() + offset) as *mut u32;
     write_volatile(ptr, value);
 }
 
@@ -269,9 +469,19 @@ pub fn is_bsp() -> bool {
     }
 }
 
-pub fn apic_id() -> u8 {
+/// Returns the x2apic id if cpuid leaf 0xb exists, else the 8 bit apic id.
+/// Both are the same for ids below 256.
+pub fn apic_id() -> u32 {
     use core::arch::x86_64::__cpuid;
-    let res = unsafe { __cpuid(0x0000_0001) };
-
-    (res.ebx >> 24) as u8
+    unsafe {
+        if __cpuid(0x0000_0000).eax >= 0x0000_000b {
+            let res = __cpuid(0x0000_000b);
+            // Leaf 0xb is only valid if ebx is non zero
+            if res.ebx != 0 {
+                return res.edx;
+            }
+        }
+        let res = __cpuid(0x0000_0001);
+        res.ebx >> 24
+    }
 }
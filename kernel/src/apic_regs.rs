@@ -17,6 +17,11 @@ pub enum Register {
     LogicalDestReg = 0xD0,
     InterCmdRegLow = 0x300,
     InterCmdRegHigh = 0x310,
+    /// AMD extended LVT entries
+    ExtendedLvt0 = 0x500,
+    ExtendedLvt1 = 0x510,
+    ExtendedLvt2 = 0x520,
+    ExtendedLvt3 = 0x530,
 }
 
 #[bitfield]
@@ -85,7 +90,8 @@ pub struct ApicVersion {
 pub struct ApicBaseReg {
     pub res0: B8,
     pub bootstrap_core: B1,
-    pub res1: B2,
+    pub res1: B1,
+    pub x2apic_enable: B1,
     pub apic_enable: B1,
     pub apic_base_addr: B40,
     pub res2: B12,
@@ -108,8 +114,20 @@ pub struct TimerLvtReg {
     pub delivery_status: B1,
     pub res1: B3,
     pub mask: B1,
-    pub timer_mode: B1,
-    pub res2: B14,
+    pub timer_mode: B2,
+    pub res2: B13,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct ExtendedLvtReg {
+    pub vec: B8,
+    pub msg_type: B3,
+    pub res0: B1,
+    pub delivery_status: B1,
+    pub res1: B3,
+    pub mask: B1,
+    pub res2: B15,
 }
 
 #[bitfield]
@@ -0,0 +1,347 @@
+use crate::println;
+use core::fmt;
+use core::fmt::Write;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/*
+ * Symbolized stack traces
+ * The kernel is built with frame pointers, every frame starts with the
+ * saved rbp of the caller followed by the return address. The walk stops
+ * as soon as rbp leaves the stacks the bootloader reserved for the
+ * current core, so a corrupted stack can not fault the unwinder.
+ * Return addresses are resolved with the symbol table of the kernel ELF
+ * image, which the bootloader keeps in memory and passes in the BootInfo.
+ * Nothing here allocates or takes a lock, it is safe to use while panicking.
+ */
+
+/// Maximum number of frames printed
+const MAX_FRAMES: usize = 64;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Elf64Header {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Elf64Shdr {
+    sh_name: u32,
+    sh_type: u32,
+    sh_flags: u64,
+    sh_addr: u64,
+    sh_offset: u64,
+    sh_size: u64,
+    sh_link: u32,
+    sh_info: u32,
+    sh_addralign: u64,
+    sh_entsize: u64,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Elf64Sym {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    st_value: u64,
+    st_size: u64,
+}
+
+struct Symbols {
+    symbols: &'static [Elf64Sym],
+    strings: &'static [u8],
+}
+
+static mut SYMBOLS: Option<Symbols> = None;
+
+static mut BOOT_INFO: Option<&'static bootloader::bootinfo::BootInfo> = None;
+
+/// Finds the symbol table in the kernel image. Without it
+/// stack traces only contain addresses.
+pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
+    BOOT_INFO = Some(boot_info);
+    if SYMBOLS.is_some() {
+        return;
+    }
+
+    let image_addr = boot_info.kernel_image_addr as u64;
+    let image_size = boot_info.kernel_image_size as u64;
+    if image_addr == 0 {
+        log::warn!("Bootloader did not pass the kernel image, no symbols");
+        return;
+    }
+    let image = core::slice::from_raw_parts(image_addr as *const u8, image_size as usize);
+
+    let header = core::ptr::read_unaligned(image.as_ptr() as *const Elf64Header);
+    let shdrs_size = header.e_shnum as u64 * core::mem::size_of::<Elf64Shdr>() as u64;
+    if &header.e_ident[..4] != b"\x7fELF" || header.e_shoff + shdrs_size > image_size {
+        log::warn!("Kernel image has no valid section headers, no symbols");
+        return;
+    }
+    let shdr = |index: usize| {
+        let ptr = image.as_ptr().add(header.e_shoff as usize) as *const Elf64Shdr;
+        core::ptr::read_unaligned(ptr.add(index))
+    };
+
+    let symtab = match (0..header.e_shnum as usize)
+        .map(shdr)
+        .find(|s| s.sh_type == SHT_SYMTAB)
+    {
+        Some(symtab) => symtab,
+        None => {
+            log::warn!("Kernel image has been stripped, no symbols");
+            return;
+        }
+    };
+    let strtab = shdr(symtab.sh_link as usize);
+    if symtab.sh_offset + symtab.sh_size > image_size
+        || strtab.sh_offset + strtab.sh_size > image_size
+        || (image_addr + symtab.sh_offset) % 8 != 0
+    {
+        log::warn!("Symbol table lies outside of the kernel image");
+        return;
+    }
+
+    let symbols = core::slice::from_raw_parts(
+        (image_addr + symtab.sh_offset) as *const Elf64Sym,
+        (symtab.sh_size / core::mem::size_of::<Elf64Sym>() as u64) as usize,
+    );
+    let strings = &image[strtab.sh_offset as usize..(strtab.sh_offset + strtab.sh_size) as usize];
+    log::debug!("Loaded {} kernel symbols", symbols.len());
+    SYMBOLS = Some(Symbols { symbols, strings });
+}
+
+/// Returns the mangled name of the function containing `addr`
+/// and the offset of `addr` into it
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    let table = unsafe { SYMBOLS.as_ref()? };
+    let sym = table.symbols.iter().find(|sym| {
+        sym.st_info & 0xf == STT_FUNC
+            && sym.st_value <= addr
+            && addr < sym.st_value + core::cmp::max(sym.st_size, 1)
+    })?;
+    let name = table.strings.get(sym.st_name as usize..)?;
+    let len = name.iter().position(|c| *c == 0)?;
+    let name = core::str::from_utf8(&name[..len]).ok()?;
+    Some((name, addr - sym.st_value))
+}
+
+/// Returns the frame pointer of the calling function
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// Returns the stack of the current core that contains `addr..addr+16`
+fn stack_of(addr: u64) -> Option<(u64, u64)> {
+    let boot_info = unsafe { BOOT_INFO? };
+    // The bootloader puts the bsp first
+    let core_index = if crate::percpu::is_initialized() {
+        crate::percpu!(core_index)
+    } else {
+        0
+    };
+    let core = boot_info.cores.get(core_index)?;
+    let tss = core.tss;
+    let tss_ends = tss.stack_end_addr;
+    core::iter::once((core.stack_end_addr, core.get_stack_start()))
+        .chain((0..bootloader::TSS_STACKS_PER_CPU).map(|i| (tss_ends[i], tss.get_stack_start(i))))
+        .filter_map(|(end, start)| Some((end as u64, start? as u64)))
+        .find(|(end, start)| *end <= addr && addr + 16 <= *start)
+}
+
+/// Follows the frame pointer chain starting at `rbp` and
+/// calls `f` with every return address found
+pub fn walk(mut rbp: u64, mut f: impl FnMut(u64)) {
+    for _ in 0..MAX_FRAMES {
+        if rbp % 8 != 0 {
+            return;
+        }
+        let stack = match stack_of(rbp) {
+            Some(stack) => stack,
+            None => return,
+        };
+        let (next, ret) = unsafe {
+            let frame = rbp as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+        if ret == 0 {
+            return;
+        }
+        f(ret);
+        // Callers lie further up the same stack, only an
+        // exception on an IST stack switches to another one
+        if next <= rbp && stack_of(next) == Some(stack) {
+            return;
+        }
+        rbp = next;
+    }
+}
+
+/// Forwards to print!, which locks the serial and vga writers
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::print!("{}", s);
+        Ok(())
+    }
+}
+
+/// Writes one line of a stack trace
+fn write_frame(w: &mut dyn Write, index: usize, addr: u64) -> fmt::Result {
+    match resolve(addr) {
+        Some((name, offset)) => writeln!(
+            w,
+            "  {:>2}: {:#x} {}+{:#x}",
+            index,
+            addr,
+            Demangle(name),
+            offset
+        ),
+        None => writeln!(w, "  {:>2}: {:#x} ??", index, addr),
+    }
+}
+
+/// Writes the call chain starting at the frame `rbp` points to.
+/// Takes no lock, the panic path writes straight to the uart with it.
+pub fn write_backtrace(w: &mut dyn Write, rbp: u64) -> fmt::Result {
+    writeln!(w, "Stack trace:")?;
+    let mut index = 0;
+    let mut result = Ok(());
+    walk(rbp, |addr| {
+        if result.is_ok() {
+            result = write_frame(w, index, addr);
+        }
+        index += 1;
+    });
+    result
+}
+
+/// Prints the call chain of the calling function
+#[inline(never)]
+pub fn print_backtrace() {
+    let _ = write_backtrace(&mut Console, frame_pointer());
+}
+
+/// Prints the interrupted instruction and the call chain that led to it.
+/// `rbp` has to be the frame pointer of the exception handler.
+pub fn print_exception(stack_frame: &InterruptStackFrame, rbp: u64) {
+    println!("Stack trace:");
+    let _ = write_frame(&mut Console, 0, stack_frame.instruction_pointer.as_u64());
+    if rbp % 8 != 0 || stack_of(rbp).is_none() {
+        return;
+    }
+    // The handler saved the frame pointer of the interrupted function
+    let mut index = 1;
+    walk(unsafe { (rbp as *const u64).read() }, |addr| {
+        let _ = write_frame(&mut Console, index, addr);
+        index += 1;
+    });
+}
+
+/// Formats a legacy mangled Rust symbol like `_ZN4core9panicking5panic17h0123456789abcdefE`
+/// as `core::panicking::panic`. Other names are printed unchanged.
+pub struct Demangle<'a>(pub &'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = match self.0.strip_prefix("_ZN").and_then(|s| s.strip_suffix('E')) {
+            Some(inner) => inner,
+            None => return f.write_str(self.0),
+        };
+
+        let mut rest = inner;
+        let mut first = true;
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(|c| c.is_ascii_digit()).count();
+            let len: usize = match rest[..digits].parse() {
+                Ok(len) if digits + len <= rest.len() => len,
+                _ => return f.write_str(self.0),
+            };
+            let part = &rest[digits..digits + len];
+            rest = &rest[digits + len..];
+
+            // The last part is the hash of the symbol
+            let is_hash = rest.is_empty()
+                && part.len() == 17
+                && part.starts_with('h')
+                && part[1..].bytes().all(|c| c.is_ascii_hexdigit());
+            if is_hash {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_part(f, part)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes one path segment and replaces the escapes of the mangling
+fn write_part(f: &mut fmt::Formatter, part: &str) -> fmt::Result {
+    // A leading underscore protects segments starting with an escape
+    let mut rest = if part.starts_with("_$") {
+        &part[1..]
+    } else {
+        part
+    };
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = r;
+            continue;
+        }
+        if let Some(end) = rest.strip_prefix('$').and_then(|r| r.find('$')) {
+            let escape = &rest[1..end + 1];
+            let c = match escape {
+                "SP" => Some('@'),
+                "BP" => Some('*'),
+                "RF" => Some('&'),
+                "LT" => Some('<'),
+                "GT" => Some('>'),
+                "LP" => Some('('),
+                "RP" => Some(')'),
+                "C" => Some(','),
+                _ => escape
+                    .strip_prefix('u')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32),
+            };
+            if let Some(c) = c {
+                f.write_char(c)?;
+                rest = &rest[end + 2..];
+                continue;
+            }
+        }
+        let c = rest.chars().next().unwrap();
+        f.write_char(c)?;
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(())
+}
@@ -1,7 +1,21 @@
+pub mod buffer;
+pub mod runner;
+pub mod stats;
+
+use crate::pmu::{Counters, Event, PmuError, MAX_COUNTERS};
 use crate::println;
-use crate::time::{elapsed, rdtsc};
-use core::arch::x86_64::__cpuid;
+use crate::time::{self, Duration, Instant};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::arch::x86_64::{__cpuid, _mm_lfence, _rdtsc};
+use core::fmt::Write;
+use core::hint::black_box;
 use raw_cpuid::CpuId;
+use stats::Stats;
+
+pub use bench_case::bench_case;
+pub use runner::BenchCase;
 
 #[repr(u32)]
 pub enum CpuidIndex {
@@ -18,20 +32,228 @@ impl CpuidIndex {
     }
 }
 
+/// Timed runs of nothing, their median is the measurement overhead
+const OVERHEAD_RUNS: usize = 1001;
+
+/// Version of the machine readable result lines
+pub const RESULT_VERSION: u32 = 1;
+
+/// Criterion style micro benchmark of a closure on the current core.
+/// Warms up, picks the iterations per sample so that all samples take
+/// about the measurement time and reports the statistics of the cycles
+/// per iteration with the measurement overhead subtracted.
 pub struct Bench {
-    start: u64,
+    name: String,
+    warm_up: Duration,
+    measurement: Duration,
+    samples: usize,
+    events: Vec<Event>,
+    print: bool,
 }
 
 impl Bench {
-    pub fn start() -> Self {
-        Bench { start: rdtsc() }
+    /// The name shows up in the result lines and must not contain whitespace
+    pub fn new(name: &str) -> Self {
+        Bench {
+            name: name.to_string(),
+            warm_up: Duration::from_millis(200),
+            measurement: Duration::from_secs(1),
+            samples: 100,
+            events: Vec::new(),
+            print: true,
+        }
+    }
+
+    pub fn warm_up(mut self, warm_up: Duration) -> Self {
+        self.warm_up = warm_up;
+        self
+    }
+
+    /// Time all samples together should take
+    pub fn measurement_time(mut self, measurement: Duration) -> Self {
+        self.measurement = measurement;
+        self
+    }
+
+    pub fn samples(mut self, samples: usize) -> Self {
+        assert!(samples >= 2, "a benchmark needs at least two samples");
+        self.samples = samples;
+        self
+    }
+
+    /// Also counts `events` with the performance counters during the samples
+    pub fn counters(mut self, events: &[Event]) -> Self {
+        self.events = events.to_vec();
+        self
+    }
+
+    /// Does not print the results, the caller does
+    pub fn quiet(mut self) -> Self {
+        self.print = false;
+        self
     }
 
-    pub fn end(&mut self) {
-        let diff = elapsed(self.start);
+    /// Benchmarks `f`, prints and returns the results
+    pub fn run<F: FnMut() -> R, R>(&self, mut f: F) -> Result<Report, PmuError> {
+        let counters = if self.events.is_empty() {
+            None
+        } else {
+            Some(Counters::new(&self.events)?)
+        };
+        let overhead = measure_overhead();
+
+        // Double the iterations until the warm up time is over
+        let warm_up_end = Instant::after(self.warm_up);
+        let mut iters = 1;
+        let (mut total_cycles, mut total_iters) = (0, 0);
+        loop {
+            total_cycles += sample(&mut f, iters);
+            total_iters += iters;
+            if warm_up_end.has_passed() {
+                break;
+            }
+            iters *= 2;
+        }
+        let cycles_per_iter = (total_cycles as f64 / total_iters as f64).max(1.0);
+        let sample_cycles = time::duration_to_cycles(self.measurement) / self.samples as u64;
+        let iters = ((sample_cycles as f64 / cycles_per_iter) as u64).max(1);
+
+        let mut cycles = Vec::with_capacity(self.samples);
+        let mut counts = [0u64; MAX_COUNTERS];
+        for _ in 0..self.samples {
+            let start = counters.as_ref().map(|counters| counters.read());
+            let elapsed = sample(&mut f, iters);
+            if let (Some(counters), Some(start)) = (&counters, start) {
+                let delta = counters.read().delta(&start);
+                for (count, value) in counts.iter_mut().zip(delta.values()) {
+                    *count += value;
+                }
+            }
+            cycles.push(elapsed.saturating_sub(overhead) as f64 / iters as f64);
+        }
+
+        let total_iters = (iters * self.samples as u64) as f64;
+        let report = Report {
+            name: self.name.clone(),
+            core_index: crate::percpu!(core_index),
+            iters,
+            overhead,
+            tsc_hz: time::tsc_hz(),
+            cycles: Stats::new(&mut cycles).unwrap(),
+            counters: self
+                .events
+                .iter()
+                .zip(counts.iter())
+                .map(|(event, count)| (*event, *count as f64 / total_iters))
+                .collect(),
+        };
+        if self.print {
+            report.print();
+        }
+        Ok(report)
+    }
+}
+
+/// Results of a benchmark, all values are per iteration
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub name: String,
+    pub core_index: usize,
+    /// Iterations of every sample
+    pub iters: u64,
+    /// Cycles subtracted from every sample for reading the TSC
+    pub overhead: u64,
+    pub tsc_hz: u64,
+    pub cycles: Stats,
+    /// Mean count of every event
+    pub counters: Vec<(Event, f64)>,
+}
+
+impl Report {
+    /// Statistics in nanoseconds
+    pub fn ns(&self) -> Stats {
+        self.cycles.scale(1_000_000_000.0 / self.tsc_hz as f64)
+    }
+
+    /// Prints a summary and the machine readable result line:
+    /// `bench: v=1 name=<name> core=<index> samples=<n> iters=<n> overhead=<cycles>
+    /// tsc_hz=<hz> outliers=<n> <stat>_cycles=<x>.. <stat>_ns=<x>.. pmu.<event>=<x>..`
+    pub fn print(&self) {
+        let ns = self.ns();
+        println!(
+            "{}: median {:.2} cycles ({:.2} ns), mean {:.2} +- {:.2} cycles, min {:.2}, p95 {:.2}, {} outliers in {} samples of {} iterations",
+            self.name,
+            self.cycles.median,
+            ns.median,
+            self.cycles.mean,
+            self.cycles.stddev,
+            self.cycles.min,
+            self.cycles.p95,
+            self.cycles.outliers,
+            self.cycles.samples,
+            self.iters
+        );
+        for (event, count) in &self.counters {
+            println!("{}: {}: {:.3}", self.name, event, count);
+        }
+        println!("{}", self.line());
+    }
+
+    /// The machine readable result line
+    pub fn line(&self) -> String {
+        let mut line = format!(
+            "bench: v={} name={} core={} samples={} iters={} overhead={} tsc_hz={} outliers={}",
+            RESULT_VERSION,
+            self.name,
+            self.core_index,
+            self.cycles.samples,
+            self.iters,
+            self.overhead,
+            self.tsc_hz,
+            self.cycles.outliers
+        );
+        for (unit, stats) in [("cycles", self.cycles), ("ns", self.ns())] {
+            for (stat, value) in stats.values() {
+                let _ = write!(line, " {}_{}={:.3}", stat, unit, value);
+            }
+        }
+        for (event, count) in &self.counters {
+            let _ = write!(line, " pmu.{}={:.3}", event, count);
+        }
+        line
+    }
+}
+
+/// Reads the TSC once all prior instructions have completed
+/// and before any later instruction starts
+#[inline(always)]
+fn start_tsc() -> u64 {
+    unsafe {
+        _mm_lfence();
+        let tsc = _rdtsc();
+        _mm_lfence();
+        tsc
+    }
+}
+
+/// TSC cycles of `iters` calls of `f`
+#[inline(never)]
+fn sample<F: FnMut() -> R, R>(f: &mut F, iters: u64) -> u64 {
+    let start = start_tsc();
+    for _ in 0..iters {
+        black_box(f());
+    }
+    time::rdtscp().saturating_sub(start)
+}
 
-        println!("\nSeconds needed: {}", diff);
+/// Cycles a sample of nothing takes
+fn measure_overhead() -> u64 {
+    let mut runs = [0u64; OVERHEAD_RUNS];
+    for run in runs.iter_mut() {
+        let start = start_tsc();
+        *run = time::rdtscp().saturating_sub(start);
     }
+    stats::median(&mut runs)
 }
 
 pub fn overflow() {
@@ -41,7 +263,7 @@ pub fn overflow() {
         asm!("mov {}, rsp", out(reg) x);
     }
     log::info!("Stack ptr: {:#x}", x);
-    core::hint::black_box(a);
+    black_box(a);
     overflow();
 }
 
@@ -62,9 +284,6 @@ pub fn check_support() {
 }
 
 // TODO: When threading is implemented add a counter where execution time is spent most of the time
-// TODO: use ibs execution sampling
-// Use the core performance counters using rdpmc to measure:
-// L2 cache misses
 // Make debug information perf compatible!
 // https://perf.wiki.kernel.org/index.php/Main_Page
 // https://github.com/torvalds/linux/tree/master/tools/perf
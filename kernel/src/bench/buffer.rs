@@ -0,0 +1,129 @@
+use crate::memory::CacheType;
+use crate::vmm::{self, VmmError};
+use core::marker::PhantomData;
+use x86_64::structures::paging::page::PageSize;
+use x86_64::structures::paging::{PageTableFlags, Size1GiB, Size2MiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/*
+ * Huge page buffers for memory benchmarks
+ * A HugeBuffer is physically contiguous and mapped with pages of size S
+ * only, either Size2MiB or Size1GiB. Physical and virtual start are
+ * aligned to S, so every page needs exactly one TLB entry.
+ * The memory type is chosen per buffer through the PAT. Buffers below
+ * the end of the identity map switch their identity mapped alias to the
+ * same type while they live.
+ * The frames come from the numa node of the allocating core
+ * and go back to the frame allocator on drop.
+ */
+
+pub struct HugeBuffer<S: PageSize = Size2MiB> {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    cache_type: CacheType,
+    page_size: PhantomData<S>,
+}
+
+impl<S: PageSize> HugeBuffer<S> {
+    /// Allocates a zeroed buffer of at least `size` bytes,
+    /// the size is rounded up to a multiple of the page size
+    pub fn new(size: u64, cache_type: CacheType) -> Result<Self, VmmError> {
+        if S::SIZE != Size2MiB::SIZE && S::SIZE != Size1GiB::SIZE {
+            return Err(VmmError::UnsupportedPageSize(S::SIZE));
+        }
+        let buffer_size = (size + S::SIZE - 1) / S::SIZE * S::SIZE;
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | cache_type.flags();
+        let (virt, phys) = vmm::alloc_huge::<S>(size, flags, "huge buffer")?;
+        if cache_type != CacheType::WriteBack {
+            unsafe {
+                vmm::set_alias_cache_type(phys, buffer_size, cache_type);
+                // Drop the lines cached through the write back alias on every core
+                for offset in (0..buffer_size).step_by(64) {
+                    core::arch::x86_64::_mm_clflush((virt + offset).as_ptr());
+                }
+                core::arch::x86_64::_mm_mfence();
+            }
+        }
+        let buffer = HugeBuffer {
+            virt,
+            phys,
+            size: buffer_size,
+            cache_type,
+            page_size: PhantomData,
+        };
+        unsafe { core::ptr::write_bytes(buffer.as_mut_ptr::<u8>(), 0, buffer.size as usize) };
+        Ok(buffer)
+    }
+
+    /// Size in bytes, a multiple of the page size
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn num_pages(&self) -> u64 {
+        self.size / S::SIZE
+    }
+
+    pub fn cache_type(&self) -> CacheType {
+        self.cache_type
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.virt.as_ptr()
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.virt.as_mut_ptr()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.size as usize) }
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_mut_ptr(), self.size as usize) }
+    }
+
+    /// Views the buffer as a slice of T.
+    ///
+    /// Unsafe because the caller has to make sure that
+    /// the current content is a valid array of T.
+    pub unsafe fn as_slice<T>(&self) -> &[T] {
+        core::slice::from_raw_parts(
+            self.as_ptr(),
+            self.size as usize / core::mem::size_of::<T>(),
+        )
+    }
+
+    /// Views the buffer as a mutable slice of T, see as_slice
+    pub unsafe fn as_slice_mut<T>(&mut self) -> &mut [T] {
+        core::slice::from_raw_parts_mut(
+            self.as_mut_ptr(),
+            self.size as usize / core::mem::size_of::<T>(),
+        )
+    }
+}
+
+impl<S: PageSize> Drop for HugeBuffer<S> {
+    fn drop(&mut self) {
+        if self.cache_type != CacheType::WriteBack {
+            unsafe {
+                // Drain the write combining buffers before the type changes
+                core::arch::x86_64::_mm_sfence();
+                vmm::set_alias_cache_type(self.phys, self.size, CacheType::WriteBack);
+            }
+        }
+        if let Err(err) = unsafe { vmm::unmap(self.virt) } {
+            log::error!("Failed to free huge buffer at {:#x}: {:?}", self.virt, err);
+        }
+    }
+}
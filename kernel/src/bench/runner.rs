@@ -0,0 +1,138 @@
+use super::{Bench, Report, RESULT_VERSION};
+use crate::pmu::PmuError;
+use crate::smp::{self, ApicState};
+use crate::{ipi, percpu, println};
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+/*
+ * Runner of the benchmarks registered with #[bench_case]
+ * `cargo bench` passes --bench to the executable and glue_gun hands
+ * the arguments of the executable to the kernel command line:
+ *   --bench          run the benchmarks instead of the tests
+ *   --core <index>   run every benchmark on the core with this index
+ *   --all-cores      run every benchmark on all cores at the same time
+ *   <filter>         only run what has one of the filters in its name
+ * Without --core or --all-cores the benchmarks run on the bsp.
+ * A benchmark runs in a cross core call, with interrupts disabled.
+ * Next to the result lines of Report::line the runner prints
+ *   bench-error: v=1 name=<name> core=<index> error=<error>
+ *   bench-done: v=1 ran=<n> failed=<n>
+ * glue_gun collects the lines starting with "bench".
+ */
+
+pub type BenchFn = fn(Bench) -> Result<Report, PmuError>;
+
+/// A benchmark registered with #[bench_case]
+#[derive(Clone, Copy)]
+pub struct BenchCase {
+    name: &'static str,
+    func: BenchFn,
+}
+
+impl BenchCase {
+    pub const fn new(name: &'static str, func: BenchFn) -> Self {
+        BenchCase { name, func }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Runs the benchmark on the current core without printing the results
+    pub fn run(&self) -> Result<Report, PmuError> {
+        (self.func)(Bench::new(self.name).quiet())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cores {
+    Current,
+    Index(usize),
+    All,
+}
+
+#[derive(Debug, Clone)]
+pub struct Args {
+    pub bench: bool,
+    pub cores: Cores,
+    pub filters: Vec<&'static str>,
+}
+
+impl Args {
+    pub fn parse(mut args: impl Iterator<Item = &'static str>) -> Self {
+        let mut parsed = Args {
+            bench: false,
+            cores: Cores::Current,
+            filters: Vec::new(),
+        };
+        while let Some(arg) = args.next() {
+            match arg {
+                "--bench" => parsed.bench = true,
+                "--all-cores" => parsed.cores = Cores::All,
+                "--core" => match args.next().map(str::parse) {
+                    Some(Ok(index)) => parsed.cores = Cores::Index(index),
+                    _ => log::warn!("--core needs a core index"),
+                },
+                _ if arg.starts_with("--") => log::warn!("Unknown argument {}", arg),
+                _ => parsed.filters.push(arg),
+            }
+        }
+        parsed
+    }
+
+    /// Whether the filters select the test or benchmark with this name
+    pub fn matches(&self, name: &str) -> bool {
+        self.filters.is_empty() || self.filters.iter().any(|filter| name.contains(filter))
+    }
+}
+
+/// Runs `case` on the selected cores, returns the core index and result of every run
+fn run_on(case: BenchCase, cores: Cores) -> Vec<(usize, Option<Result<Report, PmuError>>)> {
+    let run = move || (percpu!(core_index), Some(case.run()));
+    match cores {
+        Cores::Current => vec![ipi::call_on(percpu!(apic_id), run)],
+        Cores::Index(index) => match percpu::apic_id_of(index) {
+            Some(apic_id) if smp::get_state(apic_id) == ApicState::Online => {
+                vec![ipi::call_on(apic_id, run)]
+            }
+            _ => vec![(index, None)],
+        },
+        Cores::All => ipi::call_on_all(run),
+    }
+}
+
+/// Runs the benchmarks the filters select, returns the number of failed runs
+pub fn run(cases: &[BenchCase], args: &Args) -> usize {
+    let cases: Vec<&BenchCase> = cases.iter().filter(|c| args.matches(c.name())).collect();
+    println!("Running {} benchmarks", cases.len());
+
+    let (mut ran, mut failed) = (0, 0);
+    for case in cases {
+        for (core_index, result) in run_on(*case, args.cores) {
+            ran += 1;
+            let error = match result {
+                Some(Ok(report)) => {
+                    report.print();
+                    continue;
+                }
+                Some(Err(err)) => format!("{:?}", err),
+                None => "CoreOffline".to_string(),
+            };
+            failed += 1;
+            println!(
+                "bench-error: v={} name={} core={} error={}",
+                RESULT_VERSION,
+                case.name(),
+                core_index,
+                error
+            );
+        }
+    }
+    println!(
+        "bench-done: v={} ran={} failed={}",
+        RESULT_VERSION, ran, failed
+    );
+    failed
+}
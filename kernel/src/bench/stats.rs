@@ -0,0 +1,118 @@
+use core::arch::x86_64::{_mm_cvtsd_f64, _mm_set_sd, _mm_sqrt_sd};
+
+/*
+ * Summary statistics over the samples of a benchmark.
+ * Percentiles interpolate linearly between the two closest samples.
+ * Outliers are samples outside of the Tukey fences, 1.5 times the
+ * interquartile range below the first or above the third quartile.
+ */
+
+/// Square root with sse2, core has no float math
+#[inline]
+pub fn sqrt(x: f64) -> f64 {
+    unsafe {
+        let x = _mm_set_sd(x);
+        _mm_cvtsd_f64(_mm_sqrt_sd(x, x))
+    }
+}
+
+/// Value below which `p` percent of the sorted samples fall
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    assert!(!sorted.is_empty(), "percentile of no samples");
+    let rank = p.max(0.0).min(100.0) / 100.0 * (sorted.len() - 1) as f64;
+    let lower = rank as usize;
+    let upper = (lower + 1).min(sorted.len() - 1);
+    let fraction = rank - lower as f64;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * fraction
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub samples: usize,
+    pub min: f64,
+    pub p5: f64,
+    pub median: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// Sample standard deviation
+    pub stddev: f64,
+    pub outliers: usize,
+}
+
+impl Stats {
+    /// Sorts the samples and summarizes them, None if there are none
+    pub fn new(samples: &mut [f64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let n = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / n;
+        let variance = if samples.len() > 1 {
+            samples.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (n - 1.0)
+        } else {
+            0.0
+        };
+
+        let q1 = percentile(samples, 25.0);
+        let q3 = percentile(samples, 75.0);
+        let fence = 1.5 * (q3 - q1);
+        let outliers = samples
+            .iter()
+            .filter(|x| **x < q1 - fence || **x > q3 + fence)
+            .count();
+
+        Some(Stats {
+            samples: samples.len(),
+            min: samples[0],
+            p5: percentile(samples, 5.0),
+            median: percentile(samples, 50.0),
+            p95: percentile(samples, 95.0),
+            p99: percentile(samples, 99.0),
+            max: samples[samples.len() - 1],
+            mean,
+            stddev: sqrt(variance),
+            outliers,
+        })
+    }
+
+    /// Same statistics with every value multiplied by `factor`,
+    /// e.g. to go from cycles to nanoseconds
+    pub fn scale(&self, factor: f64) -> Stats {
+        Stats {
+            min: self.min * factor,
+            p5: self.p5 * factor,
+            median: self.median * factor,
+            p95: self.p95 * factor,
+            p99: self.p99 * factor,
+            max: self.max * factor,
+            mean: self.mean * factor,
+            stddev: self.stddev * factor,
+            ..*self
+        }
+    }
+
+    /// Name and value of every statistic, in a fixed order
+    pub fn values(&self) -> [(&'static str, f64); 8] {
+        [
+            ("min", self.min),
+            ("p5", self.p5),
+            ("median", self.median),
+            ("p95", self.p95),
+            ("p99", self.p99),
+            ("max", self.max),
+            ("mean", self.mean),
+            ("stddev", self.stddev),
+        ]
+    }
+}
+
+/// Median of raw cycle counts, sorts them
+pub fn median(values: &mut [u64]) -> u64 {
+    assert!(!values.is_empty(), "median of no values");
+    values.sort_unstable();
+    values[values.len() / 2]
+}
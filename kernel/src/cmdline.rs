@@ -0,0 +1,22 @@
+/*
+ * Kernel command line
+ * The bootloader copies the multiboot2 command line into the boot info.
+ * glue_gun puts the arguments that cargo passes to a test or bench
+ * executable there, e.g. `--bench` and name filters.
+ */
+
+static mut BOOT_INFO: Option<&'static bootloader::bootinfo::BootInfo> = None;
+
+pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
+    BOOT_INFO = Some(boot_info);
+}
+
+/// The whole command line, empty before init
+pub fn get() -> &'static str {
+    unsafe { BOOT_INFO.map_or("", |boot_info| boot_info.command_line.as_str()) }
+}
+
+/// Whitespace separated arguments of the command line
+pub fn args() -> impl Iterator<Item = &'static str> {
+    get().split_whitespace()
+}
@@ -0,0 +1,285 @@
+use crate::apic;
+use crate::interrupts::InterruptIndex;
+use crate::percpu;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use x86_64::instructions::interrupts;
+
+/*
+ * Cooperative per core executor
+ * Every core owns exactly one executor indexed by its core index.
+ * Tasks never migrate between cores, a task spawned with `spawn_on`
+ * is pinned to the given core until it completes.
+ * Wakers can be triggered from interrupt handlers and from other cores.
+ * If the target core is halted it gets woken up with a wakeup IPI.
+ */
+static mut EXECUTORS: Option<Vec<Executor>> = None;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl Task {
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+pub struct Executor {
+    core_index: usize,
+    apic_id: u32,
+    /// Tasks owned by this core. Only the owning core polls them
+    tasks: spin::Mutex<BTreeMap<TaskId, Task>>,
+    /// Task ids that have been woken up. Also written by interrupt handlers
+    /// and other cores
+    ready: spin::Mutex<VecDeque<TaskId>>,
+    waker_cache: spin::Mutex<BTreeMap<TaskId, Waker>>,
+    /// Set while the core is about to halt or is halted
+    idle: AtomicBool,
+}
+
+impl Executor {
+    fn new(core_index: usize, apic_id: u32) -> Self {
+        Executor {
+            core_index,
+            apic_id,
+            tasks: spin::Mutex::new(BTreeMap::new()),
+            ready: spin::Mutex::new(VecDeque::new()),
+            waker_cache: spin::Mutex::new(BTreeMap::new()),
+            idle: AtomicBool::new(false),
+        }
+    }
+
+    fn spawn(&self, task: Task) -> TaskId {
+        let id = TaskId::new();
+        if self.tasks.lock().insert(id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.wake(id);
+        id
+    }
+
+    // Interrupts are disabled while holding the ready lock
+    // because interrupt handlers are allowed to wake tasks
+    fn wake(&self, id: TaskId) {
+        interrupts::without_interrupts(|| self.ready.lock().push_back(id));
+        self.notify();
+    }
+
+    fn pop_ready(&self) -> Option<TaskId> {
+        interrupts::without_interrupts(|| self.ready.lock().pop_front())
+    }
+
+    fn has_ready(&self) -> bool {
+        interrupts::without_interrupts(|| !self.ready.lock().is_empty())
+    }
+
+    /// Sends a wakeup IPI if the owning core is halted
+    fn notify(&self) {
+        if self.idle.load(Ordering::SeqCst) && percpu!(apic_id) != self.apic_id {
+            unsafe {
+                apic::send_fixed_ipi(self.apic_id, InterruptIndex::Wakeup.as_u8());
+            }
+        }
+    }
+
+    fn run_ready_tasks(&self) {
+        while let Some(id) = self.pop_ready() {
+            // Take the task out of the map so that the task itself
+            // can spawn new tasks on this core while being polled
+            let mut task = match self.tasks.lock().remove(&id) {
+                Some(task) => task,
+                None => continue, // task no longer exists
+            };
+
+            let waker = self
+                .waker_cache
+                .lock()
+                .entry(id)
+                .or_insert_with(|| TaskWaker::new_waker(id, self.core_index))
+                .clone();
+            let mut context = Context::from_waker(&waker);
+
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {
+                    self.waker_cache.lock().remove(&id);
+                }
+                Poll::Pending => {
+                    self.tasks.lock().insert(id, task);
+                }
+            }
+        }
+    }
+
+    /// Halts the core until the next interrupt if there is no work to do
+    /// `woken` is an additional wake condition checked before halting
+    fn sleep_if_idle(&self, woken: impl Fn() -> bool) {
+        interrupts::disable();
+        // Announce idle before checking the queue. A remote waker pushes
+        // first and checks idle afterwards, thus one of us sees the other
+        self.idle.store(true, Ordering::SeqCst);
+        if !self.has_ready() && !woken() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+        self.idle.store(false, Ordering::SeqCst);
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    core_index: usize,
+}
+
+impl TaskWaker {
+    fn new_waker(task_id: TaskId, core_index: usize) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            task_id,
+            core_index,
+        }))
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        get_by_core_index(self.core_index).wake(self.task_id);
+    }
+}
+
+struct BlockOnWaker {
+    woken: AtomicBool,
+    core_index: usize,
+}
+
+impl Wake for BlockOnWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        get_by_core_index(self.core_index).notify();
+    }
+}
+
+/// Creates one executor per core listed by the bootloader
+/// Has to be called after the heap and the per cpu blocks have been initialized
+pub unsafe fn init() {
+    if EXECUTORS.is_none() {
+        let num_cores = percpu::num_cores();
+        let mut executors = Vec::with_capacity(num_cores);
+        for core_index in 0..num_cores {
+            let apic_id = percpu::apic_id_of(core_index).unwrap();
+            executors.push(Executor::new(core_index, apic_id));
+        }
+        EXECUTORS = Some(executors);
+    }
+}
+
+pub fn get_by_core_index(core_index: usize) -> &'static Executor {
+    unsafe {
+        EXECUTORS
+            .as_ref()
+            .expect("Executor not initialized")
+            .get(core_index)
+            .unwrap()
+    }
+}
+
+pub fn get_by_apic_id(apic_id: u32) -> &'static Executor {
+    get_by_core_index(percpu::core_index_of(apic_id).expect("No core with this apic id"))
+}
+
+/// Returns the executor of the current core
+pub fn current() -> &'static Executor {
+    get_by_core_index(percpu!(core_index))
+}
+
+/// Spawns a task on the current core
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+    spawn_on(percpu!(apic_id), future)
+}
+
+/// Spawns a task pinned to the core with the given apic id
+pub fn spawn_on(apic_id: u32, future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+    get_by_apic_id(apic_id).spawn(Task {
+        future: Box::pin(future),
+    })
+}
+
+/// Runs the future to completion on the current core.
+/// Other tasks of this core keep running while the future is pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let executor = current();
+    let waker_state = Arc::new(BlockOnWaker {
+        woken: AtomicBool::new(true),
+        core_index: executor.core_index,
+    });
+    let waker = Waker::from(waker_state.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+
+    loop {
+        if waker_state.woken.swap(false, Ordering::SeqCst) {
+            if let Poll::Ready(res) = future.as_mut().poll(&mut context) {
+                return res;
+            }
+        }
+        executor.run_ready_tasks();
+        executor.sleep_if_idle(|| waker_state.woken.load(Ordering::SeqCst));
+    }
+}
+
+/// Runs the executor of the current core forever.
+/// Halts the core if no task is ready.
+pub fn run() -> ! {
+    let executor = current();
+    loop {
+        executor.run_ready_tasks();
+        executor.sleep_if_idle(|| false);
+    }
+}
+
+/// Gives other tasks on this core the chance to run
+pub async fn yield_now() {
+    YieldNow { yielded: false }.await
+}
+
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
@@ -0,0 +1,939 @@
+use crate::percpu;
+use crate::time::{Duration, Instant};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicU8, Ordering};
+use packet::{Connection, Response, PACKET_SIZE};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::VirtAddr;
+
+pub mod packet;
+
+/*
+ * GDB remote serial protocol stub on COM2
+ * Breakpoint, debug and NMI exceptions enter through trap entries that
+ * save all general purpose registers in a TrapFrame. The stub stays
+ * inactive until gdb sends its first byte, before that the exceptions
+ * behave like the plain handlers in interrupts.rs.
+ * Once attached, the first core that traps becomes the owner. It stops
+ * the other cores with a NMI and talks to gdb over the polled uart.
+ * Stopped cores park in their NMI handler and show up as threads,
+ * the thread id is the core index plus one. Resuming the owner
+ * releases all of them, hardware breakpoints are loaded on every core
+ * when it leaves the stub.
+ * Nothing here allocates or takes a lock, the stopped cores may hold them.
+ * qemu forwards COM2 to tcp port 8125: `target remote localhost:8125`
+ */
+
+/// Io port of COM2
+const COM2: u16 = 0x2F8;
+
+/// Signals reported in stop replies
+pub const SIGINT: u8 = 2;
+pub const SIGTRAP: u8 = 5;
+pub const SIGABRT: u8 = 6;
+
+/// Time the other cores have to park after the NMI
+const PARK_TIMEOUT: Duration = Duration::from_millis(100);
+
+const MAX_CORES: usize = bootloader::MAX_CORES;
+const MAX_BREAKPOINTS: usize = 64;
+
+const INT3: u8 = 0xcc;
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_RF: u64 = 1 << 16;
+
+/// Marks a core that waits to become the owner in PARKED_BY
+const WAITING: u64 = u64::MAX;
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+/// Session of the current owner as `id << 16 | core index + 1`, zero if all cores run
+static OWNER: AtomicU64 = AtomicU64::new(0);
+static SESSIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Byte the COM2 interrupt read before the stub took over, 0x100 marks it valid
+static PENDING_BYTE: AtomicU16 = AtomicU16::new(0);
+
+// Only used to initialize the arrays below
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_U64: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_U8: AtomicU8 = AtomicU8::new(0);
+
+/// Saved registers of every core inside the stub, zero if the core runs
+static FRAMES: [AtomicU64; MAX_CORES] = [ZERO_U64; MAX_CORES];
+/// Session a core is parked in, or WAITING
+static PARKED_BY: [AtomicU64; MAX_CORES] = [ZERO_U64; MAX_CORES];
+/// Signal reported for the next int3 executed by gdb::breakpoint
+static SIGNALS: [AtomicU8; MAX_CORES] = [ZERO_U8; MAX_CORES];
+
+static mut PHYS_OFFSET: u64 = 0;
+
+/// Registers saved by the trap entries, in reverse order of the pushes
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    // Pushed by the cpu
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// Number of registers of the amd64 register set gdb uses without
+    /// a target description: 17 general purpose registers, eflags,
+    /// 6 segment registers, 16 x87 and 17 sse registers
+    pub const NUM_REGS: usize = 57;
+
+    /// Size of register `n` in the register block
+    pub fn reg_size(n: usize) -> Option<usize> {
+        match n {
+            0..=16 => Some(8),
+            17..=23 => Some(4),
+            24..=31 => Some(10),
+            32..=39 => Some(4),
+            40..=55 => Some(16),
+            56 => Some(4),
+            _ => None,
+        }
+    }
+
+    fn reg_mut(&mut self, n: usize) -> Option<&mut u64> {
+        Some(match n {
+            0 => &mut self.rax,
+            1 => &mut self.rbx,
+            2 => &mut self.rcx,
+            3 => &mut self.rdx,
+            4 => &mut self.rsi,
+            5 => &mut self.rdi,
+            6 => &mut self.rbp,
+            7 => &mut self.rsp,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            16 => &mut self.rip,
+            17 => &mut self.rflags,
+            18 => &mut self.cs,
+            19 => &mut self.ss,
+            _ => return None,
+        })
+    }
+
+    /// Value of gdb register `n`. The data segment registers are the
+    /// same on all cores, the x87 and sse registers are not saved.
+    pub fn reg(&self, n: usize) -> Option<u64> {
+        use x86_64::instructions::segmentation::{Segment, DS, ES, FS, GS};
+        match n {
+            20 => Some(DS::get_reg().0 as u64),
+            21 => Some(ES::get_reg().0 as u64),
+            22 => Some(FS::get_reg().0 as u64),
+            23 => Some(GS::get_reg().0 as u64),
+            n => {
+                let mut frame = *self;
+                frame.reg_mut(n).map(|r| *r)
+            }
+        }
+    }
+
+    /// Sets gdb register `n`, returns false if it is not saved
+    pub fn set_reg(&mut self, n: usize, value: u64) -> bool {
+        match self.reg_mut(n) {
+            Some(r) => {
+                *r = value;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HwKind {
+    Exec = 0b00,
+    Write = 0b01,
+    Access = 0b11,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HwBreakpoint {
+    addr: u64,
+    kind: HwKind,
+    len: u64,
+}
+
+/// State only touched by the owner while all other cores are parked
+struct Stub {
+    /// Software breakpoints with the byte the int3 replaced
+    breakpoints: [Option<(u64, u8)>; MAX_BREAKPOINTS],
+    /// Debug register slots 0-3
+    hw: [Option<HwBreakpoint>; 4],
+    /// Core selected by Hg for register access
+    reg_core: usize,
+    /// Core selected by Hc for stepping, None for all
+    step_core: Option<usize>,
+    /// True if gdb waits for a stop reply
+    running: bool,
+    response: Response,
+}
+
+static mut STUB: Stub = Stub {
+    breakpoints: [None; MAX_BREAKPOINTS],
+    hw: [None; 4],
+    reg_core: 0,
+    step_core: None,
+    running: false,
+    response: Response::new(),
+};
+
+/// Last packet received by the owner
+static mut PACKET: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
+
+impl Stub {
+    /// Value of DR7 enabling all used slots
+    fn dr7(&self) -> u64 {
+        let mut dr7 = 0;
+        for (i, bp) in self.hw.iter().enumerate() {
+            if let Some(bp) = bp {
+                let len = match bp.len {
+                    2 => 0b01,
+                    8 => 0b10,
+                    4 => 0b11,
+                    _ => 0b00,
+                };
+                dr7 |= 1 << (i * 2);
+                dr7 |= (bp.kind as u64 | len << 2) << (16 + i * 4);
+            }
+        }
+        dr7
+    }
+}
+
+/// Initializes COM2 and enables the stub. gdb attaches
+/// by sending anything to COM2.
+pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
+    PHYS_OFFSET = boot_info.physical_memory_offset;
+    uart_16550::SerialPort::new(COM2).init();
+    INITIALIZED.store(true, Ordering::SeqCst);
+    log::info!("gdb stub listens on COM2");
+}
+
+/// Returns true once gdb has connected
+pub fn is_attached() -> bool {
+    ATTACHED.load(Ordering::SeqCst)
+}
+
+/// Stops the current core in the debugger and reports `signal` to gdb.
+/// Does nothing useful if gdb is not attached.
+#[inline(never)]
+pub fn breakpoint(signal: u8) {
+    SIGNALS[core_index()].store(signal, Ordering::SeqCst);
+    unsafe { asm!("int3") };
+}
+
+/// Called by the COM2 interrupt handler. The first byte attaches gdb,
+/// a ctrl-c of an attached gdb stops the kernel.
+pub fn serial_interrupt() {
+    if !INITIALIZED.load(Ordering::SeqCst) {
+        return;
+    }
+    let byte = unsafe { uart_16550::SerialPort::new(COM2).read() };
+    if !ATTACHED.swap(true, Ordering::SeqCst) {
+        PENDING_BYTE.store(0x100 | byte as u16, Ordering::SeqCst);
+        breakpoint(SIGTRAP);
+    } else if byte == 0x03 {
+        breakpoint(SIGINT);
+    }
+}
+
+fn core_index() -> usize {
+    if percpu::is_initialized() {
+        percpu!(core_index)
+    } else {
+        0
+    }
+}
+
+macro_rules! trap_entry {
+    ($name:ident, $vector:literal) => {
+        /// Saves the registers of the interrupted code in a TrapFrame
+        /// on the stack and calls `trap` with it
+        #[naked]
+        pub unsafe extern "C" fn $name() {
+            asm!(
+                concat!("push ", stringify!($vector)),
+                "push rax",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push rbp",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                "mov rdi, rsp",
+                // rbx has been saved, use it to restore the stack
+                "mov rbx, rsp",
+                "and rsp, -16",
+                "sub rsp, 512",
+                "fxsave64 [rsp]",
+                "cld",
+                "call {trap}",
+                "fxrstor64 [rsp]",
+                "mov rsp, rbx",
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rbp",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+                "add rsp, 8",
+                "iretq",
+                trap = sym trap,
+                options(noreturn)
+            );
+        }
+    };
+}
+
+trap_entry!(debug_entry, 1);
+trap_entry!(nmi_entry, 2);
+trap_entry!(breakpoint_entry, 3);
+
+extern "C" fn trap(frame: &mut TrapFrame) {
+    match frame.vector {
+        1 => debug(frame),
+        2 => nmi(frame),
+        3 => int3(frame),
+        vector => panic!("Unexpected trap {}", vector),
+    }
+}
+
+fn debug(frame: &mut TrapFrame) {
+    let dr6 = unsafe { read_dr6() };
+    unsafe { write_dr6(0) };
+    if !is_attached() {
+        log::info!("debug exception");
+        panic!("{:#x?}", frame);
+    }
+    enter(frame, SIGTRAP, dr6, false);
+}
+
+fn int3(frame: &mut TrapFrame) {
+    let signal = SIGNALS[core_index()].swap(0, Ordering::SeqCst);
+    if !is_attached() {
+        log::error!("EXCEPTION: BREAKPOINT\n{:#x?}", frame);
+        return;
+    }
+    match signal {
+        0 => enter(frame, SIGTRAP, 0, true),
+        signal => enter(frame, signal, 0, false),
+    }
+}
+
+fn nmi(frame: &mut TrapFrame) {
+    // Another core panicked and stops everyone else
+    if crate::panic::is_panicking() {
+        crate::panic::halt();
+    }
+    // NMIs do not queue, a stop NMI may have been merged with an IBS NMI
+    let sampling = crate::ibs::handle_nmi();
+    let owner = OWNER.load(Ordering::SeqCst);
+    if owner != 0 {
+        park(frame, owner);
+        return;
+    }
+    // IBS sample or late NMI of a stop that already ended
+    if sampling || is_attached() {
+        return;
+    }
+    log::info!("non maskable interrupt exception");
+    panic!("{:#x?}", frame);
+}
+
+/// Waits in the NMI handler until the owner resumes
+fn park(frame: &mut TrapFrame, owner: u64) {
+    let me = core_index();
+    // Already in the stub or the owner itself
+    if PARKED_BY[me].load(Ordering::SeqCst) == WAITING || owner & 0xffff == me as u64 + 1 {
+        return;
+    }
+    FRAMES[me].store(frame as *mut _ as u64, Ordering::SeqCst);
+    PARKED_BY[me].store(owner, Ordering::SeqCst);
+    while OWNER.load(Ordering::SeqCst) == owner {
+        core::hint::spin_loop();
+    }
+    PARKED_BY[me].store(0, Ordering::SeqCst);
+    FRAMES[me].store(0, Ordering::SeqCst);
+    unsafe { load_debug_regs() };
+}
+
+/// Becomes the owner, stops the other cores and serves gdb until it resumes
+fn enter(frame: &mut TrapFrame, signal: u8, dr6: u64, sw_breakpoint: bool) {
+    let me = core_index();
+    FRAMES[me].store(frame as *mut _ as u64, Ordering::SeqCst);
+    PARKED_BY[me].store(WAITING, Ordering::SeqCst);
+
+    let session = (SESSIONS.fetch_add(1, Ordering::SeqCst) + 1) << 16 | (me as u64 + 1);
+    while OWNER
+        .compare_exchange(0, session, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    PARKED_BY[me].store(0, Ordering::SeqCst);
+
+    // Hardware breakpoints must not fire inside the stub
+    unsafe { asm!("mov dr7, {}", in(reg) 0u64) };
+    stop_other_cores(me, session);
+
+    let stub = unsafe { &mut STUB };
+    // Report the address of the int3 gdb inserted, not the one after it
+    if sw_breakpoint && stub.find_breakpoint(frame.rip.wrapping_sub(1)).is_some() {
+        frame.rip -= 1;
+    }
+    stub.reg_core = me;
+    stub.step_core = None;
+
+    let pending = PENDING_BYTE.swap(0, Ordering::SeqCst);
+    let pending = if pending & 0x100 != 0 {
+        Some(pending as u8)
+    } else {
+        None
+    };
+    let mut conn = unsafe { Connection::new(COM2, pending) };
+    let stop = Stop {
+        signal,
+        core: me,
+        dr6,
+    };
+    if stub.running {
+        stub.response.clear();
+        stub.stop_reply(&stop);
+        conn.send(stub.response.as_bytes());
+    }
+
+    let step = loop {
+        let packet = conn.recv(unsafe { &mut PACKET });
+        stub.response.clear();
+        match stub.handle(packet, &stop, session) {
+            Action::Reply => conn.send(stub.response.as_bytes()),
+            Action::Resume { step } => break step,
+            Action::Detach { reply } => {
+                if reply {
+                    conn.send(b"OK");
+                }
+                stub.remove_all();
+                ATTACHED.store(false, Ordering::SeqCst);
+                break false;
+            }
+        }
+    };
+
+    stub.running = is_attached();
+    stub.prepare_resume(session, step);
+    unsafe { load_debug_regs() };
+    FRAMES[me].store(0, Ordering::SeqCst);
+    OWNER.store(0, Ordering::SeqCst);
+}
+
+/// Sends a NMI to the other cores and waits until the online ones parked
+fn stop_other_cores(me: usize, session: u64) {
+    if crate::smp::num_cores_online() > 1 {
+        unsafe { crate::apic::send_nmi_to_others() };
+    }
+    let deadline = Instant::after(PARK_TIMEOUT);
+    while !deadline.has_passed() {
+        let all_parked = (0..percpu::num_cores())
+            .filter(|i| {
+                *i != me && crate::smp::get_state_by_index(*i) == crate::smp::ApicState::Online
+            })
+            .all(|i| frame_of(i, session).is_some());
+        if all_parked {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Saved registers of a core that is stopped in the given session
+fn frame_of(core: usize, session: u64) -> Option<&'static mut TrapFrame> {
+    if core >= MAX_CORES {
+        return None;
+    }
+    let is_owner = session & 0xffff == core as u64 + 1;
+    let parked_by = PARKED_BY[core].load(Ordering::SeqCst);
+    if !is_owner && parked_by != session && parked_by != WAITING {
+        return None;
+    }
+    let frame = FRAMES[core].load(Ordering::SeqCst);
+    if frame == 0 {
+        return None;
+    }
+    Some(unsafe { &mut *(frame as *mut TrapFrame) })
+}
+
+struct Stop {
+    signal: u8,
+    core: usize,
+    dr6: u64,
+}
+
+enum Action {
+    Reply,
+    Resume { step: bool },
+    Detach { reply: bool },
+}
+
+impl Stub {
+    fn handle(&mut self, packet: &[u8], stop: &Stop, session: u64) -> Action {
+        let (cmd, args) = match packet.split_first() {
+            Some((cmd, args)) => (*cmd, args),
+            None => return Action::Reply,
+        };
+        let result = match cmd {
+            b'?' => {
+                self.stop_reply(stop);
+                Ok(())
+            }
+            b'g' => self.read_registers(session),
+            b'G' => self.write_registers(args, session),
+            b'p' => self.read_register(args, session),
+            b'P' => self.write_register(args, session),
+            b'm' => self.read_memory(args),
+            b'M' => self.write_memory(args),
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    let rip = match packet::parse_hex(args) {
+                        Some(rip) => rip,
+                        None => return self.error(0x16),
+                    };
+                    if let Some(frame) = frame_of(self.reg_core, session) {
+                        frame.rip = rip;
+                    }
+                }
+                return Action::Resume { step: cmd == b's' };
+            }
+            b'H' => self.set_thread(args, session),
+            b'T' => match parse_thread(args).and_then(|core| frame_of(core?, session)) {
+                Some(_) => {
+                    self.response.push_str("OK");
+                    Ok(())
+                }
+                None => Err(0x03),
+            },
+            b'Z' | b'z' => self.breakpoint_packet(cmd == b'Z', args),
+            b'D' => return Action::Detach { reply: true },
+            b'k' => return Action::Detach { reply: false },
+            b'q' => {
+                self.query(args, stop, session);
+                Ok(())
+            }
+            // Unsupported packets get an empty reply
+            _ => Ok(()),
+        };
+        match result {
+            Ok(()) => Action::Reply,
+            Err(errno) => self.error(errno),
+        }
+    }
+
+    fn error(&mut self, errno: u8) -> Action {
+        self.response.clear();
+        self.response.push(b'E');
+        self.response.push_hex(errno);
+        Action::Reply
+    }
+
+    fn stop_reply(&mut self, stop: &Stop) {
+        let _ = write!(
+            self.response,
+            "T{:02x}thread:{:x};",
+            stop.signal,
+            stop.core + 1
+        );
+        // Report the data address of a triggered watchpoint
+        for (i, bp) in self.hw.iter().enumerate() {
+            if let Some(bp) = bp {
+                if stop.dr6 & (1 << i) == 0 {
+                    continue;
+                }
+                let name = match bp.kind {
+                    HwKind::Exec => continue,
+                    HwKind::Write => "watch",
+                    HwKind::Access => "awatch",
+                };
+                let _ = write!(self.response, "{}:{:x};", name, bp.addr);
+            }
+        }
+    }
+
+    fn query(&mut self, args: &[u8], stop: &Stop, session: u64) {
+        let (name, rest) = packet::split(args, b',')
+            .or_else(|| packet::split(args, b':'))
+            .unwrap_or((args, &[]));
+        match name {
+            b"Supported" => {
+                let _ = write!(self.response, "PacketSize={:x}", PACKET_SIZE);
+            }
+            b"Attached" => self.response.push(b'1'),
+            b"C" => {
+                let _ = write!(self.response, "QC{:x}", stop.core + 1);
+            }
+            b"fThreadInfo" => {
+                self.response.push(b'm');
+                let mut first = true;
+                for core in (0..percpu::num_cores()).filter(|i| frame_of(*i, session).is_some()) {
+                    if !first {
+                        self.response.push(b',');
+                    }
+                    first = false;
+                    let _ = write!(self.response, "{:x}", core + 1);
+                }
+            }
+            b"sThreadInfo" => self.response.push(b'l'),
+            b"ThreadExtraInfo" => {
+                if let Some(Some(core)) = parse_thread(rest) {
+                    let apic_id = percpu::apic_id_of(core).unwrap_or(0);
+                    let _ = write!(
+                        HexWriter(&mut self.response),
+                        "core {} apic {}",
+                        core,
+                        apic_id
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn set_thread(&mut self, args: &[u8], session: u64) -> Result<(), u8> {
+        let (op, thread) = args.split_first().ok_or(0x16)?;
+        let core = parse_thread(thread).ok_or(0x16)?;
+        if let Some(core) = core {
+            frame_of(core, session).ok_or(0x03)?;
+        }
+        match op {
+            b'g' => self.reg_core = core.unwrap_or(self.reg_core),
+            b'c' => self.step_core = core,
+            _ => return Err(0x16),
+        }
+        self.response.push_str("OK");
+        Ok(())
+    }
+
+    fn read_registers(&mut self, session: u64) -> Result<(), u8> {
+        let frame = frame_of(self.reg_core, session).ok_or(0x03)?;
+        for n in 0..TrapFrame::NUM_REGS {
+            let size = TrapFrame::reg_size(n).unwrap();
+            self.response.push_hex_le(frame.reg(n).unwrap_or(0), size);
+        }
+        Ok(())
+    }
+
+    fn write_registers(&mut self, args: &[u8], session: u64) -> Result<(), u8> {
+        let frame = frame_of(self.reg_core, session).ok_or(0x03)?;
+        let mut offset = 0;
+        // The segment, x87 and sse registers are ignored
+        for n in 0..=19 {
+            let size = TrapFrame::reg_size(n).unwrap() * 2;
+            let hex = args.get(offset..offset + size).ok_or(0x16)?;
+            frame.set_reg(n, parse_hex_le(hex).ok_or(0x16)?);
+            offset += size;
+        }
+        self.response.push_str("OK");
+        Ok(())
+    }
+
+    fn read_register(&mut self, args: &[u8], session: u64) -> Result<(), u8> {
+        let n = packet::parse_hex(args).ok_or(0x16)? as usize;
+        let size = TrapFrame::reg_size(n).ok_or(0x16)?;
+        let frame = frame_of(self.reg_core, session).ok_or(0x03)?;
+        self.response.push_hex_le(frame.reg(n).unwrap_or(0), size);
+        Ok(())
+    }
+
+    fn write_register(&mut self, args: &[u8], session: u64) -> Result<(), u8> {
+        let (n, value) = packet::split(args, b'=').ok_or(0x16)?;
+        let n = packet::parse_hex(n).ok_or(0x16)? as usize;
+        let frame = frame_of(self.reg_core, session).ok_or(0x03)?;
+        // Registers that are not saved accept any value
+        if n <= 19 {
+            frame.set_reg(n, parse_hex_le(value).ok_or(0x16)?);
+        }
+        self.response.push_str("OK");
+        Ok(())
+    }
+
+    fn read_memory(&mut self, args: &[u8]) -> Result<(), u8> {
+        let (addr, len) = parse_range(args).ok_or(0x16)?;
+        let len = core::cmp::min(len, PACKET_SIZE as u64 / 2);
+        if !is_mapped_range(addr, len) {
+            return Err(0x0e);
+        }
+        for i in 0..len {
+            let byte = unsafe { core::ptr::read_volatile((addr + i) as *const u8) };
+            self.response.push_hex(byte);
+        }
+        Ok(())
+    }
+
+    fn write_memory(&mut self, args: &[u8]) -> Result<(), u8> {
+        let (range, data) = packet::split(args, b':').ok_or(0x16)?;
+        let (addr, len) = parse_range(range).ok_or(0x16)?;
+        if data.len() as u64 != len * 2 {
+            return Err(0x16);
+        }
+        for (i, pair) in data.chunks(2).enumerate() {
+            let mut byte = [0];
+            packet::decode_hex(pair, &mut byte).ok_or(0x16)?;
+            if !unsafe { poke(addr + i as u64, byte[0]) } {
+                return Err(0x0e);
+            }
+        }
+        self.response.push_str("OK");
+        Ok(())
+    }
+
+    fn breakpoint_packet(&mut self, insert: bool, args: &[u8]) -> Result<(), u8> {
+        let (kind, rest) = packet::split(args, b',').ok_or(0x16)?;
+        let (addr, len) = parse_range(rest).ok_or(0x16)?;
+        let kind = match kind {
+            b"0" => {
+                if insert {
+                    self.insert_breakpoint(addr)?;
+                } else {
+                    self.remove_breakpoint(addr)?;
+                }
+                self.response.push_str("OK");
+                return Ok(());
+            }
+            b"1" => HwKind::Exec,
+            b"2" => HwKind::Write,
+            b"4" => HwKind::Access,
+            // Read only watchpoints do not exist on x86
+            _ => return Ok(()),
+        };
+
+        let len = if kind == HwKind::Exec { 1 } else { len };
+        if !matches!(len, 1 | 2 | 4 | 8) || addr % len != 0 {
+            return Err(0x16);
+        }
+        let same = |bp: &Option<HwBreakpoint>| matches!(bp, Some(bp) if bp.addr == addr && bp.kind == kind && bp.len == len);
+        if insert {
+            let slot = self.hw.iter_mut().find(|bp| bp.is_none()).ok_or(0x1c)?;
+            *slot = Some(HwBreakpoint { addr, kind, len });
+        } else if let Some(slot) = self.hw.iter_mut().find(|bp| same(bp)) {
+            *slot = None;
+        }
+        self.response.push_str("OK");
+        Ok(())
+    }
+
+    fn find_breakpoint(&self, addr: u64) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .position(|bp| matches!(bp, Some((a, _)) if *a == addr))
+    }
+
+    fn insert_breakpoint(&mut self, addr: u64) -> Result<(), u8> {
+        if self.find_breakpoint(addr).is_some() {
+            return Ok(());
+        }
+        let slot = self.breakpoints.iter().position(|bp| bp.is_none());
+        let slot = slot.ok_or(0x1c)?;
+        if !is_mapped_range(addr, 1) {
+            return Err(0x0e);
+        }
+        let original = unsafe { core::ptr::read_volatile(addr as *const u8) };
+        if !unsafe { poke(addr, INT3) } {
+            return Err(0x0e);
+        }
+        self.breakpoints[slot] = Some((addr, original));
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, addr: u64) -> Result<(), u8> {
+        if let Some(slot) = self.find_breakpoint(addr) {
+            let (addr, original) = self.breakpoints[slot].take().unwrap();
+            if !unsafe { poke(addr, original) } {
+                return Err(0x0e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes all breakpoints when gdb detaches
+    fn remove_all(&mut self) {
+        let breakpoints = self.breakpoints;
+        for (addr, _) in breakpoints.iter().flatten() {
+            let _ = self.remove_breakpoint(*addr);
+        }
+        self.hw = [None; 4];
+        self.running = false;
+    }
+
+    /// Sets the trap flag on the core that steps and clears it on all others.
+    /// The resume flag keeps an instruction breakpoint from firing again.
+    fn prepare_resume(&mut self, session: u64, step: bool) {
+        let step_core = self.step_core.unwrap_or(self.reg_core);
+        for core in 0..percpu::num_cores() {
+            if let Some(frame) = frame_of(core, session) {
+                frame.rflags = (frame.rflags & !RFLAGS_TF) | RFLAGS_RF;
+                if step && core == step_core {
+                    frame.rflags |= RFLAGS_TF;
+                }
+            }
+        }
+    }
+}
+
+/// Writes text hex encoded, as in qThreadExtraInfo replies
+struct HexWriter<'a>(&'a mut Response);
+
+impl Write for HexWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        s.bytes().for_each(|c| self.0.push_hex(c));
+        Ok(())
+    }
+}
+
+/// Parses a thread id, None stands for all threads (-1 or 0)
+fn parse_thread(thread: &[u8]) -> Option<Option<usize>> {
+    if thread == b"-1" {
+        return Some(None);
+    }
+    match packet::parse_hex(thread)? {
+        0 => Some(None),
+        id => Some(Some(id as usize - 1)),
+    }
+}
+
+/// Parses `addr,len`
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let (addr, len) = packet::split(args, b',')?;
+    Some((packet::parse_hex(addr)?, packet::parse_hex(len)?))
+}
+
+/// Parses a register value sent in target byte order
+fn parse_hex_le(hex: &[u8]) -> Option<u64> {
+    let mut bytes = [0; 8];
+    let len = packet::decode_hex(&hex[..core::cmp::min(hex.len(), 16)], &mut bytes)?;
+    if len == 0 {
+        return None;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Walks the active page table, the mapper lock may be held by a parked core
+fn is_mapped(addr: u64) -> bool {
+    let addr = match VirtAddr::try_new(addr) {
+        Ok(addr) => addr,
+        Err(_) => return false,
+    };
+    let mut table = Cr3::read().0.start_address();
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    for (level, index) in indices.iter().enumerate() {
+        let entries = unsafe { &*((PHYS_OFFSET + table.as_u64()) as *const PageTable) };
+        let entry = &entries[*index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        if level == 3 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        table = entry.addr();
+    }
+    false
+}
+
+fn is_mapped_range(addr: u64, len: u64) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    let mut page = addr & !0xfff;
+    while page < end {
+        if !is_mapped(page) {
+            return false;
+        }
+        page += 0x1000;
+    }
+    true
+}
+
+/// Writes a byte even into read only kernel code
+unsafe fn poke(addr: u64, value: u8) -> bool {
+    if !is_mapped_range(addr, 1) {
+        return false;
+    }
+    let cr0 = Cr0::read();
+    Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+    core::ptr::write_volatile(addr as *mut u8, value);
+    Cr0::write(cr0);
+    true
+}
+
+/// Loads the hardware breakpoints of the stub into the debug registers
+unsafe fn load_debug_regs() {
+    let addrs = STUB.hw.map(|bp| bp.map_or(0, |bp| bp.addr));
+    asm!("mov dr0, {}", in(reg) addrs[0]);
+    asm!("mov dr1, {}", in(reg) addrs[1]);
+    asm!("mov dr2, {}", in(reg) addrs[2]);
+    asm!("mov dr3, {}", in(reg) addrs[3]);
+    asm!("mov dr7, {}", in(reg) STUB.dr7());
+}
+
+unsafe fn read_dr6() -> u64 {
+    let dr6: u64;
+    asm!("mov {}, dr6", out(reg) dr6);
+    dr6
+}
+
+unsafe fn write_dr6(value: u64) {
+    asm!("mov dr6, {}", in(reg) value);
+}
@@ -0,0 +1,193 @@
+use core::fmt;
+use uart_16550::SerialPort;
+
+/*
+ * Packet framing of the gdb remote serial protocol
+ * A packet is `$<data>#<checksum>`, the checksum is the sum of the data
+ * bytes modulo 256 as two hex digits. Every packet is acknowledged with
+ * `+`, or with `-` to request a retransmission.
+ * Memory and register contents are sent as hex, the stub never sends
+ * binary data and does not use run length encoding.
+ */
+
+/// Largest packet the stub accepts, advertised in qSupported
+pub const PACKET_SIZE: usize = 4096;
+
+/// Sum of all bytes modulo 256
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, c| sum.wrapping_add(*c))
+}
+
+/// Value of a single hex digit
+pub fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Two lowercase hex digits of a byte
+pub fn hex_chars(byte: u8) -> [u8; 2] {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    [DIGITS[(byte >> 4) as usize], DIGITS[(byte & 0xf) as usize]]
+}
+
+/// Parses a big endian hex number like the addresses and lengths in packets
+pub fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter()
+        .try_fold(0, |value, c| Some(value << 4 | hex_digit(*c)? as u64))
+}
+
+/// Decodes pairs of hex digits into `out`, returns the number of bytes
+pub fn decode_hex(hex: &[u8], out: &mut [u8]) -> Option<usize> {
+    if hex.len() % 2 != 0 || hex.len() / 2 > out.len() {
+        return None;
+    }
+    for (byte, pair) in out.iter_mut().zip(hex.chunks(2)) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(hex.len() / 2)
+}
+
+/// Splits `data` at the first `sep`, without the separator
+pub fn split(data: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let pos = data.iter().position(|c| *c == sep)?;
+    Some((&data[..pos], &data[pos + 1..]))
+}
+
+/// Data of a reply packet, overlong replies are truncated
+pub struct Response {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Response {
+    pub const fn new() -> Self {
+        Response {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn push(&mut self, c: u8) {
+        if self.len < PACKET_SIZE {
+            self.buf[self.len] = c;
+            self.len += 1;
+        }
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|c| self.push(c));
+    }
+
+    /// Appends a byte as two hex digits
+    pub fn push_hex(&mut self, byte: u8) {
+        hex_chars(byte).iter().for_each(|c| self.push(*c));
+    }
+
+    /// Appends the lowest `size` bytes of `value` in target byte order,
+    /// bytes past the eighth are zero
+    pub fn push_hex_le(&mut self, value: u64, size: usize) {
+        for i in 0..size {
+            let byte = if i < 8 { (value >> (i * 8)) as u8 } else { 0 };
+            self.push_hex(byte);
+        }
+    }
+}
+
+impl fmt::Write for Response {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+/// Polled serial connection to gdb
+pub struct Connection {
+    port: SerialPort,
+    pending: Option<u8>,
+}
+
+impl Connection {
+    /// `pending` is a byte that has already been read from the port
+    pub unsafe fn new(base: u16, pending: Option<u8>) -> Self {
+        Connection {
+            port: SerialPort::new(base),
+            pending,
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        match self.pending.take() {
+            Some(c) => c,
+            None => self.port.receive(),
+        }
+    }
+
+    fn read_hex_byte(&mut self) -> Option<u8> {
+        let high = hex_digit(self.read())?;
+        let low = hex_digit(self.read())?;
+        Some(high << 4 | low)
+    }
+
+    /// Waits for the next packet with a valid checksum, acknowledges it
+    /// and returns its data. Interrupt requests and acks are skipped.
+    pub fn recv<'a>(&mut self, buf: &'a mut [u8; PACKET_SIZE]) -> &'a [u8] {
+        loop {
+            while self.read() != b'$' {}
+
+            let mut len = 0;
+            let mut overflow = false;
+            loop {
+                match self.read() {
+                    b'#' => break,
+                    c if len < PACKET_SIZE => {
+                        buf[len] = c;
+                        len += 1;
+                    }
+                    _ => overflow = true,
+                }
+            }
+
+            let valid = self.read_hex_byte() == Some(checksum(&buf[..len]));
+            if valid && !overflow {
+                self.port.send(b'+');
+                return &buf[..len];
+            }
+            self.port.send(b'-');
+        }
+    }
+
+    /// Sends a packet until gdb acknowledges it
+    pub fn send(&mut self, data: &[u8]) {
+        loop {
+            self.port.send(b'$');
+            data.iter().for_each(|c| self.port.send(*c));
+            self.port.send(b'#');
+            hex_chars(checksum(data))
+                .iter()
+                .for_each(|c| self.port.send(*c));
+
+            match self.read() {
+                b'-' => continue,
+                // gdb missed the ack and already sends the next packet
+                b'$' => self.pending = Some(b'$'),
+                _ => {}
+            }
+            return;
+        }
+    }
+}
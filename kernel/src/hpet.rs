@@ -0,0 +1,141 @@
+use crate::acpi::Acpi;
+use crate::hpet_regs::*;
+use core::ptr::{addr_of, read_unaligned, read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::PhysAddr;
+
+/// Femtoseconds per second
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+/// Femtoseconds per nanosecond
+const FS_PER_NS: u64 = 1_000_000;
+
+/// Base address of the HPET MMIO space, zero if no HPET exists
+static HPET_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Main counter tick period in femtoseconds
+static HPET_PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+/// Maps the HPET found in the ACPI tables and starts its main counter.
+/// Does nothing if the machine has no HPET.
+pub unsafe fn init(acpi: &Acpi) {
+    if is_available() {
+        return;
+    }
+
+    let table = match acpi.hpet.as_ref() {
+        Some(table) => table,
+        None => {
+            log::warn!("No HPET found");
+            return;
+        }
+    };
+
+    if table.base_address.address_space_id != 0 {
+        log::warn!("HPET is not memory mapped, ignoring it");
+        return;
+    }
+    let phys_addr = read_unaligned(addr_of!(table.base_address.address));
+
+    let base_addr = match crate::vmm::map_mmio(PhysAddr::new(phys_addr), 0x400, "hpet") {
+        Ok(virt) => virt.as_u64(),
+        Err(err) => {
+            log::warn!("Failed to map the HPET: {:?}", err);
+            return;
+        }
+    };
+
+    let cap = GeneralCapId::from_bytes(read_hpet(base_addr, Register::GeneralCapId).to_le_bytes());
+    let period = cap.counter_clk_period() as u64;
+
+    // Spec says the period has to be less or equal to 100 nanoseconds
+    if period == 0 || period > 100 * FS_PER_NS {
+        log::warn!("HPET has an invalid period of {} fs, ignoring it", period);
+        return;
+    }
+
+    if cap.count_size_cap() == 0 {
+        log::warn!("HPET main counter is only 32 bit wide");
+    }
+
+    // Stop the counter, reset it and start it again
+    // without legacy replacement routing
+    let conf = GeneralConf::from_bytes(read_hpet(base_addr, Register::GeneralConf).to_le_bytes())
+        .with_enable(0)
+        .with_legacy_replacement(0);
+    write_hpet(base_addr, Register::GeneralConf, u64::from_le_bytes(conf.into_bytes()));
+    write_hpet(base_addr, Register::MainCounter, 0);
+    let conf = conf.with_enable(1);
+    write_hpet(base_addr, Register::GeneralConf, u64::from_le_bytes(conf.into_bytes()));
+
+    HPET_PERIOD_FS.store(period, Ordering::SeqCst);
+    HPET_BASE.store(base_addr, Ordering::SeqCst);
+
+    log::info!(
+        "HPET at {:#x} with {} timers runs at {} Hz",
+        phys_addr,
+        cap.num_timers() + 1,
+        frequency()
+    );
+}
+
+unsafe fn read_hpet(base_addr: u64, register: Register) -> u64 {
+    read_volatile((base_addr + register as u64) as *const u64)
+}
+
+unsafe fn write_hpet(base_addr: u64, register: Register, value: u64) {
+    write_volatile((base_addr + register as u64) as *mut u64, value);
+}
+
+/// Returns true if a HPET has been found and its counter is running
+#[inline]
+pub fn is_available() -> bool {
+    HPET_BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Reads the main counter of the HPET
+#[inline]
+pub fn counter() -> u64 {
+    let base_addr = HPET_BASE.load(Ordering::Relaxed);
+    if base_addr == 0 {
+        panic!("HPET is not available");
+    }
+    unsafe { read_hpet(base_addr, Register::MainCounter) }
+}
+
+/// Main counter tick period in femtoseconds
+#[inline]
+pub fn period_fs() -> u64 {
+    HPET_PERIOD_FS.load(Ordering::Relaxed)
+}
+
+/// Main counter frequency in Hz
+#[inline]
+pub fn frequency() -> u64 {
+    FS_PER_SEC / period_fs()
+}
+
+/// Converts main counter ticks into nanoseconds
+#[inline]
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    ((ticks as u128 * period_fs() as u128) / FS_PER_NS as u128) as u64
+}
+
+/// Returns the nanoseconds elapsed since a prior counter value
+#[inline]
+pub fn elapsed_ns(start: u64) -> u64 {
+    ticks_to_ns(counter().wrapping_sub(start))
+}
+
+/// Busy waits at least the given number of nanoseconds.
+/// Returns the exact number of nanoseconds that elapsed.
+pub fn busy_wait_ns(ns: u64) -> u64 {
+    let start = counter();
+    loop {
+        let elapsed = elapsed_ns(start);
+        if elapsed >= ns {
+            return elapsed;
+        }
+        core::hint::spin_loop();
+    }
+}
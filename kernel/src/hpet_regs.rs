@@ -0,0 +1,32 @@
+use modular_bitfield::prelude::*;
+
+/// HPET registers (offsets into MMIO space)
+#[derive(Clone, Copy)]
+#[repr(u64)]
+pub enum Register {
+    GeneralCapId = 0x000,
+    GeneralConf = 0x010,
+    GeneralIntStatus = 0x020,
+    MainCounter = 0x0F0,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct GeneralCapId {
+    pub rev_id: B8,
+    pub num_timers: B5,
+    pub count_size_cap: B1,
+    pub res0: B1,
+    pub legacy_replacement_cap: B1,
+    pub vendor_id: B16,
+    /// Main counter tick period in femtoseconds
+    pub counter_clk_period: B32,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct GeneralConf {
+    pub enable: B1,
+    pub legacy_replacement: B1,
+    pub res0: B62,
+}
@@ -0,0 +1,446 @@
+use crate::ibs_regs::*;
+use crate::profile::ring::SampleRing;
+use crate::profile::stream::{self, Kind, StreamSample};
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use raw_cpuid::CpuId;
+use x86_64::registers::model_specific::Msr;
+
+/*
+ * AMD instruction based sampling
+ * IBS tags one op out of every period cycles, or one instruction fetch
+ * out of every period fetches, and records what happened to it: its rip,
+ * the data address, whether the caches and TLBs hit and the latencies.
+ * Once the sample is complete the core raises the IBS interrupt through
+ * the extended LVT entry the BIOS reports in IBS_CTL. The entry delivers
+ * an NMI, so code running with interrupts disabled is sampled as well.
+ * The NMI handler copies the sample into the ring buffer of its core
+ * and re-arms IBS. After stopping, dump writes the rings as binary
+ * records to COM1, see profile::stream.
+ */
+
+/// Samples of each kind every core keeps until they get dumped
+pub const RING_SAMPLES: usize = 4096;
+
+/// Shortest period, shorter ones drown the core in NMIs
+pub const MIN_PERIOD: u32 = 0x1000;
+
+#[derive(Debug, Clone, Copy)]
+struct Ibs {
+    features: IbsCpuid,
+    lvt_offset: u8,
+}
+
+static mut IBS: Option<Ibs> = None;
+
+/// Set while the cores sample
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Serializes start, stop and reading the rings
+static CONTROL: spin::Mutex<()> = spin::Mutex::new(());
+
+/// IBS_OP_CTL and IBS_FETCH_CTL values that arm sampling, zero if disabled
+static OP_CTL: AtomicU64 = AtomicU64::new(0);
+static FETCH_CTL: AtomicU64 = AtomicU64::new(0);
+
+/// Rings of every core, indexed by the core index
+static mut OP_RINGS: Vec<SampleRing<OpSample>> = Vec::new();
+static mut FETCH_RINGS: Vec<SampleRing<FetchSample>> = Vec::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IbsError {
+    /// No IBS or not the requested kind of sampling
+    NotSupported,
+    /// Period is below MIN_PERIOD or too large for the counter
+    InvalidPeriod,
+    /// Sampling has already been started
+    Running,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Cycles, or dispatched ops with `count_ops`, between op samples
+    pub op_period: Option<u32>,
+    /// Instruction fetches between fetch samples
+    pub fetch_period: Option<u32>,
+    pub count_ops: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            op_period: Some(0x10000),
+            fetch_period: None,
+            count_ops: false,
+        }
+    }
+}
+
+/// A sampled op
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OpSample {
+    pub rip: u64,
+    /// Linear address of a load or store, zero if there is none
+    pub data_addr: u64,
+    /// Cycles from tagging the op to its retirement
+    pub tag_to_retire: u16,
+    /// Cycles a data cache miss waited for its data
+    pub miss_latency: u16,
+    pub flags: u16,
+    /// Family specific source of the data of a load that missed the caches
+    pub data_src: u8,
+}
+
+impl OpSample {
+    pub const LOAD: u16 = 1 << 0;
+    pub const STORE: u16 = 1 << 1;
+    pub const DC_MISS: u16 = 1 << 2;
+    pub const L2_MISS: u16 = 1 << 3;
+    pub const DTLB_L1_MISS: u16 = 1 << 4;
+    pub const DTLB_L2_MISS: u16 = 1 << 5;
+    pub const BRANCH: u16 = 1 << 6;
+    pub const BRANCH_MISPREDICT: u16 = 1 << 7;
+    pub const UNCACHEABLE: u16 = 1 << 8;
+    pub const LOCKED: u16 = 1 << 9;
+    /// The rip does not belong to the op
+    pub const RIP_INVALID: u16 = 1 << 10;
+
+    pub fn has(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+/// Encoded as rip u64, data_addr u64, tag_to_retire u16,
+/// miss_latency u16, flags u16, data_src u8 and a zero byte
+impl StreamSample for OpSample {
+    const KIND: Kind = Kind::IbsOp;
+    const SIZE: usize = 24;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.rip.to_le_bytes());
+        out.extend_from_slice(&self.data_addr.to_le_bytes());
+        out.extend_from_slice(&self.tag_to_retire.to_le_bytes());
+        out.extend_from_slice(&self.miss_latency.to_le_bytes());
+        out.extend_from_slice(&self.flags.to_le_bytes());
+        out.push(self.data_src);
+        out.push(0);
+    }
+}
+
+/// A sampled instruction fetch
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FetchSample {
+    /// Linear address of the fetch
+    pub rip: u64,
+    /// Cycles from the fetch request to its completion
+    pub latency: u16,
+    pub flags: u16,
+}
+
+impl FetchSample {
+    pub const COMPLETED: u16 = 1 << 0;
+    pub const IC_MISS: u16 = 1 << 1;
+    pub const ITLB_L1_MISS: u16 = 1 << 2;
+    pub const ITLB_L2_MISS: u16 = 1 << 3;
+    pub const L2_MISS: u16 = 1 << 4;
+
+    pub fn has(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+/// Encoded as rip u64, latency u16, flags u16 and four zero bytes
+impl StreamSample for FetchSample {
+    const KIND: Kind = Kind::IbsFetch;
+    const SIZE: usize = 16;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.rip.to_le_bytes());
+        out.extend_from_slice(&self.latency.to_le_bytes());
+        out.extend_from_slice(&self.flags.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+    }
+}
+
+impl Ibs {
+    /// IBS_OP_CTL value that samples every `period` cycles or ops
+    fn op_ctl(&self, period: u32, count_ops: bool) -> Result<u64, IbsError> {
+        if self.features.op_sam() == 0 || (count_ops && self.features.op_cnt() == 0) {
+            return Err(IbsError::NotSupported);
+        }
+        let max_cnt = period >> 4;
+        let limit = if self.features.op_cnt_ext() == 1 {
+            (1 << 23) - 1
+        } else {
+            u16::MAX as u32
+        };
+        if period < MIN_PERIOD || max_cnt > limit {
+            return Err(IbsError::InvalidPeriod);
+        }
+        let ctl = IbsOpCtl::new()
+            .with_op_max_cnt(max_cnt as u16)
+            .with_op_max_cnt_ext((max_cnt >> 16) as u8)
+            .with_op_cnt_ctl(count_ops as u8)
+            .with_op_en(1);
+        Ok(u64::from_le_bytes(ctl.into_bytes()))
+    }
+
+    /// IBS_FETCH_CTL value that samples every `period` fetches
+    fn fetch_ctl(&self, period: u32) -> Result<u64, IbsError> {
+        if self.features.fetch_sam() == 0 {
+            return Err(IbsError::NotSupported);
+        }
+        let max_cnt = period >> 4;
+        if period < MIN_PERIOD || max_cnt > u16::MAX as u32 {
+            return Err(IbsError::InvalidPeriod);
+        }
+        let ctl = IbsFetchCtl::new()
+            .with_fetch_max_cnt(max_cnt as u16)
+            .with_fetch_en(1);
+        Ok(u64::from_le_bytes(ctl.into_bytes()))
+    }
+}
+
+/// Detects IBS, called once on the bsp
+pub unsafe fn init() {
+    let has_ibs = CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |info| info.has_ibs());
+    if !has_ibs {
+        log::info!("No instruction based sampling");
+        return;
+    }
+
+    if __cpuid(0x8000_0000).eax < 0x8000_001B {
+        log::warn!("IBS without feature leaf, disabled");
+        return;
+    }
+    let features = IbsCpuid::from_bytes(__cpuid(0x8000_001B).eax.to_le_bytes());
+    if features.ffv() == 0 {
+        log::warn!("IBS feature flags are invalid, disabled");
+        return;
+    }
+
+    let ctl = IbsCtl::from_bytes(Msr::new(IBS_CTL).read().to_le_bytes());
+    if ctl.lvt_offset_val() == 0 || ctl.lvt_offset() > 3 {
+        log::warn!("IBS has no valid LVT offset, disabled");
+        return;
+    }
+
+    log::info!(
+        "IBS fetch sampling: {}, op sampling: {}, LVT offset: {}",
+        features.fetch_sam() == 1,
+        features.op_sam() == 1,
+        ctl.lvt_offset()
+    );
+    IBS = Some(Ibs {
+        features,
+        lvt_offset: ctl.lvt_offset(),
+    });
+}
+
+/// Returns true if the cpu supports IBS
+pub fn is_available() -> bool {
+    unsafe { IBS.is_some() }
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// Clears the rings and starts sampling on all online cores
+pub fn start(config: Config) -> Result<(), IbsError> {
+    let ibs = unsafe { IBS.ok_or(IbsError::NotSupported)? };
+    let op_ctl = match config.op_period {
+        Some(period) => ibs.op_ctl(period, config.count_ops)?,
+        None => 0,
+    };
+    let fetch_ctl = match config.fetch_period {
+        Some(period) => ibs.fetch_ctl(period)?,
+        None => 0,
+    };
+
+    let _control = CONTROL.lock();
+    if is_running() {
+        return Err(IbsError::Running);
+    }
+    unsafe {
+        if OP_RINGS.is_empty() {
+            let cores = crate::percpu::num_cores();
+            OP_RINGS = (0..cores).map(|_| SampleRing::new(RING_SAMPLES)).collect();
+            FETCH_RINGS = (0..cores).map(|_| SampleRing::new(RING_SAMPLES)).collect();
+        }
+        OP_RINGS.iter_mut().for_each(|ring| ring.clear());
+        FETCH_RINGS.iter_mut().for_each(|ring| ring.clear());
+    }
+
+    OP_CTL.store(op_ctl, Ordering::SeqCst);
+    FETCH_CTL.store(fetch_ctl, Ordering::SeqCst);
+    RUNNING.store(true, Ordering::SeqCst);
+    crate::ipi::call_on_all(move || unsafe {
+        crate::apic::set_extended_lvt_nmi(ibs.lvt_offset, true);
+        if fetch_ctl != 0 {
+            Msr::new(IBS_FETCH_CTL).write(fetch_ctl);
+        }
+        if op_ctl != 0 {
+            Msr::new(IBS_OP_CTL).write(op_ctl);
+        }
+    });
+    Ok(())
+}
+
+/// Stops sampling on all online cores
+pub fn stop() {
+    let _control = CONTROL.lock();
+    if !is_running() {
+        return;
+    }
+    let ibs = unsafe { IBS.unwrap() };
+    crate::ipi::call_on_all(move || unsafe {
+        Msr::new(IBS_OP_CTL).write(0);
+        Msr::new(IBS_FETCH_CTL).write(0);
+        crate::apic::set_extended_lvt_nmi(ibs.lvt_offset, false);
+    });
+    // An NMI raised right before the stop has been delivered by now
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+/// Called on every NMI. Stores a pending sample of the current core.
+/// Returns true while sampling, the NMI may have come from IBS then.
+pub fn handle_nmi() -> bool {
+    if !is_running() {
+        return false;
+    }
+    let core = crate::percpu!(core_index);
+    unsafe {
+        let mut op_ctl = Msr::new(IBS_OP_CTL);
+        if IbsOpCtl::from_bytes(op_ctl.read().to_le_bytes()).op_val() == 1 {
+            if let Some(ring) = OP_RINGS.get(core) {
+                ring.push(read_op_sample());
+            }
+            // Re-arm with a fresh count
+            op_ctl.write(OP_CTL.load(Ordering::Relaxed));
+        }
+
+        let mut fetch_ctl = Msr::new(IBS_FETCH_CTL);
+        let fetch = IbsFetchCtl::from_bytes(fetch_ctl.read().to_le_bytes());
+        if fetch.fetch_val() == 1 {
+            if let Some(ring) = FETCH_RINGS.get(core) {
+                ring.push(read_fetch_sample(fetch));
+            }
+            fetch_ctl.write(FETCH_CTL.load(Ordering::Relaxed));
+        }
+    }
+    true
+}
+
+unsafe fn read_op_sample() -> OpSample {
+    let data = IbsOpData::from_bytes(Msr::new(IBS_OP_DATA).read().to_le_bytes());
+    let data2 = IbsOpData2::from_bytes(Msr::new(IBS_OP_DATA2).read().to_le_bytes());
+    let data3 = IbsOpData3::from_bytes(Msr::new(IBS_OP_DATA3).read().to_le_bytes());
+
+    let bits = [
+        (data3.ld_op(), OpSample::LOAD),
+        (data3.st_op(), OpSample::STORE),
+        (data3.dc_miss(), OpSample::DC_MISS),
+        (data3.l2_miss(), OpSample::L2_MISS),
+        (data3.dc_l1_tlb_miss(), OpSample::DTLB_L1_MISS),
+        (data3.dc_l2_tlb_miss(), OpSample::DTLB_L2_MISS),
+        (data.op_brn_ret(), OpSample::BRANCH),
+        (data.op_brn_misp(), OpSample::BRANCH_MISPREDICT),
+        (data3.dc_uc_mem_acc(), OpSample::UNCACHEABLE),
+        (data3.dc_locked_op(), OpSample::LOCKED),
+        (data.rip_invalid(), OpSample::RIP_INVALID),
+    ];
+    let flags = bits
+        .iter()
+        .filter(|(bit, _)| *bit == 1)
+        .fold(0, |flags, (_, flag)| flags | flag);
+
+    let load_miss = data3.ld_op() == 1 && data3.dc_miss() == 1;
+    OpSample {
+        rip: Msr::new(IBS_OP_RIP).read(),
+        data_addr: if data3.dc_lin_addr_valid() == 1 {
+            Msr::new(IBS_DC_LINADDR).read()
+        } else {
+            0
+        },
+        tag_to_retire: data.tag_to_ret_ctr(),
+        miss_latency: if load_miss { data3.dc_miss_lat() } else { 0 },
+        flags,
+        data_src: if load_miss { data2.data_src() } else { 0 },
+    }
+}
+
+unsafe fn read_fetch_sample(ctl: IbsFetchCtl) -> FetchSample {
+    let bits = [
+        (ctl.fetch_comp(), FetchSample::COMPLETED),
+        (ctl.ic_miss(), FetchSample::IC_MISS),
+        (ctl.l1_tlb_miss(), FetchSample::ITLB_L1_MISS),
+        (ctl.l2_tlb_miss(), FetchSample::ITLB_L2_MISS),
+        (ctl.fetch_l2_miss(), FetchSample::L2_MISS),
+    ];
+    FetchSample {
+        rip: Msr::new(IBS_FETCH_LINADDR).read(),
+        latency: ctl.fetch_lat(),
+        flags: bits
+            .iter()
+            .filter(|(bit, _)| *bit == 1)
+            .fold(0, |flags, (_, flag)| flags | flag),
+    }
+}
+
+/// Op samples of a core, oldest first. Sampling has to be stopped.
+pub fn op_samples(core_index: usize) -> Result<Vec<OpSample>, IbsError> {
+    let _control = CONTROL.lock();
+    if is_running() {
+        return Err(IbsError::Running);
+    }
+    unsafe {
+        Ok(OP_RINGS
+            .get_mut(core_index)
+            .map_or_else(Vec::new, |ring| ring.iter().copied().collect()))
+    }
+}
+
+/// Fetch samples of a core, oldest first. Sampling has to be stopped.
+pub fn fetch_samples(core_index: usize) -> Result<Vec<FetchSample>, IbsError> {
+    let _control = CONTROL.lock();
+    if is_running() {
+        return Err(IbsError::Running);
+    }
+    unsafe {
+        Ok(FETCH_RINGS
+            .get_mut(core_index)
+            .map_or_else(Vec::new, |ring| ring.iter().copied().collect()))
+    }
+}
+
+/// Writes the samples of all cores as binary records to COM1.
+/// Sampling has to be stopped.
+pub fn dump() -> Result<(), IbsError> {
+    let _control = CONTROL.lock();
+    if is_running() {
+        return Err(IbsError::Running);
+    }
+    unsafe {
+        for (core, ring) in OP_RINGS.iter_mut().enumerate() {
+            if ring.lost() > 0 {
+                log::warn!("IBS core {} overwrote {} op samples", core, ring.lost());
+            }
+            if !ring.is_empty() {
+                stream::write_record(core, ring.iter());
+            }
+        }
+        for (core, ring) in FETCH_RINGS.iter_mut().enumerate() {
+            if ring.lost() > 0 {
+                log::warn!("IBS core {} overwrote {} fetch samples", core, ring.lost());
+            }
+            if !ring.is_empty() {
+                stream::write_record(core, ring.iter());
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use modular_bitfield::prelude::*;
+
+/// Fetch sampling
+pub const IBS_FETCH_CTL: u32 = 0xC001_1030;
+pub const IBS_FETCH_LINADDR: u32 = 0xC001_1031;
+pub const IBS_FETCH_PHYSADDR: u32 = 0xC001_1032;
+
+/// Op sampling
+pub const IBS_OP_CTL: u32 = 0xC001_1033;
+pub const IBS_OP_RIP: u32 = 0xC001_1034;
+pub const IBS_OP_DATA: u32 = 0xC001_1035;
+pub const IBS_OP_DATA2: u32 = 0xC001_1036;
+pub const IBS_OP_DATA3: u32 = 0xC001_1037;
+pub const IBS_DC_LINADDR: u32 = 0xC001_1038;
+pub const IBS_DC_PHYSADDR: u32 = 0xC001_1039;
+
+/// LVT offset chosen by the BIOS
+pub const IBS_CTL: u32 = 0xC001_103A;
+
+/// Cpuid leaf 0x8000_001B eax
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsCpuid {
+    /// Feature flags are valid
+    pub ffv: B1,
+    pub fetch_sam: B1,
+    pub op_sam: B1,
+    pub rd_wr_op_cnt: B1,
+    /// Ops can be counted instead of cycles
+    pub op_cnt: B1,
+    pub brn_trgt: B1,
+    /// IbsOpCtl::op_max_cnt_ext exists
+    pub op_cnt_ext: B1,
+    /// IbsOpData::rip_invalid exists
+    pub rip_invalid_chk: B1,
+    pub op_brn_fuse: B1,
+    pub fetch_ctl_extd: B1,
+    pub op_data4: B1,
+    pub res0: B21,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsFetchCtl {
+    /// Fetches between samples divided by 16
+    pub fetch_max_cnt: B16,
+    pub fetch_cnt: B16,
+    /// Cycles from the fetch request to its completion
+    pub fetch_lat: B16,
+    pub fetch_en: B1,
+    /// A sample is ready
+    pub fetch_val: B1,
+    pub fetch_comp: B1,
+    pub ic_miss: B1,
+    pub phy_addr_valid: B1,
+    pub l1_tlb_pg_sz: B2,
+    pub l1_tlb_miss: B1,
+    pub l2_tlb_miss: B1,
+    pub rand_en: B1,
+    /// Family 17h and later
+    pub fetch_l2_miss: B1,
+    pub res0: B5,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsOpCtl {
+    /// Cycles or ops between samples divided by 16
+    pub op_max_cnt: B16,
+    pub res0: B1,
+    pub op_en: B1,
+    /// A sample is ready
+    pub op_val: B1,
+    /// Count dispatched ops instead of cycles
+    pub op_cnt_ctl: B1,
+    /// Bits 22:16 of op_max_cnt
+    pub op_max_cnt_ext: B7,
+    pub res1: B5,
+    pub op_cur_cnt: B27,
+    pub res2: B5,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsOpData {
+    pub comp_to_ret_ctr: B16,
+    /// Cycles from tagging the op to its retirement
+    pub tag_to_ret_ctr: B16,
+    pub op_brn_resync: B1,
+    pub op_misp_return: B1,
+    pub op_return: B1,
+    pub op_brn_taken: B1,
+    pub op_brn_misp: B1,
+    pub op_brn_ret: B1,
+    pub rip_invalid: B1,
+    pub op_brn_fuse: B1,
+    pub op_microcode: B1,
+    pub res0: B23,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsOpData2 {
+    /// Northbridge source of the data of a load that missed the caches
+    pub data_src: B3,
+    pub res0: B1,
+    pub rmt_node: B1,
+    pub cache_hit_st: B1,
+    pub res1: B58,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsOpData3 {
+    pub ld_op: B1,
+    pub st_op: B1,
+    pub dc_l1_tlb_miss: B1,
+    pub dc_l2_tlb_miss: B1,
+    pub dc_l1_tlb_hit_2m: B1,
+    pub dc_l1_tlb_hit_1g: B1,
+    pub dc_l2_tlb_hit_2m: B1,
+    pub dc_miss: B1,
+    pub dc_mis_acc: B1,
+    pub res0: B4,
+    pub dc_wc_mem_acc: B1,
+    pub dc_uc_mem_acc: B1,
+    pub dc_locked_op: B1,
+    pub dc_miss_no_mab_alloc: B1,
+    pub dc_lin_addr_valid: B1,
+    pub dc_phy_addr_valid: B1,
+    pub dc_l2_tlb_hit_1g: B1,
+    pub l2_miss: B1,
+    pub sw_pf: B1,
+    pub op_mem_width: B4,
+    pub op_dc_miss_open_mem_reqs: B6,
+    /// Cycles from the data cache miss until the data arrived
+    pub dc_miss_lat: B16,
+    pub tlb_refill_lat: B16,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsCtl {
+    pub lvt_offset: B4,
+    pub res0: B4,
+    pub lvt_offset_val: B1,
+    pub res1: B55,
+}
@@ -1,9 +1,12 @@
 use crate::apic;
+use crate::backtrace;
+use crate::gdb;
 use crate::print;
 use crate::tss;
 
 use pic8259_simple::ChainedPics;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
 
 // Offset the PICs to avoid index collision with
 // exceptions in the IDT
@@ -38,6 +41,8 @@ pub enum InterruptIndex {
     IRQ16,
     SlavePicSpurious,
     Timer = 0xe0,
+    Wakeup,
+    Call,
     Spurious = 0xff,
 }
 
@@ -49,6 +54,11 @@ impl InterruptIndex {
     pub fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+    /// Returns the ISA irq number of a legacy interrupt
+    pub fn as_isa_irq(self) -> u8 {
+        self.as_u8() - InterruptIndex::LegacyTimer.as_u8()
+    }
+
     //IMPORTAT: Fix to be migrated
     pub fn as_pic_enable_mask(self) -> u8 {
         let mut diff = self.as_usize() - InterruptIndex::LegacyTimer.as_usize();
@@ -64,6 +74,15 @@ pub static PICS: spin::Mutex<ChainedPics> =
 
 static mut IDT: Option<InterruptDescriptorTable> = None;
 
+/// Acknowledges a legacy interrupt at the I/O APIC path or at the PICs
+pub unsafe fn end_of_legacy_interrupt(index: InterruptIndex) {
+    if crate::ioapic::legacy_irqs_routed() {
+        apic::end_of_interrupt();
+    } else {
+        PICS.lock().notify_end_of_interrupt(index.as_u8());
+    }
+}
+
 pub unsafe fn init() {
     if IDT.is_none() {
         let stacks = tss::TSS_STACK_ITER.as_mut().unwrap();
@@ -104,12 +123,15 @@ pub unsafe fn init() {
         idt.invalid_opcode.set_handler_fn(invalid_op_handler);
         idt.bound_range_exceeded.set_handler_fn(bound_range_handler);
         idt.overflow.set_handler_fn(overflow_handler);
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // Breakpoints, single steps and NMIs go through the gdb stub,
+        // which needs all registers of the interrupted code
+        idt.breakpoint
+            .set_handler_addr(VirtAddr::new(gdb::breakpoint_entry as usize as u64));
         idt.non_maskable_interrupt
-            .set_handler_fn(non_maskable_handler)
+            .set_handler_addr(VirtAddr::new(gdb::nmi_entry as usize as u64))
             .set_stack_index(stacks.next().unwrap());
         idt.debug
-            .set_handler_fn(debug_handler)
+            .set_handler_addr(VirtAddr::new(gdb::debug_entry as usize as u64))
             .set_stack_index(stacks.next().unwrap());
         idt.divide_error.set_handler_fn(divide_error_handler);
 
@@ -117,8 +139,10 @@ pub unsafe fn init() {
 
         // User defined
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+        idt[InterruptIndex::Wakeup.as_usize()].set_handler_fn(wakeup_handler);
+        idt[InterruptIndex::Call.as_usize()].set_handler_fn(call_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt[InterruptIndex::COM2.as_usize()].set_handler_fn(serial_handler);
+        idt[InterruptIndex::COM2.as_usize()].set_handler_fn(gdb_serial_handler);
         idt[InterruptIndex::COM1.as_usize()].set_handler_fn(serial_handler);
         idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_handler);
         idt[InterruptIndex::SlavePicSpurious.as_usize()].set_handler_fn(spurious_handler);
@@ -143,6 +167,7 @@ extern "x86-interrupt" fn page_fault_handler(
     log::error!("Accessed Address: {:?}", addr);
     log::error!("Error Code: {:?}", error_code);
     log::error!("{:#?}", stack_frame);
+    backtrace::print_exception(&stack_frame, backtrace::frame_pointer());
     // unsafe {
     //     use x86_64::addr::VirtAddr;
     //     use crate::memory::*;
@@ -162,6 +187,7 @@ pub extern "x86-interrupt" fn default_handler<const N: usize>(stack_frame: Inter
     let rsp: u64;
     unsafe { asm!("mov {}, rsp", out(reg) rsp) };
     log::info!("rsp: {:#x}", rsp);
+    backtrace::print_exception(&stack_frame, backtrace::frame_pointer());
     panic!("{:?}", stack_frame);
 }
 
@@ -172,6 +198,7 @@ extern "x86-interrupt" fn general_prot_handler(stack_frame: InterruptStackFrame,
     let rsp: u64;
     unsafe { asm!("mov {}, rsp", out(reg) rsp) };
     log::info!("rsp: {:#x}", rsp);
+    backtrace::print_exception(&stack_frame, backtrace::frame_pointer());
     hlt_loop();
 }
 
@@ -180,6 +207,7 @@ extern "x86-interrupt" fn alignment_handler(stack_frame: InterruptStackFrame, er
     log::error!("EXCEPTION: Alignment Exception");
     log::error!("Error Code: {:?}", error_code);
     log::error!("{:#?}", stack_frame);
+    backtrace::print_exception(&stack_frame, backtrace::frame_pointer());
     hlt_loop();
 }
 
@@ -207,16 +235,10 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
 
     // Renable interrupts again
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        end_of_legacy_interrupt(InterruptIndex::Keyboard);
     }
 }
 
-// Breakpoint handler
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    log::error!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
 // Double fault handler
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
@@ -239,14 +261,26 @@ extern "x86-interrupt" fn serial_handler(_stack_frame: InterruptStackFrame) {
 
     // Renable interrupts again
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::COM2.as_u8());
+        end_of_legacy_interrupt(InterruptIndex::COM2);
+    }
+}
+
+// COM2 is reserved for the gdb stub
+extern "x86-interrupt" fn gdb_serial_handler(_stack_frame: InterruptStackFrame) {
+    crate::gdb::serial_interrupt();
+
+    unsafe {
+        end_of_legacy_interrupt(InterruptIndex::COM2);
     }
 }
 
 // timer interrupt handler
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    // Fire expired timers of this core and rearm the apic timer
+    crate::timer::handle_interrupt();
+
+    // Record the interrupted call chain if the profiler asked for this tick
+    crate::profile::sampler::tick(&stack_frame, backtrace::frame_pointer());
 
     // Renable interrupts again
     unsafe {
@@ -254,6 +288,23 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
     }
 }
 
+// Sent by the executor to wake up a halted core
+// Returning from the interrupt is enough to leave hlt
+extern "x86-interrupt" fn wakeup_handler(_stack_frame: InterruptStackFrame) {
+    unsafe {
+        apic::end_of_interrupt();
+    }
+}
+
+// Executes the functions other cores posted to this core
+extern "x86-interrupt" fn call_handler(_stack_frame: InterruptStackFrame) {
+    crate::ipi::handle_pending();
+
+    unsafe {
+        apic::end_of_interrupt();
+    }
+}
+
 extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {
     log::info!("SPURIOUS HANDLER");
 
@@ -273,21 +324,11 @@ extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {
  * Non populated cpu exceptions
  *
  */
-extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
-    log::info!("debug exception");
-    panic!("{:?}", stack_frame);
-}
-
 extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
     log::info!("divide error exception");
     panic!("{:?}", stack_frame);
 }
 
-extern "x86-interrupt" fn non_maskable_handler(stack_frame: InterruptStackFrame) {
-    log::info!("non maskable interrupt exception");
-    panic!("{:?}", stack_frame);
-}
-
 extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
     log::error!("overflow exception");
     panic!("{:?}", stack_frame);
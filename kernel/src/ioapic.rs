@@ -0,0 +1,234 @@
+use crate::acpi::Acpi;
+use crate::interrupts::InterruptIndex;
+use crate::ioapic_regs::*;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::ptr::{addr_of, read_unaligned, read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::PhysAddr;
+
+static mut IOAPICS: Option<Vec<IoApic>> = None;
+
+/// Set if the legacy ISA interrupts are delivered through the I/O APIC
+/// instead of the 8259 PICs
+static LEGACY_IRQS_ROUTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+pub struct IoApic {
+    id: u8,
+    base_addr: u64,
+    /// First global system interrupt handled by this I/O APIC
+    gsi_base: u32,
+    num_entries: u32,
+    /// IOREGSEL and IOWIN have to be accessed as a pair
+    lock: spin::Mutex<()>,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        write_volatile((self.base_addr + IOREGSEL) as *mut u32, register);
+        read_volatile((self.base_addr + IOWIN) as *const u32)
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        write_volatile((self.base_addr + IOREGSEL) as *mut u32, register);
+        write_volatile((self.base_addr + IOWIN) as *mut u32, value);
+    }
+
+    fn handles_gsi(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.num_entries).contains(&gsi)
+    }
+
+    unsafe fn read_entry(&self, index: u32) -> RedirectionEntry {
+        let reg = Register::RedirectionTable as u32 + index * 2;
+        let (low, high) = x86_64::instructions::interrupts::without_interrupts(|| {
+            let _guard = self.lock.lock();
+            (self.read(reg), self.read(reg + 1))
+        });
+        let raw = (low as u64) | ((high as u64) << 32);
+        RedirectionEntry::from_bytes(raw.to_le_bytes())
+    }
+
+    unsafe fn write_entry(&self, index: u32, entry: RedirectionEntry) {
+        let reg = Register::RedirectionTable as u32 + index * 2;
+        let raw = u64::from_le_bytes(entry.into_bytes());
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let _guard = self.lock.lock();
+            // Mask the entry while it is half written
+            self.write(reg, (raw as u32) | (1 << 16));
+            self.write(reg + 1, (raw >> 32) as u32);
+            self.write(reg, raw as u32);
+        });
+    }
+}
+
+/// Maps every I/O APIC listed in the MADT and masks all of its entries
+pub unsafe fn init(acpi: &Acpi) {
+    if IOAPICS.is_some() {
+        return;
+    }
+
+    let mut ioapics = Vec::new();
+    for entry in acpi.ioapics.as_ref().expect("No I/O APIC found").iter() {
+        let phys_addr = read_unaligned(addr_of!(entry.address)) as u64;
+        let gsi_base = read_unaligned(addr_of!(entry.interrupt_base));
+
+        let base_addr = crate::vmm::map_mmio(PhysAddr::new(phys_addr), 0x20, "ioapic")
+            .expect("failed to map the I/O APIC")
+            .as_u64();
+
+        let mut ioapic = IoApic {
+            id: entry.id,
+            base_addr,
+            gsi_base,
+            num_entries: 0,
+            lock: spin::Mutex::new(()),
+        };
+
+        let version = IoApicVersion::from_bytes(
+            ioapic
+                .read(Register::IoApicVersion as u32)
+                .to_le_bytes(),
+        );
+        ioapic.num_entries = version.max_redirection_entry() as u32 + 1;
+
+        log::info!(
+            "I/O APIC id: {} version: {} gsi: {}-{}",
+            ioapic.id,
+            version.ver(),
+            gsi_base,
+            gsi_base + ioapic.num_entries - 1
+        );
+
+        for i in 0..ioapic.num_entries {
+            let entry = ioapic.read_entry(i).with_mask(1);
+            ioapic.write_entry(i, entry);
+        }
+
+        ioapics.push(ioapic);
+    }
+
+    IOAPICS = Some(ioapics);
+}
+
+fn get_by_gsi(gsi: u32) -> Option<&'static IoApic> {
+    unsafe {
+        IOAPICS
+            .as_ref()
+            .expect("I/O APIC not initialized")
+            .iter()
+            .find(|ioapic| ioapic.handles_gsi(gsi))
+    }
+}
+
+/// Routes a global system interrupt to the given vector on the core with the
+/// given apic id and unmasks it.
+/// Without interrupt remapping only apic ids below 256 can be targeted.
+pub unsafe fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    dest_apic_id: u32,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+) {
+    let ioapic = get_by_gsi(gsi).expect("No I/O APIC handles this gsi");
+    let dest_apic_id =
+        u8::try_from(dest_apic_id).expect("I/O APIC can only deliver to apic ids below 256");
+    let entry = RedirectionEntry::new()
+        .with_vec(vector)
+        .with_delivery_mode(0b000) // Fixed
+        .with_dest_mode(0) // Physical destination
+        .with_polarity((polarity == Polarity::ActiveLow) as u8)
+        .with_trigger_mode((trigger_mode == TriggerMode::Level) as u8)
+        .with_mask(0)
+        .with_dest(dest_apic_id);
+    ioapic.write_entry(gsi - ioapic.gsi_base, entry);
+}
+
+/// Translates an ISA irq into its global system interrupt with
+/// the polarity and trigger mode of the MADT interrupt source override.
+/// Without an override the irq is identity mapped, active high and edge triggered.
+pub fn resolve_isa_irq(acpi: &Acpi, irq: u8) -> (u32, Polarity, TriggerMode) {
+    let int_override = acpi
+        .int_overrides
+        .iter()
+        .flatten()
+        .find(|o| o.source == irq);
+
+    let int_override = match int_override {
+        Some(o) => o,
+        None => return (irq as u32, Polarity::ActiveHigh, TriggerMode::Edge),
+    };
+
+    let gsi = unsafe { read_unaligned(addr_of!(int_override.mapped_to)) };
+    let flags = unsafe { read_unaligned(addr_of!(int_override.flags)) };
+    let flags = IntOverrideFlags::from_bytes(flags.to_le_bytes());
+
+    // 0b00 conforms to the ISA bus, which is active high and edge triggered
+    let polarity = match flags.polarity() {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    };
+    let trigger_mode = match flags.trigger_mode() {
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::Edge,
+    };
+    (gsi, polarity, trigger_mode)
+}
+
+/// Routes an ISA irq while honouring the MADT interrupt source overrides
+pub unsafe fn route_isa_irq(acpi: &Acpi, irq: u8, vector: u8, dest_apic_id: u32) {
+    let (gsi, polarity, trigger_mode) = resolve_isa_irq(acpi, irq);
+    log::info!(
+        "Routing isa irq {} -> gsi {} ({:?}, {:?}) to vector {:#x} on apic id {}",
+        irq,
+        gsi,
+        polarity,
+        trigger_mode,
+        vector,
+        dest_apic_id
+    );
+    route_gsi(gsi, vector, dest_apic_id, polarity, trigger_mode);
+}
+
+pub unsafe fn mask_gsi(gsi: u32) {
+    let ioapic = get_by_gsi(gsi).expect("No I/O APIC handles this gsi");
+    let index = gsi - ioapic.gsi_base;
+    let entry = ioapic.read_entry(index).with_mask(1);
+    ioapic.write_entry(index, entry);
+}
+
+pub unsafe fn unmask_gsi(gsi: u32) {
+    let ioapic = get_by_gsi(gsi).expect("No I/O APIC handles this gsi");
+    let index = gsi - ioapic.gsi_base;
+    let entry = ioapic.read_entry(index).with_mask(0);
+    ioapic.write_entry(index, entry);
+}
+
+/// Routes the legacy devices handled by the kernel to the given core.
+/// Afterwards their interrupts have to be acknowledged at the local apic.
+pub unsafe fn route_legacy_irqs(acpi: &Acpi, dest_apic_id: u32) {
+    for index in [
+        InterruptIndex::Keyboard,
+        InterruptIndex::COM2,
+        InterruptIndex::COM1,
+    ] {
+        route_isa_irq(acpi, index.as_isa_irq(), index.as_u8(), dest_apic_id);
+    }
+    LEGACY_IRQS_ROUTED.store(true, Ordering::SeqCst);
+}
+
+pub fn legacy_irqs_routed() -> bool {
+    LEGACY_IRQS_ROUTED.load(Ordering::Relaxed)
+}
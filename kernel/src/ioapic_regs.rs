@@ -0,0 +1,53 @@
+use modular_bitfield::prelude::*;
+
+/// I/O APIC registers, selected through IOREGSEL and accessed through IOWIN
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum Register {
+    IoApicId = 0x00,
+    IoApicVersion = 0x01,
+    IoApicArbitration = 0x02,
+    /// First redirection table entry, every entry uses two registers
+    RedirectionTable = 0x10,
+}
+
+/// MMIO offsets relative to the I/O APIC base address
+pub const IOREGSEL: u64 = 0x00;
+pub const IOWIN: u64 = 0x10;
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicVersion {
+    pub ver: B8,
+    pub res0: B8,
+    pub max_redirection_entry: B8,
+    pub res1: B8,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct RedirectionEntry {
+    pub vec: B8,
+    pub delivery_mode: B3,
+    pub dest_mode: B1,
+    pub delivery_status: B1,
+    /// 0 active high, 1 active low
+    pub polarity: B1,
+    pub remote_irr: B1,
+    /// 0 edge, 1 level
+    pub trigger_mode: B1,
+    pub mask: B1,
+    pub res0: B39,
+    pub dest: B8,
+}
+
+/// MPS INTI flags of a MADT interrupt source override
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IntOverrideFlags {
+    /// 00 conforms to bus, 01 active high, 11 active low
+    pub polarity: B2,
+    /// 00 conforms to bus, 01 edge, 11 level
+    pub trigger_mode: B2,
+    pub res0: B12,
+}
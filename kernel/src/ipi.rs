@@ -0,0 +1,187 @@
+use crate::apic;
+use crate::interrupts::InterruptIndex;
+use crate::percpu;
+use crate::smp;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+/*
+ * Cross core function calls
+ * Every core owns a mailbox inside its per cpu block. A call gets pushed
+ * into the mailbox of each target core, afterwards the target gets a
+ * call IPI. The interrupt handler drains the mailbox and executes the
+ * calls in the order they have been posted.
+ * Calls run in interrupt context with interrupts disabled, thus they
+ * must not block on locks that are held with interrupts enabled.
+ * While waiting for completion the calling core keeps serving its own
+ * mailbox, so two cores calling each other do not deadlock.
+ */
+
+/// A function posted to one or more cores
+pub struct Call {
+    func: Box<dyn Fn() + Send + Sync>,
+    /// Number of target cores that have not finished the call yet
+    pending: AtomicUsize,
+}
+
+impl Call {
+    fn new(func: Box<dyn Fn() + Send + Sync>, targets: usize) -> Arc<Self> {
+        Arc::new(Call {
+            func,
+            pending: AtomicUsize::new(targets),
+        })
+    }
+
+    fn run(&self) {
+        (self.func)();
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn is_done(&self) -> bool {
+        self.pending.load(Ordering::SeqCst) == 0
+    }
+}
+
+/// Executes all calls posted to the current core.
+/// Called by the call IPI handler.
+pub fn handle_pending() {
+    loop {
+        let calls = interrupts::without_interrupts(|| {
+            core::mem::take(&mut *percpu!().call_mailbox.lock())
+        });
+        if calls.is_empty() {
+            break;
+        }
+        for call in calls {
+            call.run();
+        }
+    }
+}
+
+fn post(apic_id: u32, call: &Arc<Call>) {
+    let block = percpu::get_by_apic_id(apic_id).expect("Target core is not online");
+    interrupts::without_interrupts(|| block.call_mailbox.lock().push(call.clone()));
+    unsafe {
+        apic::send_fixed_ipi(apic_id, InterruptIndex::Call.as_u8());
+    }
+}
+
+/// Posts the call to all targets, the current core executes its share inline
+fn dispatch(apic_ids: &[u32], call: &Arc<Call>) {
+    let own_id = percpu!(apic_id);
+    for &apic_id in apic_ids.iter().filter(|id| **id != own_id) {
+        post(apic_id, call);
+    }
+    if apic_ids.contains(&own_id) {
+        interrupts::without_interrupts(|| call.run());
+    }
+}
+
+fn wait(call: &Call) {
+    while !call.is_done() {
+        // Calls run with interrupts disabled, also the ones served here
+        interrupts::without_interrupts(handle_pending);
+        core::hint::spin_loop();
+    }
+}
+
+/// Returns the ids in their original order without duplicates.
+/// Every core runs a call once, no matter how often it is listed.
+fn unique(apic_ids: &[u32]) -> Vec<u32> {
+    let mut ids: Vec<u32> = Vec::with_capacity(apic_ids.len());
+    for id in apic_ids {
+        if !ids.contains(id) {
+            ids.push(*id);
+        }
+    }
+    ids
+}
+
+/// Runs `f` on every given core and waits until all of them finished.
+/// Returns the results in the order of `apic_ids`, a core listed
+/// more than once runs `f` once and only shows up at its first position.
+pub fn call_on_many<R, F>(apic_ids: &[u32], f: F) -> Vec<R>
+where
+    R: Send + 'static,
+    F: Fn() -> R + Send + Sync + 'static,
+{
+    let apic_ids = &unique(apic_ids)[..];
+    let results: Arc<spin::Mutex<Vec<Option<R>>>> =
+        Arc::new(spin::Mutex::new(apic_ids.iter().map(|_| None).collect()));
+
+    let ids: Vec<u32> = apic_ids.to_vec();
+    let slots = results.clone();
+    let call = Call::new(
+        Box::new(move || {
+            let res = f();
+            let own_id = percpu!(apic_id);
+            let index = ids.iter().position(|id| *id == own_id).unwrap();
+            slots.lock()[index] = Some(res);
+        }),
+        apic_ids.len(),
+    );
+
+    dispatch(apic_ids, &call);
+    wait(&call);
+
+    let mut results = results.lock();
+    results.iter_mut().map(|r| r.take().unwrap()).collect()
+}
+
+/// Runs `f` on the given core and waits for its result
+pub fn call_on<R, F>(apic_id: u32, f: F) -> R
+where
+    R: Send + 'static,
+    F: FnOnce() -> R + Send + 'static,
+{
+    let f = spin::Mutex::new(Some(f));
+    call_on_many(&[apic_id], move || (f.lock().take().unwrap())())
+        .pop()
+        .unwrap()
+}
+
+/// Runs `f` on every online core, including the current one,
+/// and waits until all of them finished.
+/// Returns the results in the order of `smp::online_cores`.
+pub fn call_on_all<R, F>(f: F) -> Vec<R>
+where
+    R: Send + 'static,
+    F: Fn() -> R + Send + Sync + 'static,
+{
+    call_on_many(&smp::online_cores(), f)
+}
+
+/// Posts `f` to every given core without waiting for completion
+pub fn post_on_many<F>(apic_ids: &[u32], f: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let apic_ids = &unique(apic_ids)[..];
+    let call = Call::new(Box::new(f), apic_ids.len());
+    dispatch(apic_ids, &call);
+}
+
+/// Posts `f` to the given core without waiting for completion
+pub fn post_on<F>(apic_id: u32, f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let f = spin::Mutex::new(Some(f));
+    post_on_many(&[apic_id], move || {
+        if let Some(f) = f.lock().take() {
+            f()
+        }
+    });
+}
+
+/// Posts `f` to every online core, including the current one,
+/// without waiting for completion
+pub fn post_on_all<F>(f: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    post_on_many(&smp::online_cores(), f);
+}
@@ -1,5 +1,6 @@
 use log::{Level, Metadata, Record};
 
+use crate::percpu;
 use crate::println;
 use crate::serial::SERIAL_WRITER;
 use crate::vga::VGA_WRITER;
@@ -32,7 +33,17 @@ impl log::Log for HWLogger {
     // Executed on log macros
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            println!("{} - {}", record.level(), record.args());
+            // Prefix the core index once the per cpu block is installed
+            if crate::percpu::is_initialized() {
+                println!(
+                    "[{}] {} - {}",
+                    percpu!(core_index),
+                    record.level(),
+                    record.args()
+                );
+            } else {
+                println!("{} - {}", record.level(), record.args());
+            }
         }
     }
 
@@ -6,11 +6,14 @@
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(allocator_api)]
 #![feature(bench_black_box)]
+#![feature(const_fn_fn_ptr_basics)]
 #![feature(const_mut_refs)]
 #![feature(asm)]
 #![feature(test)]
 #![feature(maybe_uninit_uninit_array)]
+#![feature(naked_functions)]
 #![no_std]
 #![allow(clippy::missing_safety_doc)]
 
@@ -19,20 +22,41 @@ pub mod acpi_regs;
 pub mod allocator;
 pub mod apic;
 pub mod apic_regs;
+pub mod backtrace;
 pub mod bench;
+pub mod cmdline;
 pub mod corestate;
 pub mod default_interrupt;
+pub mod executor;
+pub mod gdb;
+pub mod hpet;
+pub mod hpet_regs;
+pub mod ibs;
+pub mod ibs_regs;
 pub mod interrupts;
+pub mod ioapic;
+pub mod ipi;
+pub mod ioapic_regs;
 pub mod klog;
 pub mod memory;
+pub mod numa;
+pub mod panic;
 pub mod pci;
+pub mod percpu;
+pub mod pmu;
+pub mod pmu_regs;
 pub mod print;
+pub mod profile;
 pub mod serial;
 pub mod smp;
 pub mod time;
+pub mod timer;
+pub mod tlb;
 pub mod tss;
 pub mod vga;
+pub mod vmm;
 
+use alloc::vec::Vec;
 use core::ptr::*;
 extern crate alloc;
 
@@ -63,6 +87,15 @@ pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
 pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
     klog::init();
 
+    // Load the kernel symbols for stack traces
+    backtrace::init(boot_info);
+
+    // Arguments passed by glue_gun, e.g. to select benchmarks
+    cmdline::init(boot_info);
+
+    // Install the per cpu block of this core into gs
+    percpu::init(boot_info);
+
     // Make sure that other cores have the same register state like bsp
     // if apic::is_bsp() {
     //     corestate::save_corestate();
@@ -87,12 +120,15 @@ pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
     let (mapper, frame_allocator) = memory::init(boot_info);
 
     if apic::is_bsp() {
-        // Measure speed of rtsc once
-        time::calibrate();
-
         // Check support of hardware features needed for benchmarking
         bench::check_support();
 
+        // Detect the core performance counters
+        pmu::init();
+
+        // Detect instruction based sampling
+        ibs::init();
+
         // Initialize the heap allocator
         // by mapping the heap pages
         allocator::init_heap(
@@ -100,42 +136,55 @@ pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
             frame_allocator.lock().deref_mut(),
         )
         .expect("heap init failed");
-    }
 
-    log::debug!("Init apic controller");
+        // Everything mapped from now on goes through the vmm
+        vmm::init();
+
+        // Debugger on COM2, inactive until gdb connects
+        gdb::init(boot_info);
+
+        // Create the per core executors
+        executor::init();
+    }
 
     // Parse acpi tables once
     let acpi = acpi::init();
 
-    // Initialize lapic controller
-    apic::init(
-        mapper.lock().deref_mut(),
-        frame_allocator.lock().deref_mut(),
-        acpi,
-        boot_info,
-    );
+    if apic::is_bsp() {
+        // Split the physical memory into the numa nodes of the SRAT
+        numa::init(acpi);
 
-    {
-        let (core, core_index) = boot_info
-        .cores
-        .get_by_apic_id(crate::apic::apic_id())
-        .unwrap();
+        // Map and start the hpet if there is one
+        hpet::init(acpi);
 
-        log::info!(
-            "Enabling interrupts for core index {} apic_id {}", core_index, core.get_apic_id().unwrap()
-        );
+        // Measure speed of rtsc once
+        time::calibrate();
     }
+
+    log::debug!("Init apic controller");
+
+    // Initialize lapic controller
+    apic::init(acpi, boot_info);
+
+    log::info!(
+        "Enabling interrupts for core index {} apic_id {}",
+        percpu!(core_index),
+        percpu!(apic_id)
+    );
     // Enable interrupts
     x86_64::instructions::interrupts::enable();
 
+    // From now on this core can receive cross core calls
+    smp::set_core_ready();
 
     if apic::is_bsp() {
-        for lapic in acpi.apics.as_ref().unwrap().iter().skip(1) {
-            apic::mp_init(lapic.id, boot_info.smp_trampoline);
-            //time::sleep(100);
-        }
+        // Launch the other cores one by one and wait for them to check in
+        smp::boot_aps(acpi, boot_info.smp_trampoline);
     }
 
+    // Wait until every core that came up is online
+    smp::wait_for_all_cores();
+
     // Search for pci devices
     //pci::init();
     // Init pci devices
@@ -177,8 +226,27 @@ fn panic(info: &PanicInfo) -> ! {
     test_panic_handler(info)
 }
 
-// Gets array of functions annotated with #[test_case]
+// Gets array of functions annotated with #[test_case] and #[bench_case]
 pub fn test_runner(tests: &[&dyn Testable]) {
+    let args = bench::runner::Args::parse(cmdline::args());
+    if args.bench {
+        // Only the bsp starts the benchmarks, they pick their cores themselves
+        if apic::is_bsp() {
+            let cases: Vec<_> = tests.iter().filter_map(|t| t.bench_case()).collect();
+            let failed = bench::runner::run(&cases, &args);
+            exit_qemu(if failed == 0 {
+                QemuExitCode::Success
+            } else {
+                QemuExitCode::Failed
+            });
+        }
+        return;
+    }
+
+    let tests: Vec<_> = tests
+        .iter()
+        .filter(|t| t.bench_case().is_none() && args.matches(t.name()))
+        .collect();
     println!("Running {} tests", tests.len());
     for test in tests {
         test.run();
@@ -187,12 +255,13 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 // Prints panic error and quits qemu
-#[allow(unreachable_code)]
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    println!("[failed]\n");
-    println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
-    hlt_loop();
+    use core::fmt::Write;
+    // Does not print through the locks, a test could panic while holding them
+    unsafe {
+        let _ = writeln!(serial::RawWriter::new(), "[failed]\n");
+    }
+    panic::handle(info, true)
 }
 
 pub fn hlt_loop() -> ! {
@@ -207,6 +276,11 @@ pub fn hlt_loop() -> ! {
  */
 pub trait Testable {
     fn run(&self);
+    fn name(&self) -> &'static str;
+    /// Benchmarks only run in bench mode
+    fn bench_case(&self) -> Option<bench::BenchCase> {
+        None
+    }
 }
 
 impl<T> Testable for T
@@ -214,10 +288,26 @@ where
     T: Fn(),
 {
     fn run(&self) {
-        print!("{}...\t", core::any::type_name::<T>());
+        print!("{}...\t", self.name());
         self();
         println!("[ok]");
     }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+}
+
+impl Testable for bench::BenchCase {
+    fn run(&self) {}
+
+    fn name(&self) -> &'static str {
+        bench::BenchCase::name(self)
+    }
+
+    fn bench_case(&self) -> Option<bench::BenchCase> {
+        Some(*self)
+    }
 }
 
 #[test_case]
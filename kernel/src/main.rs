@@ -52,39 +52,8 @@ fn kernel_main(_boot_info: &'static bootinfo::BootInfo) -> ! {
     //     core::ptr::write_bytes(heap_addr, 0xAA, 0x200000);
     // };
 
-    // {
-    //     use perf_kernel::{allocator::HEAP_START, allocator::HEAP_SIZE, allocator::ALLOCATOR, bench::Bench, klog, print, println};
-    //     use core::hint::black_box;
-    //     use alloc::boxed::Box;
-    //     use alloc::vec::Vec;
-    //     use core::intrinsics::copy;
-    //     let mut vec:Vec::<usize> = Vec::new();
-    //     let mut i = 0;
-    //     let len = vec.len();
-    //     let new_len = (HEAP_SIZE - 512) / core::mem::size_of::<usize>();
-    //     log::info!("Start resize... new_len: {:x}, sizeof(usize) {:#x}",new_len, core::mem::size_of::<usize>());
-    //     vec.resize_with(new_len, || { i+=1; i });
-    //     log::info!("Done resizing.");
-    //     let sum: usize = vec.iter().sum();
-    
-    //     let n = new_len-len;
-    //     assert_eq!(sum, (n.pow(2)+n)/2);
-
-    //     let mut vec2:Vec::<usize> = Vec::new();
-    //     let mut i = 0;
-    //     let len = vec2.len();
-    //     let new_len = (HEAP_SIZE - 512) / core::mem::size_of::<usize>();
-    //     log::info!("Start resize2...");
-    //     vec2.resize_with(new_len, || { i+=1; i });
-    //     log::info!("Done resizing2.");
-    //     let sum: usize = vec.iter().sum();
-    
-    //     let n = new_len-len;
-    //     assert_eq!(sum, (n.pow(2)+n)/2);
-    //     black_box(vec);
-    // }
-
-    perf_kernel::hlt_loop();
+    // Run async tasks of this core, halts if there is nothing to do
+    perf_kernel::executor::run();
 }
 
 /*
@@ -94,16 +63,8 @@ fn kernel_main(_boot_info: &'static bootinfo::BootInfo) -> ! {
 //TODO: Implement a bare metal debugger
 // https://lib.rs/crates/gdbstub
 // https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
-// TODO: Make panic handler print stuff without a global lock
-// If an error occurs while reading memory inside the print lock
-// a deadlock occurs
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    perf_kernel::println!("{}", info);
-
-    #[cfg(debug)]
-    perf_kernel::exit_qemu(svm_kernel::QemuExitCode::Failed);
-
-    #[cfg(not(debug))]
-    loop {}
+    // Stops the other cores and prints without taking the print locks
+    perf_kernel::panic::handle(info, cfg!(test))
 }
@@ -1,7 +1,13 @@
+pub mod frame_alloc;
+
+pub use frame_alloc::BuddyFrameAllocator;
+pub use crate::numa::NumaFrameAllocator;
+use crate::tlb::TlbFlushBatch;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::Msr;
 // use x86_64::structures::paging::mapper::MapToError;
-use core::ptr::addr_of;
-use core::ptr::read_unaligned;
 use x86_64::structures::paging::mapper;
 use x86_64::structures::paging::mapper::MappedFrame;
 use x86_64::structures::paging::mapper::TranslateResult;
@@ -13,7 +19,7 @@ use x86_64::structures::paging::Translate;
 use x86_64::structures::paging::{OffsetPageTable, PageTable};
 use x86_64::VirtAddr;
 use x86_64::{
-    structures::paging::{FrameAllocator, PhysFrame, Size2MiB, Size4KiB},
+    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
     PhysAddr,
 };
 
@@ -34,8 +40,115 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // unsafe
 }
 
-static mut PAGE_TABLE: Option<spin::Mutex<OffsetPageTable>> = None;
-static mut FRAME_ALLOCATOR: Option<spin::Mutex<BootInfoFrameAllocator>> = None;
+/// MSR holding the page attribute table
+const IA32_PAT: u32 = 0x0000_0277;
+
+/// PAT entries selected by PCD and PWT: WB, WC, UC-, UC.
+/// Entry 1 is write combining instead of write through,
+/// entries 4-7 repeat 0-3 because the PAT bit is never set.
+const PAT_ENTRIES: u64 = 0x0007_0106_0007_0106;
+
+/// Memory type of a mapping, selected through the PAT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    WriteBack,
+    WriteCombining,
+    Uncached,
+}
+
+impl CacheType {
+    /// Page table flags that select this memory type
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheType::WriteBack => PageTableFlags::empty(),
+            CacheType::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CacheType::Uncached => PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE,
+        }
+    }
+}
+
+/// Loads PAT_ENTRIES into the PAT of the current core.
+/// Every core has to use the same entries.
+unsafe fn init_pat() {
+    let has_pat = raw_cpuid::CpuId::new()
+        .get_feature_info()
+        .map_or(false, |info| info.has_pat());
+    if !has_pat {
+        log::warn!("No PAT support, write combining falls back to write through");
+        return;
+    }
+    Msr::new(IA32_PAT).write(PAT_ENTRIES);
+    // Drop lines and translations cached with the old types
+    asm!("wbinvd", options(nostack));
+    x86_64::instructions::tlb::flush_all();
+}
+
+/// Spinlock that remembers which core holds it.
+/// The heap grows from inside the allocator and needs the page table and
+/// the frame allocator. It must fail instead of spinning if its own core
+/// holds one of them already, but may wait for any other core.
+pub struct CoreMutex<T> {
+    inner: spin::Mutex<T>,
+    /// Core index of the holder, NO_OWNER if unlocked
+    owner: AtomicUsize,
+}
+
+const NO_OWNER: usize = usize::MAX;
+
+pub struct CoreMutexGuard<'a, T> {
+    guard: spin::MutexGuard<'a, T>,
+    owner: &'a AtomicUsize,
+}
+
+impl<T> CoreMutex<T> {
+    pub const fn new(value: T) -> Self {
+        CoreMutex {
+            inner: spin::Mutex::new(value),
+            owner: AtomicUsize::new(NO_OWNER),
+        }
+    }
+
+    pub fn lock(&self) -> CoreMutexGuard<T> {
+        let guard = self.inner.lock();
+        let core_index = crate::percpu!(core_index);
+        self.owner.store(core_index, Ordering::Relaxed);
+        CoreMutexGuard {
+            guard,
+            owner: &self.owner,
+        }
+    }
+
+    /// Returns true if the current core holds the lock.
+    /// Only the holder writes its own index, so the answer is exact
+    /// for the current core.
+    pub fn is_held_by_current_core(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == crate::percpu!(core_index)
+    }
+}
+
+impl<T> Deref for CoreMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for CoreMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for CoreMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Runs before the inner guard unlocks
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+    }
+}
+
+static mut PAGE_TABLE: Option<CoreMutex<OffsetPageTable>> = None;
+static mut FRAME_ALLOCATOR: Option<CoreMutex<NumaFrameAllocator>> = None;
 
 /// Initialize a new OffsetPageTable.
 ///
@@ -46,21 +159,23 @@ static mut FRAME_ALLOCATOR: Option<spin::Mutex<BootInfoFrameAllocator>> = None;
 pub unsafe fn init(
     boot_info: &'static bootloader::bootinfo::BootInfo,
 ) -> (
-    &'static spin::Mutex<OffsetPageTable>,
-    &'static spin::Mutex<BootInfoFrameAllocator>,
+    &'static CoreMutex<OffsetPageTable>,
+    &'static CoreMutex<NumaFrameAllocator>,
 ) {
     if PAGE_TABLE.is_none() {
         let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
         let level_4_table = active_level_4_table(physical_memory_offset);
-        PAGE_TABLE = Some(spin::Mutex::new(OffsetPageTable::new(
+        PAGE_TABLE = Some(CoreMutex::new(OffsetPageTable::new(
             level_4_table,
             physical_memory_offset,
         )));
     }
 
+    init_pat();
+
     if FRAME_ALLOCATOR.is_none() {
-        FRAME_ALLOCATOR = Some(spin::Mutex::new(BootInfoFrameAllocator::new(
-            &boot_info.memory_map,
+        FRAME_ALLOCATOR = Some(CoreMutex::new(NumaFrameAllocator::new(
+            BuddyFrameAllocator::new(&boot_info.memory_map),
         )));
     }
 
@@ -70,6 +185,14 @@ pub unsafe fn init(
     )
 }
 
+/// Returns the page table and the frame allocator if memory::init has been called
+pub fn get() -> Option<(
+    &'static CoreMutex<OffsetPageTable<'static>>,
+    &'static CoreMutex<NumaFrameAllocator>,
+)> {
+    unsafe { Some((PAGE_TABLE.as_ref()?, FRAME_ALLOCATOR.as_ref()?)) }
+}
+
 // Identity maps the phys address + type size and volatile reads the type from
 // memory. Does not unmap the page
 pub unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
@@ -86,12 +209,13 @@ pub enum IdMapError {
 
 /// Identity map phys frame
 /// If virt addr already mapped checks if contains requested flags
-/// and correct phys frame addr
+/// and correct phys frame addr. Changed flags are added to `batch`.
 pub unsafe fn id_map<T: PageSize + core::fmt::Debug>(
     mapper: &mut (impl Mapper<T> + Translate),
     frame_allocator: &mut (impl FrameAllocator<Size4KiB> + ?Sized),
     my_frame: PhysFrame<T>,
     add_flags: Option<PageTableFlags>,
+    batch: &mut TlbFlushBatch,
 ) -> Result<Page<T>, IdMapError> {
     let addr = VirtAddr::new(my_frame.start_address().as_u64());
     let page = Page::<T>::from_start_address(addr).unwrap();
@@ -143,106 +267,40 @@ pub unsafe fn id_map<T: PageSize + core::fmt::Debug>(
                 }
             }
 
-            mapper
-                .update_flags(page, my_flags)
-                .map_err(IdMapError::FlagUpdateError)?
-                .flush();
+            // Accessed and dirty are set by the cpu, ignore them
+            let flags = flags - (PageTableFlags::ACCESSED | PageTableFlags::DIRTY);
+            if flags != my_flags {
+                update_flags(mapper, page, my_flags, batch).map_err(IdMapError::FlagUpdateError)?;
+            }
         }
     };
 
     Ok(page)
 }
 
-use bootloader::bootinfo::MemoryMap;
-use bootloader::bootinfo::{FrameRange, MemoryRegion, MemoryRegionType};
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-#[derive(Debug)]
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
-}
-
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn new(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
-
-    /// Returns an iterator over the usable frames specified in the memory map.
-    pub fn usable_frames<T: PageSize>(&self) -> impl Iterator<Item = PhysFrame<T>> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-
-        let usable_regions = unsafe {
-            regions.filter(|r| read_unaligned(addr_of!(r.region_type)) == MemoryRegionType::Usable)
-        };
-
-        // Reduce end of frame range to fit into T::SIZE
-        let adjusted_regions = usable_regions.map(|r| {
-            let diff = r.range.size() % T::SIZE;
-            if diff != 0 {
-                let new = r.range.end_addr() - diff;
-                return MemoryRegion {
-                    range: FrameRange::new(r.range.start_addr(), new),
-                    region_type: r.region_type,
-                };
-            }
-            *r
-        });
-
-        // Increase the start of frame range to fit into alignment
-        let adjusted_regions = adjusted_regions.map(move |r| {
-            let rest = r.range.start_addr() % T::SIZE;
-            if rest != 0 {
-                let new = r.range.start_addr() + (T::SIZE - rest);
-                if new > r.range.end_addr() {
-                    return MemoryRegion::empty();
-                }
-                return MemoryRegion {
-                    range: FrameRange::new(new, r.range.end_addr()),
-                    region_type: r.region_type,
-                };
-            }
-            r
-        });
-
-        // Filter out regions smaller then T::SIZE
-        let adjusted_regions = adjusted_regions.filter(move |r| r.range.size() >= T::SIZE);
-
-        // map each region to its address range
-        let addr_ranges = adjusted_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(move |r| r.step_by(T::SIZE as usize));
-
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-
-//TODO: If rust allows it in the future save the iterator in struct
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames::<Size4KiB>().nth(self.next);
-        log::info!("Allocated frame {:#x?}", frame);
-        self.next += 1;
-        frame
-    }
+/// Changes the flags of a mapped page and adds it to `batch`.
+/// The caller shoots the batch down after releasing the mapper lock,
+/// the other cores can't acknowledge while they wait for it.
+pub unsafe fn update_flags<T: PageSize>(
+    mapper: &mut impl Mapper<T>,
+    page: Page<T>,
+    flags: PageTableFlags,
+    batch: &mut TlbFlushBatch,
+) -> Result<(), mapper::FlagUpdateError> {
+    mapper.update_flags(page, flags)?.ignore();
+    batch.add_page(page);
+    Ok(())
 }
 
-unsafe impl FrameAllocator<Size2MiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
-
-        let frame = self.usable_frames::<Size2MiB>().nth(self.next);
-        log::info!("Allocated frame {:#x?}", frame);
-        self.next += (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
-        frame
-    }
+/// Unmaps a page and adds it to `batch`, shot down like in `update_flags`.
+/// Returns the frame the page was mapped to.
+pub unsafe fn unmap<T: PageSize>(
+    mapper: &mut impl Mapper<T>,
+    page: Page<T>,
+    batch: &mut TlbFlushBatch,
+) -> Result<PhysFrame<T>, mapper::UnmapError> {
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.ignore();
+    batch.add_page(page);
+    Ok(frame)
 }
@@ -0,0 +1,429 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ptr::{addr_of, read_unaligned};
+use x86_64::structures::paging::page::PageSize;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+/*
+ * Physical memory manager
+ * A binary buddy allocator over all usable regions of the bootloader's
+ * memory map. Blocks range from 4KiB (order 0) to 1GiB (order 18),
+ * thus 4KiB, 2MiB and 1GiB frames come from the same pool and never overlap.
+ * Instead of free lists every order keeps a bitmap with one bit per block.
+ * Summary levels above each bitmap mark the non zero words of the level
+ * below, so the first free block is found by following one word per level.
+ * The bitmaps never touch the managed frames themselves, only their
+ * storage has to be mapped. It is carved out of the first usable region
+ * below IDENTITY_MAPPED_END that is big enough.
+ * allocate splits the smallest free block that fits, so a frame can
+ * come from anywhere. Page tables are accessed through the identity map
+ * and have to be taken with allocate_below(IDENTITY_MAPPED_END), which
+ * the FrameAllocator impls do for 4KiB frames.
+ *
+ * alloc: O(NUM_ORDERS * MAX_LEVELS)
+ * dealloc: O(NUM_ORDERS * MAX_LEVELS)
+ */
+
+const FRAME_SHIFT: usize = 12;
+
+/// Orders 0 to 18, 4KiB to 1GiB
+const NUM_ORDERS: usize = 19;
+
+/// Six levels of 64 bit words cover 2^36 blocks
+const MAX_LEVELS: usize = 6;
+
+/// The bootloader identity maps the first 4GiB
+pub const IDENTITY_MAPPED_END: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Size of a block of the given order
+pub const fn order_size(order: usize) -> u64 {
+    1 << (order + FRAME_SHIFT)
+}
+
+/// Order of the blocks that back a frame of size S
+pub fn order_of<S: PageSize>() -> usize {
+    S::SIZE.trailing_zeros() as usize - FRAME_SHIFT
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Bitmap with summary levels, level 0 holds one bit per block
+#[derive(Clone, Copy)]
+struct BitTree {
+    levels: [*mut u64; MAX_LEVELS],
+    depth: usize,
+}
+
+impl BitTree {
+    const fn empty() -> Self {
+        BitTree {
+            levels: [core::ptr::null_mut(); MAX_LEVELS],
+            depth: 0,
+        }
+    }
+
+    /// Returns the number of words each level needs to hold `bits` bits
+    fn level_sizes(bits: u64) -> [usize; MAX_LEVELS] {
+        let mut sizes = [0; MAX_LEVELS];
+        let mut bits = bits;
+        for size in sizes.iter_mut() {
+            let words = (bits + 63) / 64;
+            *size = words as usize;
+            if words <= 1 {
+                return sizes;
+            }
+            bits = words;
+        }
+        panic!("Too many blocks for the frame allocator");
+    }
+
+    /// Places the levels at `storage`, which has to be zeroed.
+    /// Returns the number of words used.
+    unsafe fn new(storage: *mut u64, bits: u64) -> (Self, usize) {
+        let mut tree = BitTree::empty();
+        let mut used = 0;
+        for (i, size) in Self::level_sizes(bits).iter().enumerate() {
+            if *size == 0 {
+                break;
+            }
+            tree.levels[i] = storage.add(used);
+            tree.depth += 1;
+            used += size;
+        }
+        (tree, used)
+    }
+
+    unsafe fn get(&self, bit: usize) -> bool {
+        *self.levels[0].add(bit / 64) & (1 << (bit % 64)) != 0
+    }
+
+    unsafe fn set(&mut self, bit: usize) {
+        let mut bit = bit;
+        for level in self.levels[..self.depth].iter() {
+            let word = level.add(bit / 64);
+            let was_empty = *word == 0;
+            *word |= 1 << (bit % 64);
+            if !was_empty {
+                return;
+            }
+            bit /= 64;
+        }
+    }
+
+    unsafe fn clear(&mut self, bit: usize) {
+        let mut bit = bit;
+        for level in self.levels[..self.depth].iter() {
+            let word = level.add(bit / 64);
+            *word &= !(1 << (bit % 64));
+            if *word != 0 {
+                return;
+            }
+            bit /= 64;
+        }
+    }
+
+    /// Returns the lowest set bit
+    unsafe fn first_set(&self) -> Option<usize> {
+        if self.depth == 0 || *self.levels[self.depth - 1] == 0 {
+            return None;
+        }
+        let mut bit = 0;
+        for level in self.levels[..self.depth].iter().rev() {
+            bit = bit * 64 + (*level.add(bit)).trailing_zeros() as usize;
+        }
+        Some(bit)
+    }
+}
+
+/// Buddy allocator for physical frames between `base` and `end`
+pub struct BuddyFrameAllocator {
+    /// Address of the first block, aligned to the largest order
+    base: u64,
+    end: u64,
+    /// Free blocks of every order
+    orders: [BitTree; NUM_ORDERS],
+    /// Number of free 4KiB frames
+    num_free_frames: usize,
+}
+
+unsafe impl Send for BuddyFrameAllocator {}
+
+impl BuddyFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid. The main requirement is that all frames that are marked
+    /// as `USABLE` in it are really unused.
+    pub unsafe fn new(memory_map: &'static MemoryMap) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| read_unaligned(addr_of!(r.region_type)) == MemoryRegionType::Usable)
+                .map(|r| read_unaligned(addr_of!(r.range)))
+                .map(|r| {
+                    (
+                        align_up(r.start_addr(), order_size(0)),
+                        r.end_addr() & !(order_size(0) - 1),
+                    )
+                })
+                .filter(|(start, end)| start < end)
+        };
+
+        // Cover everything up to the highest usable frame
+        let max_addr = usable()
+            .map(|(_, end)| end)
+            .max()
+            .expect("No usable memory");
+        let storage_size = align_up((Self::storage_words(0, max_addr) * 8) as u64, order_size(0));
+
+        let (storage_start, storage_end) = usable()
+            .filter(|(start, end)| end - start >= storage_size)
+            .map(|(start, _)| (start, start + storage_size))
+            .find(|(_, end)| *end <= IDENTITY_MAPPED_END)
+            .expect("Not enough low memory for the frame allocator");
+        core::ptr::write_bytes(storage_start as *mut u8, 0, storage_size as usize);
+
+        let mut allocator = Self::empty(0, max_addr, storage_start as *mut u64);
+        for (start, end) in usable() {
+            if start == storage_start {
+                allocator.add_range(storage_end, end);
+            } else {
+                allocator.add_range(start, end);
+            }
+        }
+        log::info!(
+            "Frame allocator manages {} MiB, bookkeeping at {:#x}..{:#x}",
+            allocator.num_free_bytes() / (1024 * 1024),
+            storage_start,
+            storage_end
+        );
+        allocator
+    }
+
+    /// Number of bits every order needs to cover `base..end`.
+    /// The count is even so that each block has a buddy
+    fn order_bits(base: u64, end: u64, order: usize) -> u64 {
+        let base = base & !(order_size(NUM_ORDERS - 1) - 1);
+        align_up((end - base + order_size(order) - 1) / order_size(order), 2)
+    }
+
+    /// Number of words the bitmaps of an allocator covering `base..end` need
+    pub fn storage_words(base: u64, end: u64) -> usize {
+        (0..NUM_ORDERS)
+            .map(|order| {
+                BitTree::level_sizes(Self::order_bits(base, end, order))
+                    .iter()
+                    .sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Creates an allocator without free memory that covers `base..end`.
+    /// `storage` has to point to `storage_words` zeroed words.
+    pub unsafe fn empty(base: u64, end: u64, storage: *mut u64) -> Self {
+        let mut allocator = BuddyFrameAllocator {
+            base: base & !(order_size(NUM_ORDERS - 1) - 1),
+            end,
+            orders: [BitTree::empty(); NUM_ORDERS],
+            num_free_frames: 0,
+        };
+        let mut storage = storage;
+        for (order, tree) in allocator.orders.iter_mut().enumerate() {
+            let (new, used) = BitTree::new(storage, Self::order_bits(base, end, order));
+            *tree = new;
+            storage = storage.add(used);
+        }
+        allocator
+    }
+
+    /// Returns true if the address lies inside the range covered by the allocator
+    pub fn covers(&self, addr: PhysAddr) -> bool {
+        (self.base..self.end).contains(&addr.as_u64())
+    }
+
+    /// Frees the range in the largest aligned blocks that fit
+    pub unsafe fn add_range(&mut self, start: u64, end: u64) {
+        let mut addr = start;
+        while addr < end {
+            let order = (0..NUM_ORDERS)
+                .rev()
+                .find(|order| addr % order_size(*order) == 0 && addr + order_size(*order) <= end)
+                .unwrap();
+            self.free_block(addr, order);
+            addr += order_size(order);
+        }
+    }
+
+    /// Moves all free memory between `start` and `end` to `other`.
+    /// Blocks crossing the borders get split.
+    pub unsafe fn move_range(&mut self, other: &mut Self, start: u64, end: u64) {
+        let start = align_up(core::cmp::max(start, self.base), order_size(0));
+        let end = core::cmp::min(end, self.end) & !(order_size(0) - 1);
+        if start >= end {
+            return;
+        }
+
+        // Large blocks first, the parts outside of the range are
+        // given back as smaller blocks that are never touched again
+        for order in (0..NUM_ORDERS).rev() {
+            let size = order_size(order);
+            let first = ((start - self.base) / size) as usize;
+            let last = ((end - self.base + size - 1) / size) as usize;
+            let mut bit = first;
+            while bit < last {
+                if *self.orders[order].levels[0].add(bit / 64) == 0 {
+                    bit = (bit / 64 + 1) * 64;
+                    continue;
+                }
+                if self.orders[order].get(bit) {
+                    let addr = self.base + bit as u64 * size;
+                    self.orders[order].clear(bit);
+                    self.num_free_frames -= 1 << order;
+
+                    let inner_start = core::cmp::max(addr, start);
+                    let inner_end = core::cmp::min(addr + size, end);
+                    other.add_range(inner_start, inner_end);
+                    self.add_range(addr, inner_start);
+                    self.add_range(inner_end, addr + size);
+                }
+                bit += 1;
+            }
+        }
+    }
+
+    /// Allocates a naturally aligned block of `order_size(order)` bytes
+    pub fn allocate(&mut self, order: usize) -> Option<PhysAddr> {
+        unsafe {
+            // Find the smallest free block that fits and split it
+            let (found, bit) =
+                (order..NUM_ORDERS).find_map(|o| self.orders[o].first_set().map(|bit| (o, bit)))?;
+            Some(self.split(found, bit, order))
+        }
+    }
+
+    /// Allocates a block that ends below `limit`
+    pub fn allocate_below(&mut self, order: usize, limit: u64) -> Option<PhysAddr> {
+        unsafe {
+            // The first set bit of every order is its lowest free block.
+            // Split the smallest block whose lowest part ends below `limit`
+            let (found, bit) = (order..NUM_ORDERS).find_map(|o| {
+                self.orders[o]
+                    .first_set()
+                    .filter(|bit| {
+                        self.base + *bit as u64 * order_size(o) + order_size(order) <= limit
+                    })
+                    .map(|bit| (o, bit))
+            })?;
+            Some(self.split(found, bit, order))
+        }
+    }
+
+    /// Takes the free block `bit` of order `found` and returns its
+    /// lowest part of order `order`. The upper halves stay free.
+    unsafe fn split(&mut self, found: usize, bit: usize, order: usize) -> PhysAddr {
+        let mut bit = bit;
+        self.orders[found].clear(bit);
+        for lower in (order..found).rev() {
+            bit *= 2;
+            self.orders[lower].set(bit + 1);
+        }
+        self.num_free_frames -= 1 << order;
+        PhysAddr::new(self.base + bit as u64 * order_size(order))
+    }
+
+    /// Allocates `size` contiguous bytes aligned to the next power of two
+    /// of `size`, but to at most 1GiB. Larger ranges are taken from
+    /// consecutive free 1GiB blocks. The unused tail is freed again.
+    pub fn allocate_contiguous(&mut self, size: u64) -> Option<PhysAddr> {
+        let size = align_up(size, order_size(0));
+        let top = NUM_ORDERS - 1;
+        let (addr, block_size) = if size <= order_size(top) {
+            let block_size = size.next_power_of_two();
+            let order = (block_size / order_size(0)).trailing_zeros() as usize;
+            (self.allocate(order)?.as_u64(), block_size)
+        } else {
+            // Blocks of the highest order are never merged,
+            // a run of set bits is a run of free blocks
+            let count = ((size + order_size(top) - 1) / order_size(top)) as usize;
+            let bits = Self::order_bits(self.base, self.end, top) as usize;
+            let mut run = 0;
+            let mut last = None;
+            for bit in 0..bits {
+                run = if unsafe { self.orders[top].get(bit) } { run + 1 } else { 0 };
+                if run == count {
+                    last = Some(bit);
+                    break;
+                }
+            }
+            let first = last? + 1 - count;
+            for bit in first..first + count {
+                unsafe { self.orders[top].clear(bit) };
+            }
+            self.num_free_frames -= count << top;
+            (
+                self.base + first as u64 * order_size(top),
+                count as u64 * order_size(top),
+            )
+        };
+        unsafe { self.add_range(addr + size, addr + block_size) };
+        Some(PhysAddr::new(addr))
+    }
+
+    /// Returns a block allocated with `allocate` of the same order
+    pub unsafe fn deallocate(&mut self, addr: PhysAddr, order: usize) {
+        assert!(
+            addr.is_aligned(order_size(order)),
+            "Block is not aligned to its order"
+        );
+        self.free_block(addr.as_u64(), order);
+    }
+
+    /// Marks a block as free and merges it with its free buddies
+    unsafe fn free_block(&mut self, addr: u64, order: usize) {
+        let mut bit = ((addr - self.base) / order_size(order)) as usize;
+        assert!(
+            !self.orders[order].get(bit),
+            "Frame {:#x} freed twice",
+            addr
+        );
+        self.num_free_frames += 1 << order;
+
+        let mut order = order;
+        while order + 1 < NUM_ORDERS && self.orders[order].get(bit ^ 1) {
+            self.orders[order].clear(bit ^ 1);
+            bit /= 2;
+            order += 1;
+        }
+        self.orders[order].set(bit);
+    }
+
+    pub fn num_free_bytes(&self) -> u64 {
+        self.num_free_frames as u64 * order_size(0)
+    }
+}
+
+unsafe impl<S: PageSize> FrameAllocator<S> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        // 4KiB frames end up as page tables,
+        // which are accessed through the identity map
+        let limit = if S::SIZE == Size4KiB::SIZE {
+            IDENTITY_MAPPED_END
+        } else {
+            u64::MAX
+        };
+        let frame = self
+            .allocate_below(order_of::<S>(), limit)
+            .map(|addr| PhysFrame::from_start_address(addr).unwrap());
+        log::trace!("Allocated frame {:#x?}", frame);
+        frame
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        log::trace!("Deallocated frame {:#x?}", frame);
+        self.deallocate(frame.start_address(), order_of::<S>());
+    }
+}
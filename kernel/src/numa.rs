@@ -0,0 +1,394 @@
+use crate::acpi::Acpi;
+use crate::allocator::slab::SlabAllocator;
+use crate::allocator::{HeapRange, HEAP_MAX_SIZE, HEAP_START};
+use crate::memory;
+use crate::memory::frame_alloc::{order_of, BuddyFrameAllocator, IDENTITY_MAPPED_END};
+use crate::percpu;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::structures::paging::page::PageSize;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::PhysAddr;
+
+/*
+ * Numa nodes
+ * Every proximity domain of the SRAT becomes a node with its own buddy
+ * allocator. numa::init moves the free memory of each domain out of the
+ * boot allocator into the allocator of its node. Memory that belongs to
+ * no domain stays in the boot allocator and is used as the last resort.
+ * Frames are served from the node of the requesting core first, then
+ * from the other nodes in order. Allocations a node had to serve for a
+ * core of another node are counted as remote allocations.
+ * Without an SRAT there is a single node 0 that owns all memory.
+ * Every node also has a heap in its own virtual range behind the
+ * kernel heap, e.g. Vec::new_in(numa::heap(node)).
+ * Node heaps do not cache objects per core.
+ */
+
+/// Start of the node heaps, directly behind the kernel heap
+pub const NODE_HEAP_START: usize = HEAP_START + HEAP_MAX_SIZE;
+
+/// Size of the virtual range reserved for every node heap
+pub const NODE_HEAP_MAX_SIZE: usize = HEAP_MAX_SIZE;
+
+pub type NodeHeap = SlabAllocator<HeapRange>;
+
+pub struct Node {
+    /// Proximity domain from the SRAT
+    pub domain: u32,
+    pub heap: NodeHeap,
+}
+
+static mut NODES: Option<Vec<Node>> = None;
+
+/// Node index of every core, indexed by core index
+static mut CORE_NODES: [usize; bootloader::MAX_CORES] = [0; bootloader::MAX_CORES];
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NodeStats {
+    /// Allocations of cores on this node served by this node
+    pub local_allocs: usize,
+    /// Allocations of cores on this node served by another node
+    pub remote_allocs: usize,
+    pub free_bytes: u64,
+}
+
+struct NodeFrames {
+    frames: BuddyFrameAllocator,
+    /// Physical memory of the node as start..end pairs
+    ranges: Vec<(u64, u64)>,
+    local_allocs: usize,
+    remote_allocs: usize,
+}
+
+impl NodeFrames {
+    /// Creates the empty allocator of a node covering `ranges`.
+    /// The heap exists already, it holds the bitmaps of the node.
+    unsafe fn new(ranges: Vec<(u64, u64)>) -> Self {
+        let frames = if ranges.is_empty() {
+            BuddyFrameAllocator::empty(0, 0, core::ptr::null_mut())
+        } else {
+            let base = ranges.iter().map(|(start, _)| *start).min().unwrap();
+            let end = ranges.iter().map(|(_, end)| *end).max().unwrap();
+            let words = BuddyFrameAllocator::storage_words(base, end);
+            let storage = Box::leak(vec![0u64; words].into_boxed_slice());
+            BuddyFrameAllocator::empty(base, end, storage.as_mut_ptr())
+        };
+        NodeFrames {
+            frames,
+            ranges,
+            local_allocs: 0,
+            remote_allocs: 0,
+        }
+    }
+
+    fn contains(&self, addr: PhysAddr) -> bool {
+        self.ranges
+            .iter()
+            .any(|(start, end)| (*start..*end).contains(&addr.as_u64()))
+    }
+}
+
+/// Frame allocator that prefers the node of the current core
+pub struct NumaFrameAllocator {
+    /// Memory outside of any node, all memory before numa::init
+    default: BuddyFrameAllocator,
+    nodes: Vec<NodeFrames>,
+}
+
+impl NumaFrameAllocator {
+    pub fn new(default: BuddyFrameAllocator) -> Self {
+        NumaFrameAllocator {
+            default,
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Moves the free memory of every node's ranges into the node.
+    /// Does not touch the heap, growing it needs the frame allocator.
+    unsafe fn add_nodes(&mut self, mut nodes: Vec<NodeFrames>) {
+        for (i, node) in nodes.iter_mut().enumerate() {
+            if node.ranges.is_empty() {
+                log::warn!("Numa node {} has no memory", i);
+            }
+            for (start, end) in node.ranges.iter() {
+                self.default.move_range(&mut node.frames, *start, *end);
+            }
+        }
+        self.nodes = nodes;
+    }
+
+    pub fn num_free_bytes(&self) -> u64 {
+        self.default.num_free_bytes()
+            + self
+                .nodes
+                .iter()
+                .map(|node| node.frames.num_free_bytes())
+                .sum::<u64>()
+    }
+
+    pub fn stats(&self, node: usize) -> Option<NodeStats> {
+        if self.nodes.is_empty() && node == 0 {
+            return Some(NodeStats {
+                free_bytes: self.default.num_free_bytes(),
+                ..NodeStats::default()
+            });
+        }
+        let node = self.nodes.get(node)?;
+        Some(NodeStats {
+            local_allocs: node.local_allocs,
+            remote_allocs: node.remote_allocs,
+            free_bytes: node.frames.num_free_bytes(),
+        })
+    }
+
+    /// Allocates a block of the given order from one node only
+    pub fn alloc_on_node(&mut self, node: usize, order: usize) -> Option<PhysAddr> {
+        if self.nodes.is_empty() && node == 0 {
+            return self.default.allocate(order);
+        }
+        self.nodes.get_mut(node)?.frames.allocate(order)
+    }
+
+    /// Allocates a block that ends below `limit`, preferring the node of the current core
+    pub fn alloc_local(&mut self, order: usize, limit: u64) -> Option<PhysAddr> {
+        if self.nodes.is_empty() {
+            return self.default.allocate_below(order, limit);
+        }
+
+        let local = current_node();
+        if let Some(addr) = self.nodes[local].frames.allocate_below(order, limit) {
+            self.nodes[local].local_allocs += 1;
+            return Some(addr);
+        }
+        for i in (0..self.nodes.len()).filter(|i| *i != local) {
+            if let Some(addr) = self.nodes[i].frames.allocate_below(order, limit) {
+                self.nodes[local].remote_allocs += 1;
+                return Some(addr);
+            }
+        }
+        self.default.allocate_below(order, limit)
+    }
+
+    /// Allocates a physically contiguous range, preferring the node of the current core.
+    /// See BuddyFrameAllocator::allocate_contiguous for the alignment.
+    pub fn alloc_contiguous(&mut self, size: u64) -> Option<PhysAddr> {
+        if self.nodes.is_empty() {
+            return self.default.allocate_contiguous(size);
+        }
+
+        let local = current_node();
+        if let Some(addr) = self.nodes[local].frames.allocate_contiguous(size) {
+            self.nodes[local].local_allocs += 1;
+            return Some(addr);
+        }
+        for i in (0..self.nodes.len()).filter(|i| *i != local) {
+            if let Some(addr) = self.nodes[i].frames.allocate_contiguous(size) {
+                self.nodes[local].remote_allocs += 1;
+                return Some(addr);
+            }
+        }
+        self.default.allocate_contiguous(size)
+    }
+
+    /// Returns a block to the node it belongs to
+    pub unsafe fn dealloc(&mut self, addr: PhysAddr, order: usize) {
+        match self.nodes.iter_mut().find(|node| node.contains(addr)) {
+            Some(node) => node.frames.deallocate(addr, order),
+            None => self.default.deallocate(addr, order),
+        }
+    }
+
+    /// Returns `start..end` to the node it belongs to, the range
+    /// may cover a part of an allocated block
+    pub unsafe fn dealloc_range(&mut self, start: PhysAddr, end: PhysAddr) {
+        match self.nodes.iter_mut().find(|node| node.contains(start)) {
+            Some(node) => node.frames.add_range(start.as_u64(), end.as_u64()),
+            None => self.default.add_range(start.as_u64(), end.as_u64()),
+        }
+    }
+
+    /// Returns a frame allocator that takes 2MiB frames from the given node
+    pub fn on_node(&mut self, node: usize) -> OnNode {
+        OnNode { frames: self, node }
+    }
+}
+
+unsafe impl<S: PageSize> FrameAllocator<S> for NumaFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        // 4KiB frames end up as page tables,
+        // which are accessed through the identity map
+        let limit = if S::SIZE == Size4KiB::SIZE {
+            IDENTITY_MAPPED_END
+        } else {
+            u64::MAX
+        };
+        let frame = self
+            .alloc_local(order_of::<S>(), limit)
+            .map(|addr| PhysFrame::from_start_address(addr).unwrap());
+        log::trace!("Allocated frame {:#x?}", frame);
+        frame
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for NumaFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        log::trace!("Deallocated frame {:#x?}", frame);
+        self.dealloc(frame.start_address(), order_of::<S>());
+    }
+}
+
+/// Takes 2MiB frames from one node and page tables from anywhere
+pub struct OnNode<'a> {
+    frames: &'a mut NumaFrameAllocator,
+    node: usize,
+}
+
+unsafe impl FrameAllocator<Size2MiB> for OnNode<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.frames
+            .alloc_on_node(self.node, order_of::<Size2MiB>())
+            .map(|addr| PhysFrame::from_start_address(addr).unwrap())
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for OnNode<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.frames.allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for OnNode<'_> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.frames.deallocate_frame(frame)
+    }
+}
+
+/// Creates the nodes from the SRAT. Has to be called once by the bsp
+/// before the other cores are launched.
+pub unsafe fn init(acpi: &Acpi) {
+    let mut domains: Vec<u32> = Vec::new();
+    if let Some(memory_domains) = acpi.memory_domains.as_ref() {
+        domains.extend(memory_domains.keys());
+    }
+    if let Some(apic_domains) = acpi.apic_domains.as_ref() {
+        domains.extend(apic_domains.values());
+    }
+    domains.sort_unstable();
+    domains.dedup();
+
+    if domains.is_empty() {
+        log::info!("No SRAT found, all memory belongs to node 0");
+        domains.push(0);
+    } else {
+        // Allocate the bitmaps before taking the lock, the heap may
+        // have to grow for them
+        let nodes = domains
+            .iter()
+            .map(|domain| {
+                // Ranges of the SRAT are inclusive
+                let ranges: Vec<(u64, u64)> = acpi
+                    .memory_domains
+                    .as_ref()
+                    .and_then(|md| md.get(domain))
+                    .map(|rs| rs.entries().iter().map(|r| (r.start, r.end + 1)).collect())
+                    .unwrap_or_default();
+                NodeFrames::new(ranges)
+            })
+            .collect();
+        let (_, frame_allocator) = memory::get().unwrap();
+        frame_allocator.lock().add_nodes(nodes);
+    }
+
+    for (core_index, node) in CORE_NODES[..percpu::num_cores()].iter_mut().enumerate() {
+        let domain = percpu::apic_id_of(core_index).and_then(|apic_id| {
+            acpi.apic_domains
+                .as_ref()
+                .and_then(|ad| ad.get(&apic_id).copied())
+        });
+        *node = domain
+            .and_then(|d| domains.iter().position(|x| *x == d))
+            .unwrap_or(0);
+    }
+
+    NODES = Some(
+        domains
+            .iter()
+            .enumerate()
+            .map(|(i, domain)| Node {
+                domain: *domain,
+                heap: NodeHeap::uncached(HeapRange::new(
+                    NODE_HEAP_START + i * NODE_HEAP_MAX_SIZE,
+                    NODE_HEAP_MAX_SIZE,
+                    Some(i),
+                )),
+            })
+            .collect(),
+    );
+
+    for (i, node) in nodes().iter().enumerate() {
+        log::info!(
+            "Numa node {} domain {}: {} MiB free, {} cores",
+            i,
+            node.domain,
+            stats(i).free_bytes / (1024 * 1024),
+            CORE_NODES[..percpu::num_cores()]
+                .iter()
+                .filter(|n| **n == i)
+                .count()
+        );
+    }
+}
+
+fn nodes() -> &'static [Node] {
+    unsafe { NODES.as_deref().expect("numa::init has not been called") }
+}
+
+pub fn num_nodes() -> usize {
+    nodes().len()
+}
+
+/// Returns the node of the current core
+pub fn current_node() -> usize {
+    unsafe { CORE_NODES[percpu!(core_index)] }
+}
+
+/// Returns the node of the core with the given apic id
+pub fn node_of(apic_id: u32) -> Option<usize> {
+    percpu::core_index_of(apic_id).map(|i| unsafe { CORE_NODES[i] })
+}
+
+/// Returns the heap of a node
+pub fn heap(node: usize) -> &'static NodeHeap {
+    &nodes()[node].heap
+}
+
+/// Allocates a frame from the given node only
+pub fn alloc_on_node<S: PageSize>(node: usize) -> Option<PhysFrame<S>> {
+    let (_, frame_allocator) = memory::get()?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        frame_allocator
+            .lock()
+            .alloc_on_node(node, order_of::<S>())
+            .map(|addr| PhysFrame::from_start_address(addr).unwrap())
+    })
+}
+
+/// Returns a frame to the node it was allocated from
+pub unsafe fn dealloc_frame<S: PageSize>(frame: PhysFrame<S>) {
+    let (_, frame_allocator) = memory::get().unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        frame_allocator
+            .lock()
+            .dealloc(frame.start_address(), order_of::<S>())
+    });
+}
+
+/// Returns the allocation statistics of a node
+pub fn stats(node: usize) -> NodeStats {
+    let (_, frame_allocator) = memory::get().unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        frame_allocator.lock().stats(node).unwrap_or_default()
+    })
+}
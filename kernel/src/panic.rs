@@ -0,0 +1,83 @@
+use crate::serial::RawWriter;
+use crate::time::{Duration, Instant};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/*
+ * Panic path shared by the kernel and the tests
+ * The first core that panics sends a NMI to all other cores, they mark
+ * themselves Halted and stop with interrupts disabled. Output goes
+ * straight to the uart, the serial and vga locks could be held by a core
+ * that has just been stopped or by the panicking core itself.
+ * A core panicking while another one already does only halts.
+ */
+
+/// Time the other cores have to react to the NMI
+const HALT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Set by the first core that panics
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Returns true if a core has panicked
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::SeqCst)
+}
+
+/// Stops all cores and prints the panic message with a stack trace.
+/// Exits qemu with QemuExitCode::Failed if `exit` is set.
+pub fn handle(info: &PanicInfo, exit: bool) -> ! {
+    x86_64::instructions::interrupts::disable();
+    let mut w = unsafe { RawWriter::new() };
+
+    // Let an attached debugger look at the panicking core first,
+    // the stub stops the other cores itself
+    if !is_panicking() && crate::gdb::is_attached() {
+        let _ = writeln!(w, "\n{}, entering gdb", info);
+        crate::gdb::breakpoint(crate::gdb::SIGABRT);
+    }
+
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        // Either a panic inside the panic path or another core
+        // that panicked before the NMI arrived
+        let _ = writeln!(w, "\nNested panic: {}", info);
+        halt();
+    }
+
+    stop_other_cores();
+
+    let core = if crate::percpu::is_initialized() {
+        crate::percpu!(apic_id) as i64
+    } else {
+        -1
+    };
+    let _ = writeln!(w, "\nPANIC on core {}: {}", core, info);
+    let _ = crate::backtrace::write_backtrace(&mut w, crate::backtrace::frame_pointer());
+
+    if exit {
+        crate::exit_qemu(crate::QemuExitCode::Failed);
+    }
+    halt();
+}
+
+/// Sends a NMI to all other cores and waits until they halted
+fn stop_other_cores() {
+    if crate::smp::num_cores_online() <= 1 {
+        return;
+    }
+    unsafe { crate::apic::send_nmi_to_others() };
+
+    let deadline = Instant::after(HALT_TIMEOUT);
+    while !crate::smp::others_halted() && !deadline.has_passed() {
+        core::hint::spin_loop();
+    }
+}
+
+/// Marks the current core as Halted and stops it for good.
+/// Called by the NMI handler on the cores stopped by a panic.
+pub fn halt() -> ! {
+    crate::smp::set_core_halted();
+    loop {
+        unsafe { asm!("cli; hlt", options(nomem, nostack)) };
+    }
+}
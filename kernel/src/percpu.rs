@@ -0,0 +1,169 @@
+use crate::allocator::slab::Magazines;
+use crate::ipi::Call;
+use crate::timer::TimerWheel;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicUsize};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::gdt::GlobalDescriptorTable;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/*
+ * Per core data area
+ * Every core owns one cache line aligned PerCpu block.
+ * The address of the block gets written into the GsBase and KernelGsBase MSR,
+ * the first field of the block points to the block itself.
+ * Thus reading gs:[0] returns the block of the current core without
+ * executing cpuid.
+ * The kernel heap keeps its per core state here as well, the allocation
+ * counters and the slab magazines. Only refilling or draining a magazine
+ * takes the central locks of the allocator.
+ */
+// Only used to initialize the array below
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: PerCpu = PerCpu::empty();
+static mut PERCPU: [PerCpu; bootloader::MAX_CORES] = [EMPTY; bootloader::MAX_CORES];
+
+/// Core list of the bootloader, used to translate between apic ids and core indexes
+static mut BOOT_INFO: Option<&'static bootloader::bootinfo::BootInfo> = None;
+
+#[repr(C, align(64))]
+pub struct PerCpu {
+    /// Has to be the first field, gets read through gs:[0]
+    self_ptr: *const PerCpu,
+    /// Index of this core in BootInfo.cores
+    pub core_index: usize,
+    pub apic_id: u32,
+    pub gdt: GlobalDescriptorTable,
+    pub tss: TaskStateSegment,
+    /// Number of heap allocations done by this core
+    pub num_allocs: AtomicUsize,
+    /// Number of heap deallocations done by this core
+    pub num_deallocs: AtomicUsize,
+    /// Pending deadlines of this core
+    pub timer_wheel: spin::Mutex<TimerWheel>,
+    /// Cross core calls posted to this core
+    pub call_mailbox: spin::Mutex<Vec<Arc<Call>>>,
+    /// Free heap objects cached by this core
+    pub magazines: Magazines,
+    /// Bitmask of the performance counters handed out on this core
+    pub pmu_counters: AtomicU32,
+}
+
+impl PerCpu {
+    const fn empty() -> Self {
+        PerCpu {
+            self_ptr: core::ptr::null(),
+            core_index: 0,
+            apic_id: 0,
+            gdt: GlobalDescriptorTable::new(),
+            tss: TaskStateSegment::new(),
+            num_allocs: AtomicUsize::new(0),
+            num_deallocs: AtomicUsize::new(0),
+            timer_wheel: spin::Mutex::new(TimerWheel::new()),
+            call_mailbox: spin::Mutex::new(Vec::new()),
+            magazines: Magazines::new(),
+            pmu_counters: AtomicU32::new(0),
+        }
+    }
+}
+
+/// Access a field of the per cpu block of the current core
+/// `percpu!()` returns the whole block
+#[macro_export]
+macro_rules! percpu {
+    () => {
+        $crate::percpu::get()
+    };
+    ($field:ident) => {
+        $crate::percpu::get().$field
+    };
+}
+
+/// Initializes the per cpu block of the current core and installs it
+/// into the GsBase and KernelGsBase MSR.
+/// Has to be called on every core before any other per cpu access.
+pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
+    let apic_id = crate::apic::apic_id();
+    let (_, core_index) = boot_info
+        .cores
+        .get_by_apic_id(apic_id)
+        .expect("Couldn't find core with apic id");
+
+    if BOOT_INFO.is_none() {
+        BOOT_INFO = Some(boot_info);
+    }
+
+    let block = &mut PERCPU[core_index];
+    block.self_ptr = block;
+    block.core_index = core_index;
+    block.apic_id = apic_id;
+
+    let addr = VirtAddr::new(block as *const PerCpu as u64);
+    GsBase::write(addr);
+    // Also set the swapgs shadow, there is no user space to swap with
+    KernelGsBase::write(addr);
+}
+
+/// Returns true if the per cpu block of the current core has been installed
+pub fn is_initialized() -> bool {
+    GsBase::read().as_u64() != 0
+}
+
+#[inline]
+fn get_ptr() -> *mut PerCpu {
+    let ptr: *mut PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, preserves_flags, readonly));
+    }
+    ptr
+}
+
+/// Returns the per cpu block of the current core
+#[inline]
+pub fn get() -> &'static PerCpu {
+    unsafe { &*get_ptr() }
+}
+
+/// Returns the per cpu block of the current core mutable
+/// The caller has to make sure that no other reference to the block is alive
+#[inline]
+pub unsafe fn get_mut() -> &'static mut PerCpu {
+    &mut *get_ptr()
+}
+
+/// Returns the per cpu block of another core
+pub fn get_by_core_index(core_index: usize) -> &'static PerCpu {
+    unsafe { &PERCPU[core_index] }
+}
+
+/// Returns the per cpu block of the core with the given apic id
+/// if that core has already installed it
+pub fn get_by_apic_id(apic_id: u32) -> Option<&'static PerCpu> {
+    let block = get_by_core_index(core_index_of(apic_id)?);
+    if block.self_ptr.is_null() {
+        None
+    } else {
+        Some(block)
+    }
+}
+
+fn cores() -> &'static bootloader::bootinfo::Cores {
+    unsafe { &BOOT_INFO.expect("Per cpu blocks not initialized").cores }
+}
+
+/// Number of cores listed by the bootloader
+pub fn num_cores() -> usize {
+    cores().len()
+}
+
+/// Translates an apic id into the index of the core in BootInfo.cores
+pub fn core_index_of(apic_id: u32) -> Option<usize> {
+    cores().get_by_apic_id(apic_id).map(|(_, index)| index)
+}
+
+/// Translates a core index into the apic id of the core
+pub fn apic_id_of(core_index: usize) -> Option<u32> {
+    cores().get(core_index)?.get_apic_id()
+}
@@ -0,0 +1,368 @@
+use crate::pmu_regs::*;
+use core::fmt;
+use core::sync::atomic::Ordering;
+use raw_cpuid::CpuId;
+use x86_64::registers::model_specific::Msr;
+
+/*
+ * Core performance counters
+ * AMD has four legacy counters, six with the PerfCtrExtCore extension.
+ * Intel reports the number of general purpose counters in cpuid leaf 0xa.
+ * Counters are programmed per core through MSRs and read with rdpmc,
+ * which is cheap enough to be used inside the measured code.
+ * Every core keeps the counters it handed out in PerCpu::pmu_counters,
+ * asking for more events than free counters fails.
+ * qemu without kvm reports counters it does not implement, init probes
+ * them once and disables the pmu if they do not count.
+ */
+
+/// Maximum number of events counted at once
+pub const MAX_COUNTERS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vendor {
+    Amd,
+    Intel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    AmdLegacy,
+    AmdExtended,
+    Intel { version: u8 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pmu {
+    layout: Layout,
+    num_counters: usize,
+    /// Bit width of the counters
+    width: u8,
+}
+
+static mut PMU: Option<Pmu> = None;
+
+impl Pmu {
+    fn vendor(&self) -> Vendor {
+        match self.layout {
+            Layout::AmdLegacy | Layout::AmdExtended => Vendor::Amd,
+            Layout::Intel { .. } => Vendor::Intel,
+        }
+    }
+
+    fn ctl_msr(&self, counter: usize) -> u32 {
+        let counter = counter as u32;
+        match self.layout {
+            Layout::AmdLegacy => AMD_PERF_CTL + counter,
+            Layout::AmdExtended => AMD_PERF_CTL_EXT + counter * 2,
+            Layout::Intel { .. } => IA32_PERFEVTSEL + counter,
+        }
+    }
+
+    fn ctr_msr(&self, counter: usize) -> u32 {
+        let counter = counter as u32;
+        match self.layout {
+            Layout::AmdLegacy => AMD_PERF_CTR + counter,
+            Layout::AmdExtended => AMD_PERF_CTL_EXT + counter * 2 + 1,
+            Layout::Intel { .. } => IA32_PMC + counter,
+        }
+    }
+
+    fn mask(&self) -> u64 {
+        if self.width >= 64 {
+            u64::MAX
+        } else {
+            (1 << self.width) - 1
+        }
+    }
+
+    /// Starts `event` on a counter of the current core, counting from zero
+    unsafe fn program(&self, counter: usize, event: Event) {
+        let (select, unit_mask) = event.encoding(self.vendor());
+        let ctl = PerfEvtSel::new()
+            .with_event(select as u8)
+            .with_event_high((select >> 8) as u8 & 0xf)
+            .with_unit_mask(unit_mask)
+            .with_os(1)
+            .with_usr(1)
+            .with_enable(1);
+
+        Msr::new(self.ctl_msr(counter)).write(0);
+        Msr::new(self.ctr_msr(counter)).write(0);
+        if let Layout::Intel { version } = self.layout {
+            if version >= 2 {
+                let mut global = Msr::new(IA32_PERF_GLOBAL_CTRL);
+                global.write(global.read() | 1 << counter);
+            }
+        }
+        Msr::new(self.ctl_msr(counter)).write(u64::from_le_bytes(ctl.into_bytes()));
+    }
+
+    unsafe fn disable(&self, counter: usize) {
+        Msr::new(self.ctl_msr(counter)).write(0);
+    }
+}
+
+/// Detects the core performance counters, called once on the bsp
+pub unsafe fn init() {
+    let cpuid = CpuId::new();
+    let vendor = match cpuid.get_vendor_info() {
+        Some(info) if info.as_str() == "AuthenticAMD" => Vendor::Amd,
+        Some(info) if info.as_str() == "GenuineIntel" => Vendor::Intel,
+        _ => {
+            log::warn!("Unknown cpu vendor, no performance counters");
+            return;
+        }
+    };
+
+    let pmu = match vendor {
+        Vendor::Amd => {
+            let extended = cpuid
+                .get_extended_processor_and_feature_identifiers()
+                .map_or(false, |info| info.has_perf_cntr_extensions());
+            Pmu {
+                layout: if extended {
+                    Layout::AmdExtended
+                } else {
+                    Layout::AmdLegacy
+                },
+                num_counters: if extended { 6 } else { 4 },
+                width: 48,
+            }
+        }
+        Vendor::Intel => match cpuid.get_performance_monitoring_info() {
+            Some(info) if info.version_id() > 0 && info.number_of_counters() > 0 => Pmu {
+                layout: Layout::Intel {
+                    version: info.version_id(),
+                },
+                num_counters: info.number_of_counters() as usize,
+                width: info.counter_bit_width(),
+            },
+            _ => {
+                log::warn!("No architectural performance monitoring");
+                return;
+            }
+        },
+    };
+    let pmu = Pmu {
+        num_counters: core::cmp::min(pmu.num_counters, MAX_COUNTERS),
+        ..pmu
+    };
+
+    // Count cycles for a moment and read the counter without rdpmc,
+    // which raises #UD on emulated pmus
+    pmu.program(0, Event::Cycles);
+    for _ in 0..1000 {
+        core::hint::spin_loop();
+    }
+    let cycles = Msr::new(pmu.ctr_msr(0)).read();
+    pmu.disable(0);
+    if cycles == 0 {
+        log::warn!("Performance counters do not count, pmu disabled");
+        return;
+    }
+
+    log::info!(
+        "{:?} pmu with {} counters of {} bits",
+        vendor,
+        pmu.num_counters,
+        pmu.width
+    );
+    PMU = Some(pmu);
+}
+
+/// Returns true if the core performance counters can be used
+pub fn is_available() -> bool {
+    unsafe { PMU.is_some() }
+}
+
+/// Number of general purpose counters of every core
+pub fn num_counters() -> usize {
+    unsafe { PMU.map_or(0, |pmu| pmu.num_counters) }
+}
+
+/// Reads a performance counter. Faults if `index` is not a counter.
+#[inline(always)]
+pub unsafe fn rdpmc(index: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdpmc", in("ecx") index, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    (high as u64) << 32 | low as u64
+}
+
+/// Events with a name for AMD family 17h and Intel Skylake or later.
+/// Other models may need Raw events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Core cycles while not halted
+    Cycles,
+    /// Retired instructions
+    Instructions,
+    /// L2 cache misses of data and instruction fetches
+    L2Misses,
+    /// Data TLB misses
+    DtlbMisses,
+    /// Instruction TLB misses
+    ItlbMisses,
+    /// Retired mispredicted branches
+    BranchMisses,
+    /// Vendor specific event select and unit mask
+    Raw { event: u16, unit_mask: u8 },
+}
+
+impl Event {
+    /// Event select and unit mask of this event
+    fn encoding(self, vendor: Vendor) -> (u16, u8) {
+        match (vendor, self) {
+            (_, Event::Raw { event, unit_mask }) => (event, unit_mask),
+            (Vendor::Amd, Event::Cycles) => (0x076, 0x00),
+            (Vendor::Amd, Event::Instructions) => (0x0c0, 0x00),
+            (Vendor::Amd, Event::L2Misses) => (0x064, 0x09),
+            (Vendor::Amd, Event::DtlbMisses) => (0x045, 0xff),
+            (Vendor::Amd, Event::ItlbMisses) => (0x085, 0x07),
+            (Vendor::Amd, Event::BranchMisses) => (0x0c3, 0x00),
+            (Vendor::Intel, Event::Cycles) => (0x3c, 0x00),
+            (Vendor::Intel, Event::Instructions) => (0xc0, 0x00),
+            (Vendor::Intel, Event::L2Misses) => (0x24, 0x3f),
+            (Vendor::Intel, Event::DtlbMisses) => (0x08, 0x0e),
+            (Vendor::Intel, Event::ItlbMisses) => (0x85, 0x0e),
+            (Vendor::Intel, Event::BranchMisses) => (0xc5, 0x00),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Cycles => "cycles",
+            Event::Instructions => "instructions",
+            Event::L2Misses => "l2-misses",
+            Event::DtlbMisses => "dtlb-misses",
+            Event::ItlbMisses => "itlb-misses",
+            Event::BranchMisses => "branch-misses",
+            Event::Raw { .. } => "raw",
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Raw { event, unit_mask } => write!(f, "raw:{:#x}:{:#x}", event, unit_mask),
+            event => f.write_str(event.name()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmuError {
+    /// No usable performance counters
+    NotSupported,
+    /// More events than counters
+    TooManyEvents,
+    /// Not enough counters left on this core
+    CountersBusy,
+}
+
+/// Counter values read at one point in time, in the order of the events
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Sample {
+    values: [u64; MAX_COUNTERS],
+    len: usize,
+}
+
+impl Sample {
+    pub fn values(&self) -> &[u64] {
+        &self.values[..self.len]
+    }
+
+    /// Events counted since `earlier`, a wrapped counter is accounted for
+    pub fn delta(&self, earlier: &Sample) -> Sample {
+        let mask = unsafe { PMU.map_or(u64::MAX, |pmu| pmu.mask()) };
+        let mut delta = *self;
+        for (d, e) in delta.values.iter_mut().zip(earlier.values.iter()) {
+            *d = d.wrapping_sub(*e) & mask;
+        }
+        delta
+    }
+}
+
+/// Events counted by the performance counters of the current core.
+/// Has to be read and dropped on the core that created it.
+pub struct Counters {
+    events: [Event; MAX_COUNTERS],
+    counters: [u8; MAX_COUNTERS],
+    len: usize,
+    core_index: usize,
+}
+
+impl Counters {
+    /// Claims a free counter of the current core for every event and starts them
+    pub fn new(events: &[Event]) -> Result<Self, PmuError> {
+        let pmu = unsafe { PMU.ok_or(PmuError::NotSupported)? };
+        if events.len() > pmu.num_counters {
+            return Err(PmuError::TooManyEvents);
+        }
+
+        // Claim the lowest free counters
+        let in_use = &crate::percpu!().pmu_counters;
+        let mut claimed: u32 = 0;
+        in_use
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                claimed = 0;
+                let free = (0..pmu.num_counters).filter(|i| used & (1 << i) == 0);
+                for i in free.take(events.len()) {
+                    claimed |= 1 << i;
+                }
+                if claimed.count_ones() as usize == events.len() {
+                    Some(used | claimed)
+                } else {
+                    None
+                }
+            })
+            .map_err(|_| PmuError::CountersBusy)?;
+
+        let mut counters = Counters {
+            events: [Event::Cycles; MAX_COUNTERS],
+            counters: [0; MAX_COUNTERS],
+            len: events.len(),
+            core_index: crate::percpu!(core_index),
+        };
+        let indices = (0..pmu.num_counters).filter(|i| claimed & (1 << i) != 0);
+        for (i, (event, counter)) in events.iter().zip(indices).enumerate() {
+            counters.events[i] = *event;
+            counters.counters[i] = counter as u8;
+            unsafe { pmu.program(counter, *event) };
+        }
+        Ok(counters)
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events[..self.len]
+    }
+
+    /// Reads all counters with rdpmc
+    #[inline(always)]
+    pub fn read(&self) -> Sample {
+        let mut sample = Sample {
+            values: [0; MAX_COUNTERS],
+            len: self.len,
+        };
+        for (value, counter) in sample.values.iter_mut().zip(&self.counters[..self.len]) {
+            *value = unsafe { rdpmc(*counter as u32) };
+        }
+        sample
+    }
+}
+
+impl Drop for Counters {
+    fn drop(&mut self) {
+        debug_assert_eq!(self.core_index, crate::percpu!(core_index));
+        let pmu = unsafe { PMU.unwrap() };
+        let mut claimed = 0;
+        for counter in &self.counters[..self.len] {
+            unsafe { pmu.disable(*counter as usize) };
+            claimed |= 1 << *counter as u32;
+        }
+        crate::percpu!()
+            .pmu_counters
+            .fetch_and(!claimed, Ordering::SeqCst);
+    }
+}
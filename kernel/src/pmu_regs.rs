@@ -0,0 +1,42 @@
+use modular_bitfield::prelude::*;
+
+/// AMD legacy PERF_CTL0-3 and PERF_CTR0-3
+pub const AMD_PERF_CTL: u32 = 0xC001_0000;
+pub const AMD_PERF_CTR: u32 = 0xC001_0004;
+
+/// AMD PERF_CTL0-5 with PerfCtrExtCore, each
+/// control register is followed by its counter
+pub const AMD_PERF_CTL_EXT: u32 = 0xC001_0200;
+
+/// Intel architectural performance monitoring
+pub const IA32_PERFEVTSEL: u32 = 0x0000_0186;
+pub const IA32_PMC: u32 = 0x0000_00C1;
+pub const IA32_PERF_GLOBAL_CTRL: u32 = 0x0000_038F;
+
+/// PERF_CTL on AMD, IA32_PERFEVTSELx on Intel
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct PerfEvtSel {
+    pub event: B8,
+    pub unit_mask: B8,
+    /// Count in user mode
+    pub usr: B1,
+    /// Count in kernel mode
+    pub os: B1,
+    pub edge: B1,
+    pub pin_control: B1,
+    /// Interrupt through the LVT performance counter entry on overflow
+    pub int: B1,
+    pub any_thread: B1,
+    pub enable: B1,
+    pub invert: B1,
+    pub counter_mask: B8,
+    /// Bits 11:8 of the event select, AMD only
+    pub event_high: B4,
+    pub res0: B4,
+    /// AMD only
+    pub guest_only: B1,
+    /// AMD only
+    pub host_only: B1,
+    pub res1: B22,
+}
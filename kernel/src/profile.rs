@@ -0,0 +1,11 @@
+pub mod ring;
+pub mod sampler;
+pub mod stream;
+
+/*
+ * Sampling profilers
+ * Samplers record into per core ring buffers from interrupt or NMI
+ * context and get dumped over COM1 once sampling stopped.
+ * The timer interrupt sampler works on every x86 cpu, IBS in crate::ibs
+ * needs AMD and writes a binary stream.
+ */
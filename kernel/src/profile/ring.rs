@@ -0,0 +1,74 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+ * Sample ring buffer of one core
+ * Only the owning core pushes, mostly from NMI context, thus pushing
+ * neither allocates nor locks. A full ring overwrites its oldest samples.
+ * Reading needs a mutable reference, which the sampler hands out after
+ * sampling has been stopped on all cores.
+ */
+
+pub struct SampleRing<T> {
+    slots: UnsafeCell<Box<[T]>>,
+    /// Samples pushed since the last clear
+    written: AtomicUsize,
+}
+
+unsafe impl<T: Send> Sync for SampleRing<T> {}
+
+impl<T: Copy + Default> SampleRing<T> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        SampleRing {
+            slots: UnsafeCell::new(vec![T::default(); capacity].into_boxed_slice()),
+            written: AtomicUsize::new(0),
+        }
+    }
+
+    /// Stores `sample`, overwriting the oldest one if the ring is full.
+    /// Must only be called by the core owning the ring, never while
+    /// someone else reads it.
+    #[inline]
+    pub unsafe fn push(&self, sample: T) {
+        let slots = &mut *self.slots.get();
+        let index = self.written.load(Ordering::Relaxed);
+        slots[index % slots.len()] = sample;
+        self.written.store(index + 1, Ordering::Release);
+    }
+
+    pub fn capacity(&self) -> usize {
+        unsafe { (*self.slots.get()).len() }
+    }
+
+    /// Number of samples stored
+    pub fn len(&self) -> usize {
+        core::cmp::min(self.written.load(Ordering::Acquire), self.capacity())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of samples that have been overwritten
+    pub fn lost(&self) -> usize {
+        self.written
+            .load(Ordering::Acquire)
+            .saturating_sub(self.capacity())
+    }
+
+    /// Returns the stored samples, oldest first
+    pub fn iter(&mut self) -> impl ExactSizeIterator<Item = &T> {
+        let written = *self.written.get_mut();
+        let slots: &[T] = self.slots.get_mut();
+        let capacity = slots.len();
+        let start = written.saturating_sub(capacity);
+        (start..written).map(move |i| &slots[i % capacity])
+    }
+
+    pub fn clear(&mut self) {
+        *self.written.get_mut() = 0;
+    }
+}
@@ -0,0 +1,281 @@
+use crate::backtrace::{self, Demangle};
+use crate::println;
+use crate::profile::ring::SampleRing;
+use crate::profile::stream::{self, Kind, StreamSample};
+use crate::time::{self, Duration};
+use crate::timer::{self, TimerAction};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+
+/*
+ * Timer interrupt sampling profiler
+ * While running, every core arms a periodic timeout on its apic timer.
+ * The timeout only marks the tick as due, the timer interrupt handler
+ * then records the interrupted rip and the frame pointer chain below it
+ * into the ring of the core. Code running with interrupts disabled is
+ * never sampled, IBS does not have that blind spot but needs AMD.
+ * dump_folded prints the samples of all cores as folded stacks, one
+ * line per distinct call chain with the number of samples:
+ *
+ *   kernel_main;perf_kernel::executor::run;perf_kernel::foo 42
+ *
+ * which flamegraph.pl and inferno-flamegraph read as is.
+ * dump writes the raw samples as binary stream instead, for the
+ * kprof host tool.
+ */
+
+/// Deepest call chain recorded, including the interrupted rip
+pub const MAX_DEPTH: usize = 32;
+
+/// Samples every core keeps until they get dumped
+pub const RING_SAMPLES: usize = 2048;
+
+/// Interrupted rip followed by the return addresses of its callers
+#[derive(Debug, Clone, Copy)]
+pub struct StackSample {
+    frames: [u64; MAX_DEPTH],
+    len: u8,
+}
+
+impl Default for StackSample {
+    fn default() -> Self {
+        StackSample {
+            frames: [0; MAX_DEPTH],
+            len: 0,
+        }
+    }
+}
+
+impl StackSample {
+    /// Innermost frame first
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len as usize]
+    }
+}
+
+/// Encoded as the frames, innermost first, each u64
+impl StreamSample for StackSample {
+    const KIND: Kind = Kind::Stack;
+    const SIZE: usize = 0;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        for frame in self.frames() {
+            out.extend_from_slice(&frame.to_le_bytes());
+        }
+    }
+}
+
+struct Core {
+    ring: SampleRing<StackSample>,
+    /// Set by the periodic timeout, taken by the next timer interrupt
+    due: AtomicBool,
+}
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Incremented by every start, timeouts of an earlier run stop rearming
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Serializes start, stop and reading the rings
+static CONTROL: spin::Mutex<()> = spin::Mutex::new(());
+
+/// Indexed by the core index
+static mut CORES: Vec<Core> = Vec::new();
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// Clears the rings and samples every online core once per `period`.
+/// Returns false if the profiler is already running.
+pub fn start(period: Duration) -> bool {
+    let _control = CONTROL.lock();
+    if is_running() {
+        return false;
+    }
+    unsafe {
+        if CORES.is_empty() {
+            CORES = (0..crate::percpu::num_cores())
+                .map(|_| Core {
+                    ring: SampleRing::new(RING_SAMPLES),
+                    due: AtomicBool::new(false),
+                })
+                .collect();
+        }
+        for core in CORES.iter_mut() {
+            core.ring.clear();
+            *core.due.get_mut() = false;
+        }
+    }
+
+    let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    RUNNING.store(true, Ordering::SeqCst);
+    crate::ipi::call_on_all(move || schedule(period, generation));
+    true
+}
+
+/// Stops sampling on all cores
+pub fn stop() {
+    let _control = CONTROL.lock();
+    if !is_running() {
+        return;
+    }
+    RUNNING.store(false, Ordering::SeqCst);
+    // Interrupt handlers run with interrupts disabled, once every core
+    // ran the call none of them is still inside tick
+    crate::ipi::call_on_all(|| ());
+}
+
+/// Arms the next tick of the current core
+fn schedule(period: Duration, generation: u64) {
+    timer::set_timeout(
+        time::future(period),
+        TimerAction::callback(move || {
+            if !is_running() || GENERATION.load(Ordering::SeqCst) != generation {
+                return;
+            }
+            if let Some(core) = current_core() {
+                core.due.store(true, Ordering::Relaxed);
+            }
+            schedule(period, generation);
+        }),
+    );
+}
+
+fn current_core() -> Option<&'static Core> {
+    unsafe { CORES.get(crate::percpu!(core_index)) }
+}
+
+/// Called by the timer interrupt handler after the timers fired.
+/// `rbp` has to be the frame pointer of the interrupt handler.
+pub fn tick(stack_frame: &InterruptStackFrame, rbp: u64) {
+    if !is_running() {
+        return;
+    }
+    let core = match current_core() {
+        Some(core) if core.due.swap(false, Ordering::Relaxed) => core,
+        _ => return,
+    };
+
+    let mut sample = StackSample::default();
+    sample.frames[0] = stack_frame.instruction_pointer.as_u64();
+    sample.len = 1;
+    // The handler saved the frame pointer of the interrupted function
+    let interrupted = unsafe { (rbp as *const u64).read() };
+    backtrace::walk(interrupted, |addr| {
+        if (sample.len as usize) < MAX_DEPTH {
+            sample.frames[sample.len as usize] = addr;
+            sample.len += 1;
+        }
+    });
+    unsafe { core.ring.push(sample) };
+}
+
+/// Returns the samples of a core, None while the profiler runs
+pub fn samples(core_index: usize) -> Option<Vec<StackSample>> {
+    let _control = CONTROL.lock();
+    if is_running() {
+        return None;
+    }
+    unsafe {
+        Some(
+            CORES
+                .get_mut(core_index)
+                .map_or_else(Vec::new, |core| core.ring.iter().copied().collect()),
+        )
+    }
+}
+
+/// Name of the function containing `addr`, its address if unknown
+fn symbol_name(addr: u64) -> String {
+    let mut name = String::new();
+    let _ = match backtrace::resolve(addr) {
+        Some((symbol, _)) => write!(name, "{}", Demangle(symbol)),
+        None => write!(name, "{:#x}", addr),
+    };
+    name
+}
+
+/// Counts the samples of all cores per call chain and returns the
+/// folded stacks with their counts, None while the profiler runs
+pub fn fold() -> Option<BTreeMap<String, usize>> {
+    let _control = CONTROL.lock();
+    if is_running() {
+        return None;
+    }
+
+    // Merge identical chains first, so every address gets resolved once
+    let mut chains: BTreeMap<Vec<u64>, usize> = BTreeMap::new();
+    unsafe {
+        for core in CORES.iter_mut() {
+            for sample in core.ring.iter() {
+                // Return addresses point behind the call
+                let chain = sample
+                    .frames()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, addr)| if i == 0 { *addr } else { addr - 1 })
+                    .collect();
+                *chains.entry(chain).or_insert(0) += 1;
+            }
+        }
+    }
+
+    let mut names: BTreeMap<u64, String> = BTreeMap::new();
+    let mut folded = BTreeMap::new();
+    for (chain, count) in chains {
+        let mut line = String::new();
+        for addr in chain.iter().rev() {
+            let name = names.entry(*addr).or_insert_with(|| symbol_name(*addr));
+            if !line.is_empty() {
+                line.push(';');
+            }
+            line.push_str(name);
+        }
+        *folded.entry(line).or_insert(0) += count;
+    }
+    Some(folded)
+}
+
+/// Prints the folded stacks of all cores between two marker lines.
+/// Returns false while the profiler runs.
+pub fn dump_folded() -> bool {
+    let folded = match fold() {
+        Some(folded) => folded,
+        None => return false,
+    };
+    println!("==== folded stacks begin ====");
+    for (stack, count) in folded {
+        println!("{} {}", stack, count);
+    }
+    println!("==== folded stacks end ====");
+    true
+}
+
+/// Writes the samples of all cores as binary records to COM1.
+/// Returns false while the profiler runs.
+pub fn dump() -> bool {
+    let _control = CONTROL.lock();
+    if is_running() {
+        return false;
+    }
+    unsafe {
+        for (index, core) in CORES.iter_mut().enumerate() {
+            if core.ring.lost() > 0 {
+                log::warn!(
+                    "Sampler core {} overwrote {} samples",
+                    index,
+                    core.ring.lost()
+                );
+            }
+            if !core.ring.is_empty() {
+                stream::write_record(index, core.ring.iter());
+            }
+        }
+    }
+    true
+}
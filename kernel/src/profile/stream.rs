@@ -0,0 +1,93 @@
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/*
+ * Binary sample stream
+ * Samples leave the kernel through COM1, in between the log lines.
+ * Every record starts with a magic whose first byte never shows up in
+ * UTF-8 text, so a reader can skip everything else. All integers are
+ * little endian:
+ *
+ *   magic        4 bytes  0xff 'P' 'K' 'S'
+ *   version      u8       VERSION
+ *   kind         u8       Kind
+ *   core         u16      core index that took the samples
+ *   count        u32      number of samples
+ *   sample_size  u16      bytes per sample, zero if the size varies
+ *   reserved     u16
+ *   samples      count samples
+ *   checksum     u32      wrapping sum of all sample bytes
+ *
+ * Samples of varying size are each preceded by their size as u16,
+ * which counts towards the checksum.
+ *
+ * The layout of a sample depends on the kind and is described
+ * at its StreamSample implementation.
+ */
+
+pub const MAGIC: [u8; 4] = [0xff, b'P', b'K', b'S'];
+pub const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Kind {
+    IbsOp = 1,
+    IbsFetch = 2,
+    Stack = 3,
+}
+
+/// A sample with a binary encoding
+pub trait StreamSample {
+    const KIND: Kind;
+    /// Size of every sample, zero if it varies
+    const SIZE: usize;
+
+    /// Appends the sample to `out`, exactly SIZE bytes if SIZE is not zero
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+/// Encodes `samples` of one core into a record
+pub fn encode_record<'a, S, I>(core: usize, samples: I) -> Vec<u8>
+where
+    S: StreamSample + 'a,
+    I: ExactSizeIterator<Item = &'a S>,
+{
+    let count = samples.len();
+    let mut out = Vec::with_capacity(HEADER_SIZE + count * S::SIZE + 4);
+    out.extend_from_slice(&MAGIC);
+    out.push(VERSION);
+    out.push(S::KIND as u8);
+    out.extend_from_slice(&u16::try_from(core).unwrap().to_le_bytes());
+    out.extend_from_slice(&u32::try_from(count).unwrap().to_le_bytes());
+    out.extend_from_slice(&u16::try_from(S::SIZE).unwrap().to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+
+    for sample in samples {
+        if S::SIZE != 0 {
+            sample.encode(&mut out);
+            continue;
+        }
+        let start = out.len();
+        out.extend_from_slice(&[0; 2]);
+        sample.encode(&mut out);
+        let size = u16::try_from(out.len() - start - 2).unwrap();
+        out[start..start + 2].copy_from_slice(&size.to_le_bytes());
+    }
+    debug_assert!(S::SIZE == 0 || out.len() == HEADER_SIZE + count * S::SIZE);
+
+    let checksum = out[HEADER_SIZE..]
+        .iter()
+        .fold(0u32, |sum, byte| sum.wrapping_add(*byte as u32));
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+/// Writes a record with `samples` of one core to COM1
+pub fn write_record<'a, S, I>(core: usize, samples: I)
+where
+    S: StreamSample + 'a,
+    I: ExactSizeIterator<Item = &'a S>,
+{
+    crate::serial::write_bytes(&encode_record(core, samples));
+}
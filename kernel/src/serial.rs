@@ -3,10 +3,13 @@ use uart_16550::SerialPort;
 // Serial programming resource:
 // https://en.wikibooks.org/wiki/Serial_Programming/8250_UART_Programming
 
+/// Io port of COM1
+const COM1: u16 = 0x3F8;
+
 pub static mut SERIAL_WRITER: Option<spin::Mutex<SerialPort>> = None;
 
 pub unsafe fn init() {
-    let mut serial_port = SerialPort::new(0x3F8);
+    let mut serial_port = SerialPort::new(COM1);
     serial_port.init();
     SERIAL_WRITER = Some(spin::Mutex::new(serial_port));
 }
@@ -26,3 +29,34 @@ pub fn _print(args: fmt::Arguments) {
             .unwrap();
     });
 }
+
+/// Writes binary data to COM1 in one piece, log lines of
+/// other cores can only show up before or after it.
+pub fn write_bytes(bytes: &[u8]) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| unsafe {
+        let mut port = SERIAL_WRITER.as_ref().unwrap().lock();
+        for byte in bytes {
+            port.send_raw(*byte);
+        }
+    });
+}
+
+/// Writes to COM1 without taking the SERIAL_WRITER lock, which
+/// may be held by a core that has been stopped.
+/// Output can interleave with other writers, only use it to panic.
+pub struct RawWriter(SerialPort);
+
+impl RawWriter {
+    /// Expects COM1 to be initialized by serial::init
+    pub unsafe fn new() -> Self {
+        RawWriter(SerialPort::new(COM1))
+    }
+}
+
+impl fmt::Write for RawWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        fmt::Write::write_str(&mut self.0, s)
+    }
+}
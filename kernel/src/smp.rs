@@ -1,10 +1,32 @@
-use crate::apic;
+use crate::acpi::Acpi;
+use crate::percpu;
+use crate::time::{Duration, Instant};
+use alloc::vec::Vec;
 use core::mem::MaybeUninit;
 use core::sync::atomic::AtomicU8;
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
+/*
+ * Application processor bring-up
+ * The bsp launches the cores listed in the MADT one after another.
+ * Every core is marked Launched before it gets the INIT/SIPI sequence
+ * and checks in by setting itself Online at the end of its init.
+ * If a core does not check in within CHECK_IN_TIMEOUT the sequence is
+ * repeated, after LAUNCH_ATTEMPTS the core is given up and set Offline.
+ * States are indexed by the core index of BootInfo.cores, apic ids
+ * can be larger than the number of cores.
+ */
 static mut CORES: Option<[MaybeUninit<AtomicU8>; bootloader::MAX_CORES]> = None;
-static mut NUM_CORES_ONLINE: AtomicU8 = AtomicU8::new(0);
+static NUM_CORES_ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// Set by the bsp once every core has been launched or given up
+static BOOT_DONE: AtomicBool = AtomicBool::new(false);
+
+/// Time a launched core has to check in
+const CHECK_IN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Number of INIT/SIPI sequences sent to a core before giving up
+const LAUNCH_ATTEMPTS: usize = 3;
 
 /// Different states for APICs to be in
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -51,41 +73,167 @@ pub unsafe fn init() {
 }
 
 pub fn set_core_ready() {
-    let id = apic::apic_id();
-    set_state(id as usize, ApicState::Online);
-    unsafe {
-        NUM_CORES_ONLINE.fetch_add(1, Ordering::SeqCst);
+    let index = percpu!(core_index);
+    if !crate::apic::is_bsp() && get_state_by_index(index) != ApicState::Launched {
+        log::warn!("Core {} checked in after it has been given up", percpu!(apic_id));
+    }
+    set_state(index, ApicState::Online);
+    NUM_CORES_ONLINE.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Marks the current core as halted, it has to stop right after.
+/// Only takes atomics, the NMI handler calls it while another core panics.
+pub fn set_core_halted() {
+    if !crate::percpu::is_initialized() || unsafe { CORES.is_none() } {
+        return;
+    }
+    let index = percpu!(core_index);
+    if get_state_by_index(index) == ApicState::Online {
+        NUM_CORES_ONLINE.fetch_sub(1, Ordering::SeqCst);
+    }
+    set_state(index, ApicState::Halted);
+}
+
+/// Returns true if no other core than the current one is still running
+pub fn others_halted() -> bool {
+    if !crate::percpu::is_initialized() || unsafe { CORES.is_none() } {
+        return true;
+    }
+    let own = percpu!(core_index);
+    (0..percpu::num_cores())
+        .filter(|i| *i != own)
+        .all(|i| get_state_by_index(i) != ApicState::Online)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LaunchError {
+    /// The core did not check in after all attempts
+    Timeout,
+    /// The bootloader did not set up a stack for this apic id
+    UnknownCore,
+}
+
+/// Sends the INIT/SIPI sequence to a core and waits until it checked in.
+/// Retries LAUNCH_ATTEMPTS times before the core is set Offline.
+pub unsafe fn launch(apic_id: u32, trampoline: u32) -> Result<(), LaunchError> {
+    let index = percpu::core_index_of(apic_id).ok_or(LaunchError::UnknownCore)?;
+    for attempt in 1..=LAUNCH_ATTEMPTS {
+        set_state(index, ApicState::Launched);
+        crate::apic::mp_init(apic_id, trampoline);
+
+        let deadline = Instant::after(CHECK_IN_TIMEOUT);
+        while !deadline.has_passed() {
+            if get_state_by_index(index) == ApicState::Online {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        log::warn!(
+            "Core {} did not check in within {:?} (attempt {}/{})",
+            apic_id,
+            CHECK_IN_TIMEOUT,
+            attempt,
+            LAUNCH_ATTEMPTS
+        );
+    }
+
+    set_state(index, ApicState::Offline);
+    Err(LaunchError::Timeout)
+}
+
+/// Launches every application processor listed in the MADT.
+/// Has to be called by the bsp. Returns the apic ids of cores that failed.
+pub unsafe fn boot_aps(acpi: &Acpi, trampoline: u32) -> Vec<u32> {
+    let own_id = percpu!(apic_id);
+    let mut failed = Vec::new();
+
+    for lapic in acpi.apics.as_ref().unwrap().iter() {
+        let apic_id = lapic.id;
+        if apic_id == own_id {
+            continue;
+        }
+        if let Err(err) = launch(apic_id, trampoline) {
+            log::error!("Failed to boot core {}: {:?}", apic_id, err);
+            failed.push(apic_id);
+        }
     }
+
+    log::info!(
+        "{} cores online, {} failed to boot",
+        num_cores_online(),
+        failed.len()
+    );
+    BOOT_DONE.store(true, Ordering::SeqCst);
+    failed
 }
 
-// pub fn are_all_cores_online(acpi: &Acpi) -> bool {
-//      unsafe {
-//         if NUM_CORES_ONLINE.load(Ordering::SeqCst) != ;
-//     }
-// }
+/// Returns true if every core listed in the MADT is online
+pub fn are_all_cores_online(acpi: &Acpi) -> bool {
+    acpi.apics
+        .as_ref()
+        .unwrap()
+        .iter()
+        .all(|lapic| get_state(lapic.id) == ApicState::Online)
+}
 
-fn set_state(id: usize, state: ApicState) {
+/// Barrier that returns once the bsp is done booting and
+/// every launched core is online.
+/// Cores that failed to boot are not waited for.
+pub fn wait_for_all_cores() {
+    loop {
+        if BOOT_DONE.load(Ordering::SeqCst)
+            && (0..bootloader::MAX_CORES).all(|i| get_state_by_index(i) != ApicState::Launched)
+        {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Returns the number of cores that checked in with the kernel
+pub fn num_cores_online() -> usize {
+    NUM_CORES_ONLINE.load(Ordering::SeqCst)
+}
+
+fn set_state(index: usize, state: ApicState) {
     unsafe {
         CORES
             .as_mut()
             .unwrap()
-            .get_mut(id)
+            .get_mut(index)
             .unwrap()
             .assume_init_mut()
             .store(state as u8, Ordering::SeqCst);
     }
 }
 
-pub fn get_state(id: usize) -> ApicState {
+/// Returns the state of the core with the given apic id
+pub fn get_state(apic_id: u32) -> ApicState {
+    match percpu::core_index_of(apic_id) {
+        Some(index) => get_state_by_index(index),
+        None => ApicState::None,
+    }
+}
+
+/// Returns the state of the core with the given core index
+pub fn get_state_by_index(index: usize) -> ApicState {
     unsafe {
         ApicState::from(
             CORES
                 .as_ref()
                 .unwrap()
-                .get(id)
+                .get(index)
                 .unwrap()
                 .assume_init_ref()
                 .load(Ordering::SeqCst),
         )
     }
 }
+
+/// Returns the apic ids of all cores that are online
+pub fn online_cores() -> Vec<u32> {
+    (0..percpu::num_cores())
+        .filter(|i| get_state_by_index(*i) == ApicState::Online)
+        .filter_map(percpu::apic_id_of)
+        .collect()
+}
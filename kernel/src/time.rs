@@ -1,12 +1,29 @@
-use core::arch::x86_64::_rdtsc;
+use crate::hpet;
+use core::arch::x86_64::{__rdtscp, _mm_lfence, _rdtsc};
+use core::convert::TryFrom;
+use core::ops::{Add, AddAssign, Sub};
 use core::sync::atomic::{AtomicU64, Ordering};
 use x86_64::instructions::port::Port;
 
-/// The TSC tick rate in MHz
+pub use core::time::Duration;
+
+/// The TSC tick rate in Hz
 /// We "default" to a 3 GHz tick rate, which is likely within a ballpark of
 /// actual tick rates if you happen to use the time routines prior to
 /// calibrating the TSC.
-static RDTSC_MHZ: AtomicU64 = AtomicU64::new(3_000);
+static RDTSC_HZ: AtomicU64 = AtomicU64::new(3_000_000_000);
+
+/// Fixed point shift of the cycle <-> nanosecond multipliers
+const MULT_SHIFT: u32 = 32;
+
+/// Nanoseconds per TSC cycle as 32.32 fixed point value
+static NS_PER_CYCLE: AtomicU64 = AtomicU64::new(TscScale::new(3_000_000_000).ns_per_cycle);
+
+/// TSC cycles per nanosecond as 32.32 fixed point value
+static CYCLES_PER_NS: AtomicU64 = AtomicU64::new(TscScale::new(3_000_000_000).cycles_per_ns);
+
+/// Length of the HPET measurement window used to calibrate the TSC
+const HPET_CALIBRATION_NS: u64 = 50_000_000;
 
 /// TSC at the time of boot of the system
 static RDTSC_START: AtomicU64 = AtomicU64::new(0);
@@ -14,32 +31,201 @@ static RDTSC_START: AtomicU64 = AtomicU64::new(0);
 /// Get the TSC rate in MHz
 #[inline]
 pub fn tsc_mhz() -> u64 {
-    RDTSC_MHZ.load(Ordering::Relaxed)
+    tsc_hz() / 1_000_000
+}
+
+/// Get the TSC rate in Hz
+#[inline]
+pub fn tsc_hz() -> u64 {
+    RDTSC_HZ.load(Ordering::Relaxed)
+}
+
+/// Fixed point multipliers converting between cycles and nanoseconds
+/// of a TSC running at a given rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TscScale {
+    ns_per_cycle: u64,
+    cycles_per_ns: u64,
+}
+
+impl TscScale {
+    pub const fn new(hz: u64) -> Self {
+        TscScale {
+            ns_per_cycle: ((1_000_000_000u128 << MULT_SHIFT) / hz as u128) as u64,
+            cycles_per_ns: (((hz as u128) << MULT_SHIFT) / 1_000_000_000) as u64,
+        }
+    }
+
+    /// Scale of the calibrated TSC
+    #[inline]
+    pub fn current() -> Self {
+        TscScale {
+            ns_per_cycle: NS_PER_CYCLE.load(Ordering::Relaxed),
+            cycles_per_ns: CYCLES_PER_NS.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Converts TSC cycles into nanoseconds, saturates at u64::MAX
+    #[inline]
+    pub fn cycles_to_ns(&self, cycles: u64) -> u64 {
+        let ns = (cycles as u128 * self.ns_per_cycle as u128) >> MULT_SHIFT;
+        u64::try_from(ns).unwrap_or(u64::MAX)
+    }
+
+    /// Converts nanoseconds into TSC cycles, saturates at u64::MAX
+    #[inline]
+    pub fn ns_to_cycles(&self, ns: u64) -> u64 {
+        let cycles = (ns as u128 * self.cycles_per_ns as u128) >> MULT_SHIFT;
+        u64::try_from(cycles).unwrap_or(u64::MAX)
+    }
+
+    /// Converts a duration into TSC cycles, saturates at u64::MAX
+    #[inline]
+    pub fn duration_to_cycles(&self, duration: Duration) -> u64 {
+        match u64::try_from(duration.as_nanos()) {
+            Ok(ns) => self.ns_to_cycles(ns),
+            Err(_) => u64::MAX,
+        }
+    }
+}
+
+/// Stores the TSC rate and derives the fixed point multipliers from it
+fn set_tsc_hz(hz: u64) {
+    let scale = TscScale::new(hz);
+    NS_PER_CYCLE.store(scale.ns_per_cycle, Ordering::Relaxed);
+    CYCLES_PER_NS.store(scale.cycles_per_ns, Ordering::Relaxed);
+    RDTSC_HZ.store(hz, Ordering::Relaxed);
+}
+
+/// Converts TSC cycles into nanoseconds
+#[inline]
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    TscScale::current().cycles_to_ns(cycles)
+}
+
+/// Converts nanoseconds into TSC cycles
+#[inline]
+pub fn ns_to_cycles(ns: u64) -> u64 {
+    TscScale::current().ns_to_cycles(ns)
+}
+
+/// Converts a duration into TSC cycles
+#[inline]
+pub fn duration_to_cycles(duration: Duration) -> u64 {
+    TscScale::current().duration_to_cycles(duration)
+}
+
+/// A point in time measured by the TSC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Returns the current time, ordered with rdtscp
+    #[inline]
+    pub fn now() -> Self {
+        Instant(rdtscp())
+    }
+
+    /// Returns the point in time `duration` from now
+    #[inline]
+    pub fn after(duration: Duration) -> Self {
+        Instant::now() + duration
+    }
+
+    #[inline]
+    pub const fn from_cycles(cycles: u64) -> Self {
+        Instant(cycles)
+    }
+
+    /// Raw TSC value of this instant
+    #[inline]
+    pub const fn as_cycles(&self) -> u64 {
+        self.0
+    }
+
+    /// Cycles elapsed since a prior instant, zero if `earlier` is later
+    #[inline]
+    pub fn cycles_since(&self, earlier: Instant) -> u64 {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    /// Duration elapsed since a prior instant, zero if `earlier` is later
+    #[inline]
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(cycles_to_ns(self.cycles_since(earlier)))
+    }
+
+    /// Duration elapsed since this instant
+    #[inline]
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Returns true if this instant used as a deadline has passed
+    #[inline]
+    pub fn has_passed(&self) -> bool {
+        rdtsc() >= self.0
+    }
+
+    /// Duration left until this deadline, zero if it has passed
+    #[inline]
+    pub fn remaining(&self) -> Duration {
+        self.duration_since(Instant::now())
+    }
+
+    #[inline]
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let ns = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(ns_to_cycles(ns)).map(Instant)
+    }
 }
 
-/// Returns the TSC value upon a future time in microseconds
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Returns the point in time `duration` from now
 #[inline]
-pub fn future(microseconds: u64) -> u64 {
-    rdtsc() + (microseconds * tsc_mhz())
+pub fn future(duration: Duration) -> Instant {
+    Instant::after(duration)
 }
 
-/// Returns system uptime in seconds as a float
+/// Returns system uptime
 #[inline]
-pub fn uptime() -> f64 {
-    elapsed(RDTSC_START.load(Ordering::Relaxed))
+pub fn uptime() -> Duration {
+    Instant::now().duration_since(Instant(RDTSC_START.load(Ordering::Relaxed)))
 }
 
-/// Return number of seconds elapsed since a prior TSC value
+/// Return the duration elapsed since a prior instant
 #[inline]
-pub fn elapsed(start_time: u64) -> f64 {
-    (rdtsc() - start_time) as f64 / RDTSC_MHZ.load(Ordering::Relaxed) as f64 / 1_000_000.0
+pub fn elapsed(start_time: Instant) -> Duration {
+    start_time.elapsed()
 }
 
-/// Busy sleep for a given number of microseconds
+/// Busy sleep for the given duration
 #[inline]
-pub fn sleep(microseconds: u64) {
-    let waitval = future(microseconds);
-    while rdtsc() < waitval {
+pub fn sleep(duration: Duration) {
+    let deadline = future(duration);
+    while !deadline.has_passed() {
         core::hint::spin_loop();
     }
 }
@@ -51,8 +237,20 @@ pub fn rdtsc() -> u64 {
     unsafe { _rdtsc() }
 }
 
-/// Using the PIT, determine the frequency of rdtsc. Round this frequency to
-/// the nearest 100MHz and return it.
+/// Reads the TSC after all prior instructions have completed.
+/// The lfence keeps later instructions from starting before the read.
+#[inline]
+pub fn rdtscp() -> u64 {
+    let mut aux: u32 = 0;
+    unsafe {
+        let tsc = __rdtscp(&mut aux as *mut u32);
+        _mm_lfence();
+        tsc
+    }
+}
+
+/// Determine the frequency of rdtsc with the HPET.
+/// Falls back to the PIT if the machine has no HPET.
 pub unsafe fn calibrate() {
     // Store off the current rdtsc value
     let start = rdtsc();
@@ -63,6 +261,34 @@ pub unsafe fn calibrate() {
 
     RDTSC_START.store(start, Ordering::Relaxed);
 
+    if hpet::is_available() {
+        calibrate_hpet();
+    } else {
+        calibrate_pit();
+    }
+    log::info!("TSC runs at {} Hz", tsc_hz());
+}
+
+/// Measure the TSC ticks elapsed in a fixed HPET window
+unsafe fn calibrate_hpet() {
+    let hpet_start = hpet::counter();
+    let start = rdtsc();
+    while hpet::elapsed_ns(hpet_start) < HPET_CALIBRATION_NS {
+        core::hint::spin_loop();
+    }
+    let end = rdtsc();
+    let elapsed_ns = hpet::elapsed_ns(hpet_start);
+
+    // Compute Hz for the rdtsc, no rounding needed
+    let rate = ((end - start) as u128 * 1_000_000_000) / elapsed_ns as u128;
+    set_tsc_hz(rate as u64);
+}
+
+/// Using the PIT, determine the frequency of rdtsc. Round this frequency to
+/// the nearest 100MHz.
+unsafe fn calibrate_pit() {
+    let start = rdtsc();
+
     // Start a timer
     let mut c0_data: Port<u8> = Port::new(0x40);
     let mut command: Port<u8> = Port::new(0x43);
@@ -102,5 +328,5 @@ pub unsafe fn calibrate() {
     let rounded_rate = (((computed_rate / 100.0) + 0.5) as u64) * 100;
 
     // Stock the TSC rate
-    RDTSC_MHZ.store(rounded_rate, Ordering::Relaxed);
+    set_tsc_hz(rounded_rate * 1_000_000);
 }
@@ -0,0 +1,254 @@
+use crate::apic::{self, TimerMode};
+use crate::percpu;
+use crate::time::{self, Duration, Instant};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use x86_64::instructions::interrupts;
+
+/*
+ * Per core timer subsystem
+ * Every core keeps its pending deadlines in a hashed timer wheel inside
+ * its per cpu block. The local apic timer is programmed for the earliest
+ * deadline only, either in TSC-deadline mode if cpuid reports it
+ * or in one-shot mode. Internally all deadlines are absolute TSC values.
+ */
+
+/// Number of slots in the timer wheel
+const WHEEL_SLOTS: usize = 256;
+
+/// Width of a wheel slot in nanoseconds
+const SLOT_NS: u64 = 1_000_000;
+
+/// What happens when a timer expires.
+/// Runs in interrupt context with interrupts disabled.
+pub enum TimerAction {
+    Callback(Box<dyn FnOnce() + Send>),
+    Wake(Waker),
+}
+
+impl TimerAction {
+    pub fn callback(f: impl FnOnce() + Send + 'static) -> Self {
+        TimerAction::Callback(Box::new(f))
+    }
+
+    fn fire(self) {
+        match self {
+            TimerAction::Callback(f) => f(),
+            TimerAction::Wake(waker) => waker.wake(),
+        }
+    }
+}
+
+struct Timer {
+    deadline: u64,
+    action: TimerAction,
+}
+
+pub struct TimerWheel {
+    slots: Vec<Vec<Timer>>,
+    /// Width of a slot in TSC ticks
+    granularity: u64,
+    /// Slot tick up to which all timers have been processed
+    current: u64,
+    /// Deadline the apic timer is currently programmed for
+    armed: Option<u64>,
+    len: usize,
+}
+
+impl TimerWheel {
+    pub const fn new() -> Self {
+        TimerWheel {
+            slots: Vec::new(),
+            granularity: 1,
+            current: 0,
+            armed: None,
+            len: 0,
+        }
+    }
+
+    fn init(&mut self) {
+        if !self.slots.is_empty() {
+            return;
+        }
+        self.slots.resize_with(WHEEL_SLOTS, Vec::new);
+        self.granularity = core::cmp::max(time::ns_to_cycles(SLOT_NS), 1);
+        self.current = time::rdtsc() / self.granularity;
+    }
+
+    fn tick_of(&self, deadline: u64) -> u64 {
+        deadline / self.granularity
+    }
+
+    fn insert(&mut self, timer: Timer) {
+        self.init();
+        // Deadlines that already passed go into the current slot,
+        // pop_expired only scans from there on
+        let tick = core::cmp::max(self.tick_of(timer.deadline), self.current);
+        let slot = (tick % WHEEL_SLOTS as u64) as usize;
+        self.slots[slot].push(timer);
+        self.len += 1;
+    }
+
+    /// Removes one timer whose deadline has passed
+    fn pop_expired(&mut self, now: u64) -> Option<Timer> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let now_tick = self.tick_of(now);
+        // Deadlines in the past can only sit in slots between current and now
+        let ticks = core::cmp::min(
+            now_tick.saturating_sub(self.current) + 1,
+            WHEEL_SLOTS as u64,
+        );
+        for tick in self.current..self.current + ticks {
+            let slot = &mut self.slots[(tick % WHEEL_SLOTS as u64) as usize];
+            if let Some(i) = slot.iter().position(|t| t.deadline <= now) {
+                self.len -= 1;
+                return Some(slot.swap_remove(i));
+            }
+        }
+        self.current = core::cmp::max(self.current, now_tick);
+        None
+    }
+
+    /// Returns the earliest pending deadline
+    fn next_deadline(&self) -> Option<u64> {
+        if self.len == 0 {
+            return None;
+        }
+
+        // Walk one rotation, the first slot holding a timer of
+        // its own rotation contains the earliest deadline
+        for tick in self.current..self.current + WHEEL_SLOTS as u64 {
+            let slot = &self.slots[(tick % WHEEL_SLOTS as u64) as usize];
+            let next = slot
+                .iter()
+                .map(|t| t.deadline)
+                .filter(|d| self.tick_of(*d) <= tick)
+                .min();
+            if next.is_some() {
+                return next;
+            }
+        }
+
+        // Only timers more than one rotation ahead are left
+        self.slots.iter().flatten().map(|t| t.deadline).min()
+    }
+
+    /// Programs the apic timer for the earliest deadline or disarms it
+    unsafe fn rearm(&mut self) {
+        self.armed = self.next_deadline();
+        match self.armed {
+            Some(deadline) => program(deadline),
+            None => disarm(),
+        }
+    }
+}
+
+/// Returns the timer mode used on this machine
+pub fn mode() -> TimerMode {
+    if apic::has_tsc_deadline() {
+        TimerMode::TscDeadline
+    } else {
+        TimerMode::OneShot
+    }
+}
+
+/// Puts the apic timer of the current core into one-shot or TSC-deadline mode.
+/// The apic timer has to be calibrated before.
+pub unsafe fn init() {
+    let mode = mode();
+    apic::set_timer_mode(mode);
+    disarm();
+    if apic::is_bsp() {
+        log::info!("Apic timer uses {:?} mode", mode);
+    }
+}
+
+unsafe fn program(deadline: u64) {
+    match mode() {
+        TimerMode::TscDeadline => apic::set_tsc_deadline(core::cmp::max(deadline, 1)),
+        _ => {
+            let cycles = deadline.saturating_sub(time::rdtsc());
+            let ticks = (cycles as u128 * apic::timer_hz() as u128) / time::tsc_hz() as u128;
+            // Too long deadlines fire early and get rearmed
+            let ticks = ticks.clamp(1, u32::MAX as u128) as u32;
+            apic::set_timer_count(ticks);
+        }
+    }
+}
+
+unsafe fn disarm() {
+    match mode() {
+        TimerMode::TscDeadline => apic::set_tsc_deadline(0),
+        _ => apic::set_timer_count(0),
+    }
+}
+
+/// Runs `action` on the current core once `deadline` has passed
+pub fn set_timeout(deadline: Instant, action: TimerAction) {
+    let deadline = deadline.as_cycles();
+    interrupts::without_interrupts(|| {
+        let mut wheel = percpu!().timer_wheel.lock();
+        wheel.insert(Timer { deadline, action });
+        if wheel.armed.map_or(true, |armed| deadline < armed) {
+            wheel.armed = Some(deadline);
+            unsafe { program(deadline) };
+        }
+    });
+}
+
+/// Called by the apic timer interrupt handler
+pub fn handle_interrupt() {
+    let now = time::rdtsc();
+    let wheel = &percpu!().timer_wheel;
+
+    // Release the lock before firing, so that actions can add new timers
+    loop {
+        let timer = wheel.lock().pop_expired(now);
+        match timer {
+            Some(timer) => timer.action.fire(),
+            None => break,
+        }
+    }
+
+    unsafe { wheel.lock().rearm() };
+}
+
+/// Future that completes once the deadline has passed
+pub struct Sleep {
+    deadline: Instant,
+    registered: bool,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.deadline.has_passed() {
+            return Poll::Ready(());
+        }
+        if !self.registered {
+            self.registered = true;
+            set_timeout(self.deadline, TimerAction::Wake(cx.waker().clone()));
+        }
+        Poll::Pending
+    }
+}
+
+/// Asynchronously sleeps until `deadline` has passed
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        registered: false,
+    }
+}
+
+/// Asynchronously sleeps for the given duration
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(time::future(duration))
+}
@@ -0,0 +1,188 @@
+use crate::ipi;
+use crate::percpu;
+use crate::smp;
+use alloc::vec::Vec;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::structures::paging::page::PageSize;
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
+
+/*
+ * TLB shootdown
+ * All cores share the page table set up by memory::init, but every core
+ * caches translations in its own TLB. After unmapping a page or changing
+ * its flags the stale entries have to be invalidated on every core.
+ * Changes are collected in a TlbFlushBatch. Finishing the batch flushes
+ * the local TLB, sends a call IPI to all other online cores and waits
+ * until every one of them acknowledged the invalidation.
+ * The batch does not allocate, thus it can be used before the heap exists.
+ * A core waiting for the acknowledgment keeps serving calls, but a target
+ * core spinning with interrupts disabled on a lock held by the
+ * initiator never acknowledges.
+ */
+
+/// Number of distinct ranges a batch keeps before it falls back to a full flush
+const MAX_RANGES: usize = 16;
+
+/// Number of pages above which a full flush is cheaper than invlpg
+const FULL_FLUSH_THRESHOLD: u64 = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct Range {
+    start: u64,
+    num_pages: u64,
+    page_size: u64,
+}
+
+impl Range {
+    const fn empty() -> Self {
+        Range {
+            start: 0,
+            num_pages: 0,
+            page_size: 0,
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.num_pages * self.page_size
+    }
+}
+
+/// Collects invalidations that get flushed on all cores at once
+#[derive(Debug, Clone, Copy)]
+pub struct TlbFlushBatch {
+    ranges: [Range; MAX_RANGES],
+    len: usize,
+    num_pages: u64,
+    full: bool,
+}
+
+impl Default for TlbFlushBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TlbFlushBatch {
+    pub const fn new() -> Self {
+        TlbFlushBatch {
+            ranges: [Range::empty(); MAX_RANGES],
+            len: 0,
+            num_pages: 0,
+            full: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.full && self.len == 0
+    }
+
+    /// Adds a single page of any size to the batch
+    pub fn add_page<T: PageSize>(&mut self, page: Page<T>) {
+        self.add_range(page.start_address(), 1, T::SIZE);
+    }
+
+    /// Adds `num_pages` consecutive pages of size `page_size` starting at `start`
+    pub fn add_range(&mut self, start: VirtAddr, num_pages: u64, page_size: u64) {
+        if self.full || num_pages == 0 {
+            return;
+        }
+
+        self.num_pages += num_pages;
+        if self.num_pages > FULL_FLUSH_THRESHOLD {
+            self.full = true;
+            return;
+        }
+
+        let start = start.align_down(page_size).as_u64();
+        // Extend the previous range if the new one directly follows it
+        if let Some(last) = self.ranges[..self.len].last_mut() {
+            if last.page_size == page_size && last.end() == start {
+                last.num_pages += num_pages;
+                return;
+            }
+        }
+
+        if self.len == MAX_RANGES {
+            self.full = true;
+            return;
+        }
+        self.ranges[self.len] = Range {
+            start,
+            num_pages,
+            page_size,
+        };
+        self.len += 1;
+    }
+
+    /// Invalidates the whole TLB instead of single pages
+    pub fn add_all(&mut self) {
+        self.full = true;
+    }
+
+    /// Invalidates the collected entries in the TLB of the current core
+    pub fn flush_local(&self) {
+        if self.full {
+            flush_all_local();
+            return;
+        }
+        for range in self.ranges[..self.len].iter() {
+            for i in 0..range.num_pages {
+                x86_64::instructions::tlb::flush(VirtAddr::new(range.start + i * range.page_size));
+            }
+        }
+    }
+
+    /// Invalidates the collected entries on every online core
+    /// and waits until all of them are done
+    pub fn shootdown(self) {
+        if self.is_empty() {
+            return;
+        }
+        self.flush_local();
+
+        // Without other cores there is nobody to notify.
+        // This also avoids touching the heap during early boot
+        if smp::num_cores_online() <= 1 {
+            return;
+        }
+
+        let own_id = percpu!(apic_id);
+        let targets: Vec<u32> = smp::online_cores()
+            .into_iter()
+            .filter(|id| *id != own_id)
+            .collect();
+        if targets.is_empty() {
+            return;
+        }
+        ipi::call_on_many(&targets, move || self.flush_local());
+    }
+}
+
+/// Invalidates the whole TLB of the current core, including global pages
+pub fn flush_all_local() {
+    let cr4 = Cr4::read();
+    if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+        // Toggling PGE drops global entries as well
+        unsafe {
+            Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(cr4);
+        }
+    } else {
+        x86_64::instructions::tlb::flush_all();
+    }
+}
+
+/// Invalidates a single page on every online core
+pub fn shootdown_page<T: PageSize>(page: Page<T>) {
+    let mut batch = TlbFlushBatch::new();
+    batch.add_page(page);
+    batch.shootdown();
+}
+
+/// Invalidates the whole TLB on every online core
+pub fn shootdown_all() {
+    let mut batch = TlbFlushBatch::new();
+    batch.add_all();
+    batch.shootdown();
+}
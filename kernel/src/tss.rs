@@ -30,16 +30,9 @@ impl core::iter::Iterator for StackIter {
     }
 }
 
-static mut GDT_ARR: [Option<GlobalDescriptorTable>; bootloader::MAX_CORES] =
-    [None; bootloader::MAX_CORES];
-static mut TSS_ARR: [Option<TaskStateSegment>; bootloader::MAX_CORES] =
-    [None; bootloader::MAX_CORES];
-
 pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
-    let (core, core_index) = boot_info
-        .cores
-        .get_by_apic_id(crate::apic::apic_id())
-        .unwrap();
+    let percpu = crate::percpu::get_mut();
+    let core = &boot_info.cores[percpu.core_index];
 
     TSS_STACK_ITER = Some(StackIter::new(
         bootloader::TSS_STACKS_PER_CPU.try_into().unwrap(),
@@ -55,19 +48,12 @@ pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
         }
     }
 
-    TSS_ARR[core_index] = Some(tss);
-    GDT_ARR[core_index] = Some(GlobalDescriptorTable::new());
-    let code_selector = GDT_ARR[core_index]
-        .as_mut()
-        .unwrap()
-        .add_entry(Descriptor::kernel_code_segment());
-    let tss_selector = GDT_ARR[core_index]
-        .as_mut()
-        .unwrap()
-        .add_entry(Descriptor::tss_segment(
-            TSS_ARR[core_index].as_ref().unwrap(),
-        ));
-    GDT_ARR[core_index].as_ref().unwrap().load();
+    // The gdt and tss live in the per cpu block of this core
+    percpu.tss = tss;
+    percpu.gdt = GlobalDescriptorTable::new();
+    let code_selector = percpu.gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = percpu.gdt.add_entry(Descriptor::tss_segment(&percpu.tss));
+    percpu.gdt.load();
     CS::set_reg(code_selector);
     load_tss(tss_selector);
 }
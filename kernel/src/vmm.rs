@@ -0,0 +1,618 @@
+use crate::memory;
+use crate::memory::frame_alloc::IDENTITY_MAPPED_END;
+use crate::memory::CacheType;
+use crate::numa::NumaFrameAllocator;
+use crate::tlb::TlbFlushBatch;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use rangeset::{Range, RangeSet};
+use raw_cpuid::CpuId;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::structures::paging::page::PageSize;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size1GiB, Size2MiB,
+    Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/*
+ * Kernel virtual memory manager
+ * Hands out virtual regions from the window VMM_START..VMM_START+VMM_SIZE
+ * and maps them either to a given physical range (devices, firmware tables)
+ * or to frames allocated for the region (buffers, arenas).
+ * Every mapping uses the largest page size that fits the alignment of
+ * the virtual and the physical address, regions get aligned so that
+ * 1GiB and 2MiB pages can be used whenever the size allows it.
+ * alloc_huge maps physically contiguous memory with a single page size.
+ * Each region carries a purpose string, `dump` lists all of them.
+ * Unmapping invalidates the TLB of every core before the frames and the
+ * virtual range get reused.
+ * The heaps live outside of this window and grow on their own.
+ */
+
+/// Start of the virtual window managed by the vmm
+pub const VMM_START: u64 = 0x_6000_0000_0000;
+
+/// Size of the window, 16TiB
+pub const VMM_SIZE: u64 = 0x_1000_0000_0000;
+
+static mut VMM: Option<spin::Mutex<Vmm>> = None;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmmError {
+    /// The size is zero
+    InvalidSize,
+    /// No virtual range of the requested size is left
+    OutOfVirtualMemory,
+    /// No physical frame is left to back the region
+    OutOfPhysicalMemory,
+    /// The page table update failed, the range is mapped already
+    MapFailed(VirtAddr),
+    /// The address does not belong to any region
+    NotMapped(VirtAddr),
+    /// The cpu does not support the requested page size
+    UnsupportedPageSize(u64),
+}
+
+/// A mapped virtual region
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    /// Page aligned start of the mapping
+    pub start: VirtAddr,
+    /// Size of the mapping, a multiple of 4KiB
+    pub size: u64,
+    /// Start of the physical range if the region is physically contiguous
+    pub phys: Option<PhysAddr>,
+    pub flags: PageTableFlags,
+    /// What the region is used for, shown by `dump`
+    pub purpose: &'static str,
+    /// The frames have been allocated by the vmm and get freed on unmap
+    owned: bool,
+    /// Reserved virtual range, includes the padding in front of `start`
+    reserved: Range,
+}
+
+impl Region {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}..{:#x} ", self.start.as_u64(), self.end().as_u64())?;
+        match self.phys {
+            Some(phys) => write!(f, "-> {:#x} ", phys.as_u64())?,
+            None => write!(f, "-> scattered ")?,
+        }
+        write!(f, "{:?} {}", self.flags, self.purpose)
+    }
+}
+
+struct Vmm {
+    /// Unreserved parts of the window
+    free: RangeSet,
+    /// Mapped regions by start address
+    regions: BTreeMap<u64, Region>,
+}
+
+/// Creates the vmm. Has to be called once after the heap has been initialized.
+pub unsafe fn init() {
+    if VMM.is_some() {
+        return;
+    }
+    let mut free = RangeSet::new();
+    free.insert(Range {
+        start: VMM_START,
+        end: VMM_START + VMM_SIZE - 1,
+    });
+    VMM = Some(spin::Mutex::new(Vmm {
+        free,
+        regions: BTreeMap::new(),
+    }));
+}
+
+/// Runs `f` with the vmm locked and interrupts disabled
+fn with_vmm<R>(f: impl FnOnce(&mut Vmm) -> R) -> R {
+    let vmm = unsafe { VMM.as_ref().expect("vmm::init has not been called") };
+    interrupts::without_interrupts(|| f(&mut vmm.lock()))
+}
+
+/// Runs `f` with the page table and the frame allocator locked.
+/// `f` must not allocate on the heap, growing the heap needs both locks.
+fn with_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut NumaFrameAllocator) -> R,
+) -> R {
+    let (page_table, frame_allocator) = memory::get().expect("memory::init has not been called");
+    interrupts::without_interrupts(|| f(&mut page_table.lock(), &mut frame_allocator.lock()))
+}
+
+/// Returns true if the cpu can map 1GiB pages
+pub fn has_1gib_pages() -> bool {
+    CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |info| info.has_1gib_pages())
+}
+
+/// Returns the page sizes the cpu supports, largest first
+fn page_sizes() -> &'static [u64] {
+    if has_1gib_pages() {
+        &[Size1GiB::SIZE, Size2MiB::SIZE, Size4KiB::SIZE]
+    } else {
+        &[Size2MiB::SIZE, Size4KiB::SIZE]
+    }
+}
+
+/// Largest page size up to `max_page_size` both addresses
+/// are aligned to and that fits into `size`
+fn page_size_for(virt: u64, phys: u64, size: u64, max_page_size: u64) -> u64 {
+    *page_sizes()
+        .iter()
+        .filter(|s| **s <= max_page_size)
+        .find(|s| virt % **s == 0 && phys % **s == 0 && size >= **s)
+        .unwrap_or(&Size4KiB::SIZE)
+}
+
+/// Buddy order of a block of `size` bytes
+fn order_of_size(size: u64) -> usize {
+    (size / Size4KiB::SIZE).trailing_zeros() as usize
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Reserves `size` bytes of virtual memory, `offset` bytes behind an address
+/// aligned to the largest page size up to `max_page_size` that fits into `size`.
+/// Returns the reserved range and the address at `offset`.
+fn reserve(size: u64, offset: u64, max_page_size: u64) -> Result<(Range, u64), VmmError> {
+    let align = *page_sizes()
+        .iter()
+        .find(|s| **s <= max_page_size && size >= **s)
+        .unwrap_or(&Size4KiB::SIZE);
+    let offset = offset % align;
+    let len = offset + size;
+    let start =
+        with_vmm(|vmm| vmm.free.allocate(len, align)).ok_or(VmmError::OutOfVirtualMemory)? as u64;
+    Ok((
+        Range {
+            start,
+            end: start + len - 1,
+        },
+        start + offset,
+    ))
+}
+
+fn release(range: Range) {
+    with_vmm(|vmm| vmm.free.insert(range));
+}
+
+unsafe fn map_page<S: PageSize + fmt::Debug>(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut NumaFrameAllocator,
+    virt: u64,
+    phys: u64,
+    flags: PageTableFlags,
+) -> Result<(), VmmError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let page = Page::<S>::from_start_address(VirtAddr::new(virt)).unwrap();
+    let frame = PhysFrame::<S>::from_start_address(PhysAddr::new(phys)).unwrap();
+    // The range has not been mapped before, no other core can have it cached
+    mapper
+        .map_to(page, frame, flags, frame_allocator)
+        .map_err(|_| VmmError::MapFailed(page.start_address()))?
+        .flush();
+    Ok(())
+}
+
+unsafe fn map_any(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut NumaFrameAllocator,
+    virt: u64,
+    phys: u64,
+    page_size: u64,
+    flags: PageTableFlags,
+) -> Result<(), VmmError> {
+    match page_size {
+        Size1GiB::SIZE => map_page::<Size1GiB>(mapper, frame_allocator, virt, phys, flags),
+        Size2MiB::SIZE => map_page::<Size2MiB>(mapper, frame_allocator, virt, phys, flags),
+        _ => map_page::<Size4KiB>(mapper, frame_allocator, virt, phys, flags),
+    }
+}
+
+/// Allocates a frame of `page_size` bytes
+fn alloc_frame(frame_allocator: &mut NumaFrameAllocator, page_size: u64) -> Option<u64> {
+    let addr = match page_size {
+        Size1GiB::SIZE => {
+            FrameAllocator::<Size1GiB>::allocate_frame(frame_allocator)?.start_address()
+        }
+        Size2MiB::SIZE => {
+            FrameAllocator::<Size2MiB>::allocate_frame(frame_allocator)?.start_address()
+        }
+        _ => FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator)?.start_address(),
+    };
+    Some(addr.as_u64())
+}
+
+/// Unmaps `virt..virt+size` and collects the invalidations in `batch`.
+/// If `frames` is given, the unmapped frames get pushed into it.
+/// Returns the number of pages in the range.
+unsafe fn unmap_range(
+    mapper: &mut OffsetPageTable<'static>,
+    virt: u64,
+    size: u64,
+    batch: &mut TlbFlushBatch,
+    mut frames: Option<&mut Vec<(PhysAddr, u64)>>,
+) -> usize {
+    let mut num_pages = 0;
+    let mut offset = 0;
+    while offset < size {
+        let addr = VirtAddr::new(virt + offset);
+        let (phys, page_size) = match mapper.translate(addr) {
+            TranslateResult::Mapped { frame, .. } => match frame {
+                MappedFrame::Size4KiB(f) => (f.start_address(), f.size()),
+                MappedFrame::Size2MiB(f) => (f.start_address(), f.size()),
+                MappedFrame::Size1GiB(f) => (f.start_address(), f.size()),
+            },
+            _ => {
+                offset += Size4KiB::SIZE;
+                continue;
+            }
+        };
+        num_pages += 1;
+
+        if let Some(frames) = frames.as_mut() {
+            let res = match page_size {
+                Size1GiB::SIZE => mapper
+                    .unmap(Page::<Size1GiB>::containing_address(addr))
+                    .map(|(_, flush)| flush.ignore()),
+                Size2MiB::SIZE => mapper
+                    .unmap(Page::<Size2MiB>::containing_address(addr))
+                    .map(|(_, flush)| flush.ignore()),
+                _ => mapper
+                    .unmap(Page::<Size4KiB>::containing_address(addr))
+                    .map(|(_, flush)| flush.ignore()),
+            };
+            if let Err(err) = res {
+                log::error!("Failed to unmap {:#x}: {:?}", addr.as_u64(), err);
+            }
+            batch.add_range(addr, 1, page_size);
+            frames.push((phys, page_size));
+        }
+        offset += page_size;
+    }
+    num_pages
+}
+
+/// Removes the pages of a region from the page table, flushes them
+/// on every core and frees the frames if the region owns them
+unsafe fn teardown(start: u64, size: u64, owned: bool) {
+    // Count first, the frame list must not grow while the locks are held
+    let num_pages =
+        with_memory(|mapper, _| unmap_range(mapper, start, size, &mut TlbFlushBatch::new(), None));
+    let mut frames = Vec::with_capacity(num_pages);
+    let mut batch = TlbFlushBatch::new();
+    with_memory(|mapper, _| unmap_range(mapper, start, size, &mut batch, Some(&mut frames)));
+
+    // No core may still reach the frames when they get reused
+    batch.shootdown();
+
+    if owned {
+        with_memory(|_, frame_allocator| {
+            for (phys, page_size) in frames.iter() {
+                frame_allocator.dealloc(*phys, order_of_size(*page_size));
+            }
+        });
+    }
+}
+
+fn insert(region: Region) {
+    log::debug!("Mapped {}", region);
+    with_vmm(|vmm| vmm.regions.insert(region.start.as_u64(), region));
+}
+
+/// Maps the physical range `phys..phys+size` into a new region.
+/// Returns the virtual address of `phys`.
+pub unsafe fn map_phys(
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    purpose: &'static str,
+) -> Result<VirtAddr, VmmError> {
+    map_phys_with(phys, size, flags, Size1GiB::SIZE, purpose)
+}
+
+/// Like map_phys but uses pages of at most `max_page_size` bytes
+unsafe fn map_phys_with(
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    max_page_size: u64,
+    purpose: &'static str,
+) -> Result<VirtAddr, VmmError> {
+    if size == 0 {
+        return Err(VmmError::InvalidSize);
+    }
+    let phys_start = phys.align_down(Size4KiB::SIZE).as_u64();
+    let map_size = align_up(phys.as_u64() + size, Size4KiB::SIZE) - phys_start;
+
+    // Keep the virtual address congruent to the physical one,
+    // that way large pages can be used
+    let (reserved, virt) = reserve(map_size, phys_start, max_page_size)?;
+    let flags = flags | PageTableFlags::PRESENT;
+
+    let res = with_memory(|mapper, frame_allocator| {
+        let mut offset = 0;
+        while offset < map_size {
+            let page_size = page_size_for(
+                virt + offset,
+                phys_start + offset,
+                map_size - offset,
+                max_page_size,
+            );
+            map_any(
+                mapper,
+                frame_allocator,
+                virt + offset,
+                phys_start + offset,
+                page_size,
+                flags,
+            )?;
+            offset += page_size;
+        }
+        Ok(())
+    });
+    if let Err(err) = res {
+        teardown(virt, map_size, false);
+        release(reserved);
+        return Err(err);
+    }
+
+    insert(Region {
+        start: VirtAddr::new(virt),
+        size: map_size,
+        phys: Some(PhysAddr::new(phys_start)),
+        flags,
+        purpose,
+        owned: false,
+        reserved,
+    });
+    Ok(VirtAddr::new(virt + (phys.as_u64() - phys_start)))
+}
+
+/// Maps device memory uncached and not executable
+pub unsafe fn map_mmio(
+    phys: PhysAddr,
+    size: u64,
+    purpose: &'static str,
+) -> Result<VirtAddr, VmmError> {
+    map_phys(
+        phys,
+        size,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::NO_EXECUTE,
+        purpose,
+    )
+}
+
+/// Allocates a region of `size` bytes backed by new frames.
+/// The frames are not contiguous, large pages are used where possible.
+pub fn alloc(
+    size: u64,
+    flags: PageTableFlags,
+    purpose: &'static str,
+) -> Result<VirtAddr, VmmError> {
+    if size == 0 {
+        return Err(VmmError::InvalidSize);
+    }
+    let size = align_up(size, Size4KiB::SIZE);
+    let (reserved, virt) = reserve(size, 0, Size1GiB::SIZE)?;
+    let flags = flags | PageTableFlags::PRESENT;
+
+    let res = with_memory(|mapper, frame_allocator| unsafe {
+        let mut offset = 0;
+        while offset < size {
+            // Take the largest frame that fits, smaller ones if memory is fragmented
+            let (phys, page_size) = page_sizes()
+                .iter()
+                .filter(|s| (virt + offset) % **s == 0 && size - offset >= **s)
+                .find_map(|s| alloc_frame(frame_allocator, *s).map(|phys| (phys, *s)))
+                .ok_or(VmmError::OutOfPhysicalMemory)?;
+            if let Err(err) = map_any(
+                mapper,
+                frame_allocator,
+                virt + offset,
+                phys,
+                page_size,
+                flags,
+            ) {
+                frame_allocator.dealloc(PhysAddr::new(phys), order_of_size(page_size));
+                return Err(err);
+            }
+            offset += page_size;
+        }
+        Ok(())
+    });
+    if let Err(err) = res {
+        unsafe { teardown(virt, size, true) };
+        release(reserved);
+        return Err(err);
+    }
+
+    insert(Region {
+        start: VirtAddr::new(virt),
+        size,
+        phys: None,
+        flags,
+        purpose,
+        owned: true,
+        reserved,
+    });
+    Ok(VirtAddr::new(virt))
+}
+
+/// Allocates a physically contiguous region, e.g. for DMA buffers.
+/// Returns the virtual and the physical start address.
+pub fn alloc_contiguous(
+    size: u64,
+    flags: PageTableFlags,
+    purpose: &'static str,
+) -> Result<(VirtAddr, PhysAddr), VmmError> {
+    alloc_contiguous_with(size, flags, Size1GiB::SIZE, purpose)
+}
+
+/// Allocates a physically contiguous region mapped with pages of size S only.
+/// The size is rounded up to a multiple of S, the physical start is aligned to S.
+pub fn alloc_huge<S: PageSize>(
+    size: u64,
+    flags: PageTableFlags,
+    purpose: &'static str,
+) -> Result<(VirtAddr, PhysAddr), VmmError> {
+    if S::SIZE == Size1GiB::SIZE && !has_1gib_pages() {
+        return Err(VmmError::UnsupportedPageSize(S::SIZE));
+    }
+    if size == 0 {
+        return Err(VmmError::InvalidSize);
+    }
+    alloc_contiguous_with(align_up(size, S::SIZE), flags, S::SIZE, purpose)
+}
+
+fn alloc_contiguous_with(
+    size: u64,
+    flags: PageTableFlags,
+    max_page_size: u64,
+    purpose: &'static str,
+) -> Result<(VirtAddr, PhysAddr), VmmError> {
+    if size == 0 {
+        return Err(VmmError::InvalidSize);
+    }
+    let size = align_up(size, Size4KiB::SIZE);
+
+    let phys = with_memory(|_, frame_allocator| frame_allocator.alloc_contiguous(size))
+        .ok_or(VmmError::OutOfPhysicalMemory)?;
+
+    let res = unsafe { map_phys_with(phys, size, flags, max_page_size, purpose) };
+    match res {
+        Ok(virt) => {
+            // The region owns the frames now
+            with_vmm(|vmm| vmm.regions.get_mut(&virt.as_u64()).unwrap().owned = true);
+            Ok((virt, phys))
+        }
+        Err(err) => {
+            with_memory(|_, frame_allocator| unsafe {
+                frame_allocator.dealloc_range(phys, phys + size)
+            });
+            Err(err)
+        }
+    }
+}
+
+/// Sets the memory type of the identity mapped alias of `phys..phys+size`,
+/// only memory below IDENTITY_MAPPED_END has such an alias.
+/// Accessing memory through mappings with different types is undefined,
+/// a region that is not write back has to change its alias first and
+/// set it back to write back before its frames get freed.
+/// Lines cached with the old type are not flushed.
+/// The type is set per mapped page, the bootloader maps the alias with
+/// 2MiB pages. Panics if a page of the alias reaches outside the range,
+/// the range has to be aligned to the pages mapping it.
+pub(crate) unsafe fn set_alias_cache_type(phys: PhysAddr, size: u64, cache_type: CacheType) {
+    let start = phys.as_u64();
+    let end = core::cmp::min(start + size, IDENTITY_MAPPED_END);
+    if start >= end {
+        return;
+    }
+
+    let mut batch = TlbFlushBatch::new();
+    with_memory(|mapper, _| {
+        let mut addr = start;
+        while addr < end {
+            let virt = VirtAddr::new(addr);
+            let (frame, flags) = match mapper.translate(virt) {
+                TranslateResult::Mapped { frame, flags, .. } => (frame, flags),
+                _ => {
+                    addr += Size4KiB::SIZE;
+                    continue;
+                }
+            };
+            let page_start = frame.start_address().as_u64();
+            assert!(
+                page_start >= start && page_start + frame.size() <= end,
+                "Alias page {:#x} of size {:#x} exceeds {:#x}..{:#x}",
+                page_start,
+                frame.size(),
+                start,
+                end
+            );
+            let flags = (flags - PageTableFlags::WRITE_THROUGH - PageTableFlags::NO_CACHE)
+                | cache_type.flags();
+            let res = match frame {
+                MappedFrame::Size1GiB(_) => mapper
+                    .update_flags(Page::<Size1GiB>::containing_address(virt), flags)
+                    .map(|flush| flush.ignore()),
+                MappedFrame::Size2MiB(_) => mapper
+                    .update_flags(Page::<Size2MiB>::containing_address(virt), flags)
+                    .map(|flush| flush.ignore()),
+                MappedFrame::Size4KiB(_) => mapper
+                    .update_flags(Page::<Size4KiB>::containing_address(virt), flags)
+                    .map(|flush| flush.ignore()),
+            };
+            if let Err(err) = res {
+                log::error!("Failed to update {:#x}: {:?}", addr, err);
+            }
+            batch.add_range(VirtAddr::new(page_start), 1, frame.size());
+            addr = page_start + frame.size();
+        }
+    });
+    batch.shootdown();
+}
+
+/// Unmaps the region that contains `addr` and frees its frames
+/// if they have been allocated by the vmm
+pub unsafe fn unmap(addr: VirtAddr) -> Result<(), VmmError> {
+    let region = with_vmm(|vmm| {
+        let start = vmm
+            .regions
+            .range(..=addr.as_u64())
+            .next_back()
+            .filter(|(_, region)| region.contains(addr))
+            .map(|(start, _)| *start)?;
+        vmm.regions.remove(&start)
+    })
+    .ok_or(VmmError::NotMapped(addr))?;
+
+    log::debug!("Unmapping {}", region);
+    teardown(region.start.as_u64(), region.size, region.owned);
+    release(region.reserved);
+    Ok(())
+}
+
+/// Returns the region that contains `addr`
+pub fn region_of(addr: VirtAddr) -> Option<Region> {
+    with_vmm(|vmm| {
+        vmm.regions
+            .range(..=addr.as_u64())
+            .next_back()
+            .map(|(_, region)| *region)
+            .filter(|region| region.contains(addr))
+    })
+}
+
+/// Returns all mapped regions ordered by address
+pub fn regions() -> Vec<Region> {
+    with_vmm(|vmm| vmm.regions.values().copied().collect())
+}
+
+/// Logs all mapped regions
+pub fn dump() {
+    for region in regions() {
+        log::info!("{}", region);
+    }
+}
@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::panic::PanicInfo;
+use perf_kernel::backtrace::{self, Demangle};
+use perf_kernel::println;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== backtrace test =====");
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[inline(never)]
+fn target() {}
+
+#[test_case]
+fn resolve_function() {
+    let addr = target as fn() as usize as u64;
+    let (name, offset) = backtrace::resolve(addr + 1).unwrap();
+    assert_eq!(offset, 1);
+    assert_eq!(format!("{}", Demangle(name)), "backtrace::target");
+}
+
+#[inline(never)]
+fn frames() -> usize {
+    let mut num_frames = 0;
+    let mut first = None;
+    backtrace::walk(backtrace::frame_pointer(), |addr| {
+        first.get_or_insert(addr);
+        num_frames += 1;
+    });
+    let (name, _) = backtrace::resolve(first.unwrap()).unwrap();
+    assert_eq!(format!("{}", Demangle(name)), "backtrace::caller");
+    num_frames
+}
+
+#[inline(never)]
+fn caller() -> usize {
+    frames()
+}
+
+#[test_case]
+fn walk_stack() {
+    assert!(caller() >= 2);
+}
@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(bench_black_box)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::hint::black_box;
+use core::panic::PanicInfo;
+use perf_kernel::bench::runner::{self, Args, Cores};
+use perf_kernel::bench::stats::{percentile, Stats};
+use perf_kernel::bench::{Bench, BenchCase, Report};
+use perf_kernel::pmu::{self, Event, PmuError};
+use perf_kernel::println;
+use perf_kernel::time::Duration;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== bench test =====");
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn summary_statistics() {
+    let mut samples = [0.0; 101];
+    for (i, sample) in samples.iter_mut().rev().enumerate() {
+        *sample = i as f64;
+    }
+    samples[50] = 1000.0;
+    let stats = Stats::new(&mut samples).unwrap();
+    assert_eq!(stats.min, 0.0);
+    assert_eq!(stats.max, 1000.0);
+    assert_eq!(stats.median, 51.0);
+    assert_eq!(stats.outliers, 1);
+    assert!(stats.mean > 50.0 && stats.stddev > 29.0);
+    assert_eq!(percentile(&[1.0, 2.0], 50.0), 1.5);
+    assert!(Stats::new(&mut []).is_none());
+}
+
+#[test_case]
+fn bench_sum() {
+    let events = [Event::Instructions];
+    let bench = Bench::new("bench_sum")
+        .warm_up(Duration::from_millis(10))
+        .measurement_time(Duration::from_millis(50))
+        .samples(20)
+        .counters(&events);
+    let report = match bench.run(|| (0..black_box(100u64)).sum::<u64>()) {
+        Ok(report) => report,
+        Err(err) => {
+            assert_eq!(err, PmuError::NotSupported);
+            Bench::new("bench_sum")
+                .warm_up(Duration::from_millis(10))
+                .measurement_time(Duration::from_millis(50))
+                .samples(20)
+                .run(|| (0..black_box(100u64)).sum::<u64>())
+                .unwrap()
+        }
+    };
+    let cycles = report.cycles;
+    assert_eq!(cycles.samples, 20);
+    assert!(report.iters > 1);
+    assert!(cycles.min <= cycles.median && cycles.median <= cycles.max);
+    assert!(cycles.median > 0.0);
+    assert!(report.line().starts_with("bench: v=1 name=bench_sum "));
+    if pmu::is_available() {
+        assert!(report.counters[0].1 >= 100.0);
+    }
+}
+
+fn short_bench(bench: Bench) -> Result<Report, PmuError> {
+    bench
+        .warm_up(Duration::from_millis(10))
+        .measurement_time(Duration::from_millis(20))
+        .samples(10)
+        .run(|| black_box(1u64) + 1)
+}
+
+#[test_case]
+fn run_selected_cases() {
+    let args = Args::parse("--bench short --core 0 --unknown".split_whitespace());
+    assert!(args.bench);
+    assert_eq!(args.cores, Cores::Index(0));
+    assert!(args.matches("bench::short_bench"));
+    assert!(!args.matches("bench::other"));
+
+    let cases = [
+        BenchCase::new("bench::short_bench", short_bench),
+        BenchCase::new("bench::other", short_bench),
+    ];
+    assert_eq!(runner::run(&cases, &args), 0);
+    let offline = Args::parse("--bench --core 100000".split_whitespace());
+    assert_eq!(runner::run(&cases, &offline), 2);
+}
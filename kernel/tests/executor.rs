@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use perf_kernel::time::{Duration, Instant};
+use perf_kernel::{executor, println, time, timer};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== executor test =====");
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn block_on_ready() {
+    let res = executor::block_on(async { 41 + 1 });
+    assert_eq!(res, 42);
+}
+
+#[test_case]
+fn block_on_yield() {
+    let res = executor::block_on(async {
+        executor::yield_now().await;
+        executor::yield_now().await;
+        7
+    });
+    assert_eq!(res, 7);
+}
+
+#[test_case]
+fn spawned_tasks_run() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..10 {
+        let counter = counter.clone();
+        executor::spawn(async move {
+            executor::yield_now().await;
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    // Spawned tasks run while block_on waits
+    executor::block_on(async {
+        while counter.load(Ordering::SeqCst) != 10 {
+            executor::yield_now().await;
+        }
+    });
+    assert_eq!(counter.load(Ordering::SeqCst), 10);
+}
+
+#[test_case]
+fn timer_sleep() {
+    let deadline = time::future(Duration::from_millis(2));
+    executor::block_on(timer::sleep_until(deadline));
+    assert!(deadline.has_passed());
+}
+
+#[test_case]
+fn timer_callbacks_fire_in_order() {
+    let order = Arc::new(AtomicUsize::new(0));
+    let now = Instant::now();
+    for i in (0..4).rev() {
+        let order = order.clone();
+        timer::set_timeout(
+            now + Duration::from_micros((i + 1) * 500),
+            timer::TimerAction::callback(move || {
+                assert_eq!(order.fetch_add(1, Ordering::SeqCst), i as usize);
+            }),
+        );
+    }
+
+    executor::block_on(timer::sleep(Duration::from_millis(5)));
+    assert_eq!(order.load(Ordering::SeqCst), 4);
+}
+
+#[test_case]
+fn timer_past_deadline_fires() {
+    let fired = Arc::new(AtomicBool::new(false));
+    // Far enough back to lie behind the slots the wheel already processed
+    let past = time::duration_to_cycles(Duration::from_millis(300));
+    let deadline = Instant::from_cycles(Instant::now().as_cycles().saturating_sub(past));
+    let flag = fired.clone();
+    timer::set_timeout(
+        deadline,
+        timer::TimerAction::callback(move || flag.store(true, Ordering::SeqCst)),
+    );
+
+    time::sleep(Duration::from_millis(2));
+    assert!(fired.load(Ordering::SeqCst));
+}
@@ -0,0 +1,116 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::panic::PanicInfo;
+use perf_kernel::memory::frame_alloc::order_of;
+use perf_kernel::memory::BuddyFrameAllocator;
+use perf_kernel::{memory, println};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== frame_alloc test =====");
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn mixed_sizes_do_not_overlap() {
+    let (_, frame_allocator) = memory::get().unwrap();
+    let mut frame_allocator = frame_allocator.lock();
+
+    let small: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().unwrap();
+    let huge: PhysFrame<Size2MiB> = frame_allocator.allocate_frame().unwrap();
+    let small2: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().unwrap();
+
+    assert!(huge.start_address().is_aligned(Size2MiB::SIZE));
+    assert_ne!(small, small2);
+    for frame in [small, small2].iter() {
+        let addr = frame.start_address();
+        assert!(addr < huge.start_address() || addr >= huge.start_address() + huge.size());
+    }
+
+    unsafe {
+        frame_allocator.deallocate_frame(small);
+        frame_allocator.deallocate_frame(huge);
+        frame_allocator.deallocate_frame(small2);
+    }
+}
+
+#[test_case]
+fn dealloc_returns_memory() {
+    let (_, frame_allocator) = memory::get().unwrap();
+    let mut frame_allocator = frame_allocator.lock();
+    let free = frame_allocator.num_free_bytes();
+
+    let frame: PhysFrame<Size2MiB> = frame_allocator.allocate_frame().unwrap();
+    assert_eq!(frame_allocator.num_free_bytes(), free - Size2MiB::SIZE);
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(frame_allocator.num_free_bytes(), free);
+
+    // The merged block is handed out again
+    let again: PhysFrame<Size2MiB> = frame_allocator.allocate_frame().unwrap();
+    assert_eq!(again, frame);
+    unsafe { frame_allocator.deallocate_frame(again) };
+}
+
+#[test_case]
+fn allocate_below_skips_split_high_blocks() {
+    const GIB: u64 = 1024 * 1024 * 1024;
+    let end = 8 * GIB;
+    // The bitmaps never touch the managed frames, the ranges are made up
+    let mut storage = vec![0u64; BuddyFrameAllocator::storage_words(0, end)];
+    let mut frames = unsafe { BuddyFrameAllocator::empty(0, end, storage.as_mut_ptr()) };
+
+    // Splitting the high block leaves it as the smallest free one
+    unsafe { frames.add_range(4 * GIB, 5 * GIB) };
+    let huge = frames.allocate(order_of::<Size2MiB>()).unwrap();
+    assert_eq!(huge.as_u64(), 4 * GIB);
+    unsafe { frames.add_range(GIB, 2 * GIB) };
+
+    let low = frames.allocate_below(0, 4 * GIB).unwrap();
+    assert_eq!(low.as_u64(), GIB);
+    assert!(frames.allocate_below(0, GIB).is_none());
+
+    // The high remainder is still handed out to unlimited allocations
+    let high = frames.allocate(0).unwrap();
+    assert!(high.as_u64() >= 4 * GIB);
+}
+
+#[test_case]
+fn small_frames_stay_identity_mapped() {
+    const GIB: u64 = 1024 * 1024 * 1024;
+    let end = 8 * GIB;
+    let mut storage = vec![0u64; BuddyFrameAllocator::storage_words(0, end)];
+    let mut frames = unsafe { BuddyFrameAllocator::empty(0, end, storage.as_mut_ptr()) };
+    unsafe {
+        frames.add_range(6 * GIB, 6 * GIB + Size4KiB::SIZE);
+        frames.add_range(2 * GIB, 3 * GIB);
+    }
+
+    // The smallest free block is the high 4KiB frame
+    let small: PhysFrame<Size4KiB> = frames.allocate_frame().unwrap();
+    assert!(small.start_address().as_u64() < memory::frame_alloc::IDENTITY_MAPPED_END);
+    let huge: PhysFrame<Size2MiB> = frames.allocate_frame().unwrap();
+    assert_eq!(huge.start_address().as_u64(), 2 * GIB + Size2MiB::SIZE);
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::panic::PanicInfo;
+use perf_kernel::gdb::packet::{self, Response};
+use perf_kernel::gdb::TrapFrame;
+use perf_kernel::println;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== gdb test =====");
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn packet_encoding() {
+    // $qAttached#8f as sent by gdb
+    assert_eq!(packet::checksum(b"qAttached"), 0x8f);
+    assert_eq!(packet::parse_hex(b"ffffffff8000a0f0"), Some(0xffff_ffff_8000_a0f0));
+    assert_eq!(packet::parse_hex(b"x1"), None);
+
+    let mut bytes = [0; 4];
+    assert_eq!(packet::decode_hex(b"deadBEEF", &mut bytes), Some(4));
+    assert_eq!(bytes, [0xde, 0xad, 0xbe, 0xef]);
+    assert_eq!(packet::decode_hex(b"abc", &mut bytes), None);
+
+    let mut response = Response::new();
+    response.push_hex_le(0x1234, 4);
+    assert_eq!(response.as_bytes(), b"34120000");
+}
+
+#[test_case]
+fn register_numbers() {
+    let mut frame = TrapFrame {
+        rax: 1,
+        rsp: 2,
+        r15: 3,
+        rip: 4,
+        rflags: 0x202,
+        ..Default::default()
+    };
+    assert_eq!(frame.reg(0), Some(1));
+    assert_eq!(frame.reg(7), Some(2));
+    assert_eq!(frame.reg(15), Some(3));
+    assert_eq!(frame.reg(16), Some(4));
+    assert_eq!(frame.reg(17), Some(0x202));
+    assert_eq!(frame.reg(40), None);
+
+    assert!(frame.set_reg(16, 0x1000));
+    assert_eq!(frame.rip, 0x1000);
+    assert!(!frame.set_reg(40, 0));
+
+    let size: usize = (0..TrapFrame::NUM_REGS)
+        .map(|n| TrapFrame::reg_size(n).unwrap())
+        .sum();
+    assert_eq!(size, 536);
+}
@@ -4,6 +4,7 @@
 #![test_runner(perf_kernel::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(bench_black_box)]
+#![feature(allocator_api)]
 
 extern crate alloc;
 
@@ -11,7 +12,7 @@ use bootloader::bootinfo::BootInfo;
 use bootloader::entry_point;
 use core::hint::black_box;
 use core::panic::PanicInfo;
-use perf_kernel::{allocator::HEAP_START, allocator::ALLOCATOR, bench::Bench, klog, print, println};
+use perf_kernel::{allocator::HEAP_START, allocator::ALLOCATOR, klog, print, println};
 
 entry_point!(main);
 
@@ -106,7 +107,6 @@ fn realloc_grow_forward() {
 fn realloc_copy_grow() {
     unsafe {
         let lock = TEST_LOCK.lock();
-        let mut bench = Bench::start();
         let layout = Layout::from_size_align(32, 16).unwrap();
         let old_ptr = alloc(layout);
         let obstacle_ptr = alloc(layout);
@@ -123,7 +123,6 @@ fn realloc_copy_grow() {
 
         dealloc(new_ptr, layout);
         dealloc(obstacle_ptr, layout);
-        bench.end();
         black_box(lock);
     }
 }
@@ -132,7 +131,6 @@ fn realloc_copy_grow() {
 fn realloc_copy_shrink() {
     let lock = unsafe {TEST_LOCK.lock() };
     unsafe {
-        let mut bench = Bench::start();
         let layout = Layout::from_size_align(32, 16).unwrap();
         let old_ptr = alloc(layout);
         let obstacle_ptr = alloc(layout);
@@ -148,7 +146,6 @@ fn realloc_copy_shrink() {
 
         dealloc(new_ptr, layout);
         dealloc(obstacle_ptr, layout);
-        bench.end();
     }
     black_box(lock);
 }
@@ -183,6 +180,87 @@ fn heap_full_alloc() {
     black_box(vec);
 }
 
+#[test_case]
+fn heap_grows() {
+    let size = 4 * HEAP_SIZE;
+    let mut vec: Vec<u8> = Vec::new();
+    vec.resize(size, 0xab);
+    assert!(perf_kernel::allocator::heap_size() >= size);
+    assert!(vec.iter().all(|b| *b == 0xab));
+}
+
+#[test_case]
+fn heap_exhaustion_is_reported() {
+    let mut vec: Vec<u8> = Vec::new();
+    assert!(vec.try_reserve(perf_kernel::allocator::HEAP_MAX_SIZE).is_err());
+}
+
+/// Unused virtual range between the node heaps and the vmm window
+const TEST_HEAP_START: usize = 0x_5000_0000_0000;
+
+#[test_case]
+fn frame_exhaustion_releases_partial_grow() {
+    use core::alloc::Allocator;
+    use perf_kernel::allocator::slab::SlabAllocator;
+    use perf_kernel::allocator::HeapRange;
+    use perf_kernel::{memory, smp};
+    use x86_64::instructions::interrupts::without_interrupts;
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB};
+
+    // Other cores would fail their own allocations while the frames are drained
+    if smp::num_cores_online() > 1 {
+        print!("(skipped, other cores online) ");
+        return;
+    }
+
+    let step = 2 * 1024 * 1024;
+    let heap = SlabAllocator::uncached(HeapRange::new(TEST_HEAP_START, 1 << 30, None));
+
+    // Map the first page while memory is left, later pages share its page tables
+    let first = heap.allocate(Layout::new::<u64>()).unwrap();
+    assert_eq!(heap.backing().size(), step);
+
+    // Leave two 2MiB frames, growing by four pages fails half way
+    let (_, frame_allocator) = memory::get().unwrap();
+    let num_frames = frame_allocator.lock().num_free_bytes() as usize / step;
+    // Room for frames freed meanwhile, the vec must not grow while the lock is held
+    let mut frames: Vec<PhysFrame<Size2MiB>> = Vec::with_capacity(num_frames + 64);
+    // An interrupt handler allocating here must not wait for the held lock
+    without_interrupts(|| {
+        let mut frame_allocator = frame_allocator.lock();
+        while let Some(frame) = frame_allocator.allocate_frame() {
+            frames.push(frame);
+        }
+        for frame in frames.drain(frames.len() - 2..) {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    });
+    let free = frame_allocator.lock().num_free_bytes();
+
+    assert!(heap
+        .allocate(Layout::from_size_align(4 * step, 4096).unwrap())
+        .is_err());
+    assert_eq!(heap.backing().size(), step);
+    assert_eq!(frame_allocator.lock().num_free_bytes(), free);
+
+    without_interrupts(|| {
+        let mut frame_allocator = frame_allocator.lock();
+        for frame in frames.drain(..) {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    });
+    unsafe { heap.deallocate(first.cast(), Layout::new::<u64>()) };
+}
+
+#[test_case]
+fn allocations_are_counted() {
+    let (allocs, deallocs) = perf_kernel::allocator::num_allocs();
+    drop(black_box(Box::new(1u64)));
+    let (allocs_after, deallocs_after) = perf_kernel::allocator::num_allocs();
+    assert!(allocs_after > allocs);
+    assert!(deallocs_after > deallocs);
+}
+
 #[test_case]
 fn mult_alloc() {
     {
@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(bench_black_box)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::hint::black_box;
+use core::panic::PanicInfo;
+use perf_kernel::ibs::{self, Config, IbsError, OpSample};
+use perf_kernel::println;
+use perf_kernel::profile::ring::SampleRing;
+use perf_kernel::profile::stream;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== ibs test =====");
+    if !ibs::is_available() {
+        println!("No IBS, sampling is not tested");
+    }
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn ring_overwrites_oldest() {
+    let mut ring = SampleRing::new(4);
+    for i in 0..6u64 {
+        unsafe { ring.push(i) };
+    }
+    assert_eq!(ring.len(), 4);
+    assert_eq!(ring.lost(), 2);
+    assert!(ring.iter().copied().eq(2..6));
+    ring.clear();
+    assert!(ring.is_empty());
+}
+
+#[test_case]
+fn record_layout() {
+    let sample = OpSample {
+        rip: 0xffff_ffff_8000_1234,
+        data_addr: 0x1000,
+        tag_to_retire: 3,
+        miss_latency: 200,
+        flags: OpSample::LOAD | OpSample::DC_MISS,
+        data_src: 1,
+    };
+    let record = stream::encode_record(2, [sample, sample].iter());
+    assert_eq!(record.len(), stream::HEADER_SIZE + 2 * 24 + 4);
+    assert_eq!(record[..4], stream::MAGIC);
+    assert_eq!(record[5], stream::Kind::IbsOp as u8);
+    assert_eq!(record[6..8], 2u16.to_le_bytes());
+    assert_eq!(record[8..12], 2u32.to_le_bytes());
+    assert_eq!(record[12..14], 24u16.to_le_bytes());
+    assert_eq!(record[16..24], sample.rip.to_le_bytes());
+
+    let body = &record[stream::HEADER_SIZE..record.len() - 4];
+    let checksum: u32 = body.iter().map(|b| *b as u32).sum();
+    assert_eq!(record[record.len() - 4..], checksum.to_le_bytes());
+}
+
+#[test_case]
+fn sample_ops() {
+    let config = Config {
+        op_period: Some(0x4000),
+        ..Config::default()
+    };
+    if !ibs::is_available() {
+        assert_eq!(ibs::start(config), Err(IbsError::NotSupported));
+        return;
+    }
+    let too_short = Config {
+        op_period: Some(ibs::MIN_PERIOD - 1),
+        ..config
+    };
+    assert_eq!(ibs::start(too_short), Err(IbsError::InvalidPeriod));
+
+    ibs::start(config).unwrap();
+    assert_eq!(ibs::start(config), Err(IbsError::Running));
+    for i in 0..1_000_000u64 {
+        black_box(i);
+    }
+    ibs::stop();
+
+    let core = perf_kernel::percpu!(core_index);
+    let samples = ibs::op_samples(core).unwrap();
+    assert!(!samples.is_empty());
+    assert!(samples.iter().any(|s| s.rip != 0));
+}
@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::panic::PanicInfo;
+use perf_kernel::{ipi, percpu, println, smp};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== ipi test =====");
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn call_on_self() {
+    let own_id = percpu!(apic_id);
+    let res = ipi::call_on(own_id, move || percpu!(apic_id) + 1);
+    assert_eq!(res, own_id + 1);
+}
+
+#[test_case]
+fn call_on_all_collects_results() {
+    let cores = smp::online_cores();
+    let res = ipi::call_on_all(|| percpu!(apic_id));
+    assert_eq!(res.len(), cores.len());
+    for (apic_id, res) in cores.iter().zip(res.iter()) {
+        assert_eq!(apic_id, res);
+    }
+}
+
+#[test_case]
+fn call_on_many_runs_duplicates_once() {
+    let cores = smp::online_cores();
+    let mut ids = cores.clone();
+    ids.extend_from_slice(&cores);
+    let res = ipi::call_on_many(&ids, || percpu!(apic_id));
+    assert_eq!(res, cores);
+}
+
+#[test_case]
+fn all_launched_cores_online() {
+    smp::wait_for_all_cores();
+    let online = smp::online_cores();
+    assert!(online.contains(&percpu!(apic_id)));
+    assert_eq!(online.len(), smp::num_cores_online() as usize);
+}
@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(allocator_api)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::panic::PanicInfo;
+use perf_kernel::{numa, println};
+use x86_64::structures::paging::{PhysFrame, Size2MiB};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== numa test =====");
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn alloc_on_every_node() {
+    assert!(numa::current_node() < numa::num_nodes());
+    for node in 0..numa::num_nodes() {
+        let free = numa::stats(node).free_bytes;
+        if free == 0 {
+            continue;
+        }
+        let frame: PhysFrame<Size2MiB> = numa::alloc_on_node(node).unwrap();
+        assert_eq!(numa::stats(node).free_bytes, free - 2 * 1024 * 1024);
+        unsafe { numa::dealloc_frame(frame) };
+        assert_eq!(numa::stats(node).free_bytes, free);
+    }
+}
+
+#[test_case]
+fn node_heap() {
+    let heap = numa::heap(numa::current_node());
+    let mut vec = Vec::new_in(heap);
+    for i in 0..10_000u64 {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), 9_999 * 10_000 / 2);
+}
@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(bench_black_box)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::hint::black_box;
+use core::panic::PanicInfo;
+use perf_kernel::pmu::{self, Counters, Event, PmuError};
+use perf_kernel::println;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== pmu test =====");
+    if !pmu::is_available() {
+        println!("No performance counters, tests only check the errors");
+    }
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn count_instructions() {
+    let counters = match Counters::new(&[Event::Instructions, Event::Cycles]) {
+        Ok(counters) => counters,
+        Err(err) => {
+            assert_eq!(err, PmuError::NotSupported);
+            return;
+        }
+    };
+    let start = counters.read();
+    for i in 0..10_000u64 {
+        black_box(i);
+    }
+    let counts = counters.read().delta(&start);
+    assert!(counts.values()[0] >= 10_000);
+    assert!(counts.values()[1] > 0);
+}
+
+#[test_case]
+fn claim_counters() {
+    let all = [Event::Cycles; pmu::MAX_COUNTERS + 1];
+    let expected = if pmu::is_available() {
+        PmuError::TooManyEvents
+    } else {
+        PmuError::NotSupported
+    };
+    assert_eq!(Counters::new(&all).err(), Some(expected));
+    if !pmu::is_available() {
+        return;
+    }
+
+    let n = pmu::num_counters();
+    let counters = Counters::new(&all[..n]).unwrap();
+    assert_eq!(Counters::new(&all[..1]).err(), Some(PmuError::CountersBusy));
+    drop(counters);
+    assert!(Counters::new(&all[..1]).is_ok());
+}
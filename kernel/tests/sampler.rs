@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(bench_black_box)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::hint::black_box;
+use core::panic::PanicInfo;
+use perf_kernel::println;
+use perf_kernel::profile::sampler;
+use perf_kernel::time::{self, Duration};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== sampler test =====");
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[inline(never)]
+fn spin_for(duration: Duration) {
+    let deadline = time::future(duration);
+    while !deadline.has_passed() {
+        black_box(0);
+    }
+}
+
+#[test_case]
+fn folded_stacks() {
+    assert!(sampler::start(Duration::from_millis(1)));
+    assert!(!sampler::start(Duration::from_millis(1)));
+    assert!(sampler::fold().is_none());
+    spin_for(Duration::from_millis(100));
+    sampler::stop();
+
+    let core = perf_kernel::percpu!(core_index);
+    let samples = sampler::samples(core).unwrap();
+    assert!(samples.len() > 10);
+    assert!(samples.iter().all(|s| !s.frames().is_empty()));
+
+    let folded = sampler::fold().unwrap();
+    let spinning: usize = folded
+        .iter()
+        .filter(|(stack, _)| stack.contains("sampler::spin_for"))
+        .map(|(_, count)| count)
+        .sum();
+    assert!(spinning > samples.len() / 2);
+    assert!(sampler::dump_folded());
+    assert!(sampler::dump());
+}
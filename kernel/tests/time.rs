@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::panic::PanicInfo;
+use perf_kernel::println;
+use perf_kernel::time::{self, Duration, Instant, TscScale};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== time test =====");
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn exact_rate_round_trip() {
+    // 2GHz has exact fixed point multipliers
+    let scale = TscScale::new(2_000_000_000);
+    assert_eq!(scale.ns_to_cycles(1_000_000_000), 2_000_000_000);
+    assert_eq!(scale.cycles_to_ns(2_000_000_000), 1_000_000_000);
+    for ns in [0, 1, 999, 123_456_789, 3_600_000_000_000].iter() {
+        assert_eq!(scale.cycles_to_ns(scale.ns_to_cycles(*ns)), *ns);
+    }
+}
+
+#[test_case]
+fn inexact_rate_round_trip() {
+    let scale = TscScale::new(3_300_000_000);
+    let cycles = scale.ns_to_cycles(1_000_000);
+    assert!(cycles <= 3_300_000 && 3_300_000 - cycles <= 1);
+    for ns in [1_000, 1_000_000, 1_000_000_000, 3_600_000_000_000].iter() {
+        let back = scale.cycles_to_ns(scale.ns_to_cycles(*ns));
+        // Both conversions round down, the multipliers are off by less than 1ppm
+        assert!(
+            back <= *ns && *ns - back <= 2 + *ns / 1_000_000,
+            "{} -> {}",
+            ns,
+            back
+        );
+    }
+}
+
+#[test_case]
+fn duration_to_cycles_saturates() {
+    let scale = TscScale::new(3_000_000_000);
+    assert_eq!(scale.duration_to_cycles(Duration::MAX), u64::MAX);
+    // Fits into u64 nanoseconds but not into u64 cycles
+    assert_eq!(
+        scale.duration_to_cycles(Duration::from_nanos(u64::MAX)),
+        u64::MAX
+    );
+    assert!(scale.cycles_to_ns(u64::MAX) <= u64::MAX / 3);
+    assert_eq!(time::duration_to_cycles(Duration::MAX), u64::MAX);
+}
+
+#[test_case]
+fn instant_arithmetic() {
+    let earlier = Instant::from_cycles(1_000);
+    let step = Duration::from_micros(5);
+    let later = earlier + step;
+    assert!(later > earlier);
+    assert_eq!(
+        later.as_cycles() - earlier.as_cycles(),
+        time::duration_to_cycles(step)
+    );
+
+    let mut moved = earlier;
+    moved += step;
+    assert_eq!(moved, later);
+
+    let elapsed = later.duration_since(earlier);
+    // Rounding loses up to one cycle and one nanosecond
+    let slack = Duration::from_nanos(2 + 1_000_000_000 / time::tsc_hz());
+    assert!(elapsed <= step && step - elapsed <= slack);
+    assert_eq!(later - earlier, elapsed);
+}
+
+#[test_case]
+fn instant_earlier_is_later() {
+    let earlier = Instant::from_cycles(1_000);
+    let later = Instant::from_cycles(5_000);
+    assert_eq!(earlier.duration_since(later), Duration::ZERO);
+    assert_eq!(earlier.cycles_since(later), 0);
+    assert_eq!(earlier - later, Duration::ZERO);
+}
+
+#[test_case]
+fn instant_add_overflow() {
+    let end = Instant::from_cycles(u64::MAX);
+    assert!(end.checked_add(Duration::from_secs(1)).is_none());
+    assert!(end.checked_add(Duration::MAX).is_none());
+    assert_eq!(end.checked_add(Duration::ZERO), Some(end));
+}
@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::panic::PanicInfo;
+use perf_kernel::bench::buffer::HugeBuffer;
+use perf_kernel::memory::frame_alloc::IDENTITY_MAPPED_END;
+use perf_kernel::memory::{self, CacheType};
+use perf_kernel::{println, vmm};
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::page::PageSize;
+use x86_64::structures::paging::{PageTableFlags, Size2MiB, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== vmm test =====");
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn alloc_and_unmap() {
+    let size = 3 * Size2MiB::SIZE;
+    let virt = vmm::alloc(size, PageTableFlags::WRITABLE, "vmm test").unwrap();
+    assert_eq!(virt.as_u64() % Size2MiB::SIZE, 0);
+
+    let slice =
+        unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u64>(), size as usize / 8) };
+    slice
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, x)| *x = i as u64);
+    assert!(slice.iter().enumerate().all(|(i, x)| *x == i as u64));
+
+    let region = vmm::region_of(virt + size / 2).unwrap();
+    assert_eq!(region.purpose, "vmm test");
+    assert_eq!(region.size, size);
+
+    unsafe { vmm::unmap(virt).unwrap() };
+    assert!(vmm::region_of(virt).is_none());
+    assert!(unsafe { vmm::unmap(virt) }.is_err());
+}
+
+#[test_case]
+fn contiguous_region() {
+    let (virt, phys) = vmm::alloc_contiguous(0x5000, PageTableFlags::WRITABLE, "dma test").unwrap();
+    assert_eq!(vmm::region_of(virt).unwrap().phys, Some(phys));
+    unsafe {
+        virt.as_mut_ptr::<u32>().write_volatile(0xdead_beef);
+        vmm::unmap(virt).unwrap();
+    }
+}
+
+#[test_case]
+fn huge_buffer() {
+    let mut buffer =
+        HugeBuffer::<Size2MiB>::new(Size2MiB::SIZE + 1, CacheType::WriteCombining).unwrap();
+    assert_eq!(buffer.size(), 2 * Size2MiB::SIZE);
+    assert!(buffer.phys_addr().is_aligned(Size2MiB::SIZE));
+    assert!(buffer.virt_addr().is_aligned(Size2MiB::SIZE));
+    assert!(buffer.as_bytes().iter().all(|b| *b == 0));
+    buffer.as_bytes_mut().fill(0xab);
+
+    let virt = buffer.virt_addr();
+    drop(buffer);
+    assert!(vmm::region_of(virt).is_none());
+}
+
+#[test_case]
+fn huge_buffer_alias_cache_type() {
+    let alias_flags = |phys: PhysAddr| {
+        let (page_table, _) = memory::get().unwrap();
+        match page_table.lock().translate(VirtAddr::new(phys.as_u64())) {
+            TranslateResult::Mapped { flags, .. } => flags,
+            _ => panic!("Identity alias of {:#x} is not mapped", phys.as_u64()),
+        }
+    };
+    let type_flags = PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE;
+
+    let buffer = HugeBuffer::<Size2MiB>::new(Size2MiB::SIZE, CacheType::Uncached).unwrap();
+    let phys = buffer.phys_addr();
+    if phys.as_u64() >= IDENTITY_MAPPED_END {
+        // There is no alias to keep consistent
+        return;
+    }
+    assert!(alias_flags(phys).contains(CacheType::Uncached.flags()));
+
+    drop(buffer);
+    assert!(!alias_flags(phys).intersects(type_flags));
+}
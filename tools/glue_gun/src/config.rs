@@ -34,6 +34,8 @@ pub struct Config {
     pub test_args: Option<Vec<String>>,
     /// The timeout for running an test through `glue_gun test` or `glue_gun runner` in seconds
     pub test_timeout: u32,
+    /// The timeout for running a test executable with `--bench` in seconds
+    pub bench_timeout: u32,
     /// An exit code that should be considered as success for test executables (applies to
     /// `glue_gun runner`)
     pub test_success_exit_code: Option<i32>,
@@ -81,6 +83,12 @@ fn read_config_inner(manifest_path: &Path) -> Result<Config> {
             ("test-timeout", Value::Integer(timeout)) => {
                 config.test_timeout = Some(timeout as u32);
             }
+            ("bench-timeout", Value::Integer(timeout)) if timeout.is_negative() => {
+                return Err(anyhow!("bench-timeout must not be negative"))
+            }
+            ("bench-timeout", Value::Integer(timeout)) => {
+                config.bench_timeout = Some(timeout as u32);
+            }
             ("test-success-exit-code", Value::Integer(exit_code)) => {
                 config.test_success_exit_code = Some(exit_code as i32);
             }
@@ -130,6 +138,7 @@ struct ConfigBuilder {
     run_args: Option<Vec<String>>,
     test_args: Option<Vec<String>>,
     test_timeout: Option<u32>,
+    bench_timeout: Option<u32>,
     test_success_exit_code: Option<i32>,
     debug_run_command: Option<Vec<String>>,
 }
@@ -163,6 +172,7 @@ impl From<ConfigBuilder> for Config {
             run_args: s.run_args,
             test_args: s.test_args.or_else(|| Some(vec!["-no-reboot".into()])),
             test_timeout: s.test_timeout.unwrap_or(60 * 5),
+            bench_timeout: s.bench_timeout.unwrap_or(60 * 30),
             test_success_exit_code: s.test_success_exit_code,
         }
     }
@@ -18,6 +18,8 @@ fn main() {
         .unwrap();
     log::set_max_level(LevelFilter::Info);
 
+    let (args, kernel_args) = split_kernel_args(env::args().collect());
+
     let matches = App::new("Glue gun")
         .author("Luis Hebendanz <luis.nixos@gmail.com")
         .about("Glues together a rust bootloader and kernel to generate a bootable ISO file")
@@ -48,7 +50,7 @@ fn main() {
                         .takes_value(false),
                 ),
         )
-        .get_matches();
+        .get_matches_from(args);
 
     if matches.is_present("verbose") {
         log::set_max_level(LevelFilter::Debug);
@@ -59,12 +61,42 @@ fn main() {
             log::set_max_level(LevelFilter::Debug);
         }
         debug!("Args: {:?}", std::env::args());
+        debug!("Kernel args: {:?}", kernel_args);
+
+        run(matches, &kernel_args);
+    }
+}
+
+/// Flags of `glue_gun run` that may follow the executable, e.g. from `cargo run -- -d`
+const RUN_FLAGS: [&str; 2] = ["-d", "-v"];
+
+/// Splits off the arguments after the executable that are not for glue_gun.
+/// Cargo passes them to the runner, e.g. `--bench` from `cargo bench` or
+/// test name filters. They end up on the kernel command line.
+fn split_kernel_args(args: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let executable = args.iter().position(|arg| arg == "run").and_then(|run| {
+        args.iter()
+            .skip(run + 1)
+            .position(|arg| !arg.starts_with('-'))
+            .map(|offset| run + 1 + offset)
+    });
+    let executable = match executable {
+        Some(executable) => executable,
+        None => return (args, Vec::new()),
+    };
 
-        run(matches);
+    let (mut own, mut kernel) = (Vec::new(), Vec::new());
+    for (i, arg) in args.into_iter().enumerate() {
+        if i <= executable || RUN_FLAGS.contains(&arg.as_str()) {
+            own.push(arg);
+        } else {
+            kernel.push(arg);
+        }
     }
+    (own, kernel)
 }
 
-fn run(matches: &ArgMatches) {
+fn run(matches: &ArgMatches, kernel_args: &[String]) {
     /*
         Where do these environment variables come from?
         https://doc.rust-lang.org/cargo/reference/environment-variables.html#environment-variables-cargo-sets-for-crates
@@ -181,13 +213,35 @@ fn run(matches: &ArgMatches) {
 
         println!("Iso for {} -> {}", kernel_name, iso_img.to_str().unwrap());
 
-        glue_grub(&iso_dir, &iso_img, &merged_exe);
+        glue_grub(&iso_dir, &iso_img, &merged_exe, kernel_args);
     }
 
-    run::run(config, &iso_img, is_test, matches.is_present("debug")).unwrap();
+    // Benchmark results go to target/<target>/<profile>/bench/<executable>.txt
+    let bench_results = if is_test && kernel_args.iter().any(|arg| arg == "--bench") {
+        let stem = merged_exe.file_stem().unwrap().to_str().unwrap();
+        let name = stem.rsplit_once('-').map_or(stem, |(name, _hash)| name);
+        let profile_dir = target_dir.parent().unwrap_or(&target_dir);
+        Some(profile_dir.join("bench").join(format!("{}.txt", name)))
+    } else {
+        None
+    };
+
+    run::run(
+        config,
+        &iso_img,
+        is_test,
+        matches.is_present("debug"),
+        bench_results.as_deref(),
+    )
+    .unwrap();
 }
 
-fn glue_grub(iso_dir: &PathBuf, iso_img: &PathBuf, executable: &PathBuf) {
+/// Quotes an argument for the grub shell
+fn grub_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+fn glue_grub(iso_dir: &PathBuf, iso_img: &PathBuf, executable: &PathBuf, kernel_args: &[String]) {
     match std::fs::create_dir(iso_dir) {
         Ok(_) => (),
         Err(e) => {
@@ -224,17 +278,21 @@ fn glue_grub(iso_dir: &PathBuf, iso_img: &PathBuf, executable: &PathBuf) {
         .open(&grub_dir.join("grub.cfg"))
         .unwrap();
 
+    let command_line: Vec<String> = kernel_args.iter().map(|arg| grub_quote(arg)).collect();
     grubcfg
-        .write(
-            r#"
+        .write_all(
+            format!(
+                r#"
             set timeout=0
             set default=0
 
-            menuentry "kernel" {
-                multiboot2 /boot/kernel.elf
+            menuentry "kernel" {{
+                multiboot2 /boot/kernel.elf {}
                 boot
-            }
-            "#
+            }}
+            "#,
+                command_line.join(" ")
+            )
             .as_bytes(),
         )
         .unwrap();
@@ -1,7 +1,13 @@
 //! Provides a function for running a disk image in QEMU.
 
 use crate::config::Config;
-use std::{io, path::Path, process, time::Duration};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+    process, thread,
+    time::Duration,
+};
 use thiserror::Error;
 use wait_timeout::ChildExt;
 
@@ -11,11 +17,15 @@ use wait_timeout::ChildExt;
 /// commands defined in the given `Config`. Since test executables are treated
 /// differently (run with a timeout and match exit status), the caller needs to
 /// specify whether the given disk image is a test or not.
+///
+/// With `bench_results` the test runs as benchmark, the result lines the
+/// kernel prints get written to that file.
 pub fn run(
     config: Config,
     image_path: &Path,
     is_test: bool,
     is_debug: bool,
+    bench_results: Option<&Path>,
 ) -> Result<i32, RunError> {
     let mut run_command: Vec<_> = if is_debug {
         config
@@ -44,22 +54,40 @@ pub fn run(
     command.args(&run_command[1..]);
 
     let exit_code = if is_test {
+        if bench_results.is_some() {
+            command.stdout(process::Stdio::piped());
+        }
         let mut child = command.spawn().map_err(|error| RunError::Io {
             context: IoErrorContext::QemuTestCommand {
                 command: format!("{:?}", command),
             },
             error,
         })?;
-        let timeout = Duration::from_secs(config.test_timeout.into());
-        match child
-            .wait_timeout(timeout)
-            .map_err(context(IoErrorContext::WaitWithTimeout))?
-        {
-            None => {
-                child.kill().map_err(context(IoErrorContext::KillQemu))?;
-                child.wait().map_err(context(IoErrorContext::WaitForQemu))?;
-                return Err(RunError::TestTimedOut);
-            }
+        let collector = child
+            .stdout
+            .take()
+            .map(|stdout| thread::spawn(move || collect_bench_results(stdout)));
+
+        let timeout = if bench_results.is_some() {
+            config.bench_timeout
+        } else {
+            config.test_timeout
+        };
+        let status = child
+            .wait_timeout(Duration::from_secs(timeout.into()))
+            .map_err(context(IoErrorContext::WaitWithTimeout))?;
+        if status.is_none() {
+            child.kill().map_err(context(IoErrorContext::KillQemu))?;
+            child.wait().map_err(context(IoErrorContext::WaitForQemu))?;
+        }
+
+        if let (Some(path), Some(collector)) = (bench_results, collector) {
+            let results = collector.join().expect("Collecting bench results failed");
+            write_bench_results(path, &results)?;
+        }
+
+        match status {
+            None => return Err(RunError::TestTimedOut),
             Some(exit_status) => {
                 #[cfg(unix)]
                 {
@@ -91,6 +119,47 @@ pub fn run(
     Ok(exit_code)
 }
 
+/// Prefixes of the lines the benchmark runner of the kernel prints
+const BENCH_PREFIXES: [&str; 3] = ["bench: ", "bench-error: ", "bench-done: "];
+
+/// Passes the serial output of the kernel through to stdout and returns
+/// the benchmark result lines in it
+fn collect_bench_results(stdout: impl Read) -> Vec<String> {
+    let mut results = Vec::new();
+    let mut reader = BufReader::new(stdout);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let mut out = io::stdout();
+        let _ = out.write_all(&line).and_then(|_| out.flush());
+
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end();
+        if BENCH_PREFIXES.iter().any(|prefix| text.starts_with(prefix)) {
+            results.push(text.to_string());
+        }
+    }
+    results
+}
+
+fn write_bench_results(path: &Path, results: &[String]) -> Result<(), RunError> {
+    let bench_context = || IoErrorContext::BenchResults {
+        path: format!("{}", path.display()),
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(context(bench_context()))?;
+    }
+    let mut content = results.join("\n");
+    content.push('\n');
+    fs::write(path, content).map_err(context(bench_context()))?;
+    log::info!("{} bench results -> {}", results.len(), path.display());
+    Ok(())
+}
+
 /// Running the disk image failed.
 #[derive(Debug, Error)]
 pub enum RunError {
@@ -140,6 +209,13 @@ pub enum IoErrorContext {
     /// Failed to wait for QEMU process
     #[error("Failed to wait for QEMU process")]
     WaitForQemu,
+
+    /// Failed to write the benchmark results
+    #[error("Failed to write bench results to `{path}`")]
+    BenchResults {
+        /// The file the results should go to
+        path: String,
+    },
 }
 
 /// Helper function for IO error construction
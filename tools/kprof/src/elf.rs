@@ -0,0 +1,235 @@
+use std::convert::TryInto;
+use std::fmt::Write;
+use std::path::Path;
+
+/*
+ * Function symbols of the kernel ELF image
+ * Reads the .symtab section, the kernel is never stripped.
+ * Names get demangled the same way the kernel does in its stack traces.
+ */
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub start: u64,
+    pub size: u64,
+    /// Demangled name
+    pub name: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Symbols {
+    /// Sorted by start address
+    functions: Vec<Function>,
+}
+
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N], String> {
+    bytes
+        .get(offset..offset + N)
+        .map(|b| b.try_into().unwrap())
+        .ok_or_else(|| format!("ELF truncated at {:#x}", offset))
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, String> {
+    read(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    read(bytes, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Result<u64, String> {
+    read(bytes, offset).map(u64::from_le_bytes)
+}
+
+impl Symbols {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let image = std::fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Self::parse(&image).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    pub fn parse(image: &[u8]) -> Result<Self, String> {
+        if image.get(..4) != Some(b"\x7fELF") || image.get(4) != Some(&2) {
+            return Err("not an ELF64 image".into());
+        }
+        let shoff = u64_at(image, 0x28)? as usize;
+        let shentsize = u16_at(image, 0x3a)? as usize;
+        let shnum = u16_at(image, 0x3c)? as usize;
+
+        let section = |index: usize| shoff + index * shentsize;
+        let symtab = (0..shnum)
+            .map(section)
+            .find(|sh| u32_at(image, sh + 4) == Ok(SHT_SYMTAB))
+            .ok_or("no symbol table")?;
+        let strtab = section(u32_at(image, symtab + 40)? as usize);
+
+        let sym_offset = u64_at(image, symtab + 24)? as usize;
+        let sym_size = u64_at(image, symtab + 32)? as usize;
+        let str_offset = u64_at(image, strtab + 24)? as usize;
+        let str_size = u64_at(image, strtab + 32)? as usize;
+        let strings = image
+            .get(str_offset..str_offset + str_size)
+            .ok_or("string table truncated")?;
+
+        let mut functions = Vec::new();
+        for sym in (sym_offset..sym_offset + sym_size).step_by(24) {
+            if image.get(sym + 4).map(|info| info & 0xf) != Some(STT_FUNC) {
+                continue;
+            }
+            let start = u64_at(image, sym + 8)?;
+            let name = u32_at(image, sym)? as usize;
+            if start == 0 || name >= strings.len() {
+                continue;
+            }
+            let len = strings[name..].iter().position(|c| *c == 0).unwrap_or(0);
+            functions.push(Function {
+                start,
+                size: u64_at(image, sym + 16)?,
+                name: demangle(&String::from_utf8_lossy(&strings[name..name + len])),
+            });
+        }
+        functions.sort_by_key(|f| f.start);
+        Ok(Symbols { functions })
+    }
+
+    /// Returns the function containing `addr`
+    pub fn lookup(&self, addr: u64) -> Option<&Function> {
+        let index = self.functions.partition_point(|f| f.start <= addr);
+        let function = self.functions.get(index.checked_sub(1)?)?;
+        if addr < function.start + function.size.max(1) {
+            Some(function)
+        } else {
+            None
+        }
+    }
+
+    /// Lowest and highest address of all functions
+    pub fn range(&self) -> Option<(u64, u64)> {
+        let first = self.functions.first()?;
+        let end = self.functions.iter().map(|f| f.start + f.size).max()?;
+        Some((first.start, end))
+    }
+
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
+}
+
+/// Turns a legacy mangled Rust symbol like `_ZN4core9panicking5panic17h0123456789abcdefE`
+/// into `core::panicking::panic`. Other names are returned unchanged.
+pub fn demangle(name: &str) -> String {
+    let inner = match name.strip_prefix("_ZN").and_then(|s| s.strip_suffix('E')) {
+        Some(inner) => inner,
+        None => return name.to_string(),
+    };
+
+    let mut out = String::new();
+    let mut rest = inner;
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(|c| c.is_ascii_digit()).count();
+        let len: usize = match rest[..digits].parse() {
+            Ok(len) if digits + len <= rest.len() => len,
+            _ => return name.to_string(),
+        };
+        let part = &rest[digits..digits + len];
+        rest = &rest[digits + len..];
+
+        // The last part is the hash of the symbol
+        let is_hash = rest.is_empty()
+            && part.len() == 17
+            && part.starts_with('h')
+            && part[1..].bytes().all(|c| c.is_ascii_hexdigit());
+        if is_hash {
+            break;
+        }
+        if !out.is_empty() {
+            out.push_str("::");
+        }
+        write_part(&mut out, part);
+    }
+    out
+}
+
+/// Appends one path segment and replaces the escapes of the mangling
+fn write_part(out: &mut String, part: &str) {
+    // A leading underscore protects segments starting with an escape
+    let mut rest = part
+        .strip_prefix('_')
+        .filter(|r| r.starts_with('$'))
+        .unwrap_or(part);
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix("..") {
+            out.push_str("::");
+            rest = r;
+            continue;
+        }
+        if let Some(end) = rest.strip_prefix('$').and_then(|r| r.find('$')) {
+            let escape = &rest[1..end + 1];
+            let c = match escape {
+                "SP" => Some('@'),
+                "BP" => Some('*'),
+                "RF" => Some('&'),
+                "LT" => Some('<'),
+                "GT" => Some('>'),
+                "LP" => Some('('),
+                "RP" => Some(')'),
+                "C" => Some(','),
+                _ => escape
+                    .strip_prefix('u')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32),
+            };
+            if let Some(c) = c {
+                out.push(c);
+                rest = &rest[end + 2..];
+                continue;
+            }
+        }
+        let c = rest.chars().next().unwrap();
+        let _ = out.write_char(c);
+        rest = &rest[c.len_utf8()..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demangle_legacy() {
+        assert_eq!(
+            demangle("_ZN4core9panicking5panic17h0123456789abcdefE"),
+            "core::panicking::panic"
+        );
+        assert_eq!(
+            demangle("_ZN11perf_kernel5bench5Bench3end28_$u7b$$u7b$closure$u7d$$u7d$17h0123456789abcdefE"),
+            "perf_kernel::bench::Bench::end::{{closure}}"
+        );
+        assert_eq!(demangle("_start"), "_start");
+    }
+
+    #[test]
+    fn lookup_by_address() {
+        let symbols = Symbols {
+            functions: vec![
+                Function {
+                    start: 0x1000,
+                    size: 0x10,
+                    name: "a".into(),
+                },
+                Function {
+                    start: 0x1010,
+                    size: 0x20,
+                    name: "b".into(),
+                },
+            ],
+        };
+        assert_eq!(symbols.lookup(0x100f).unwrap().name, "a");
+        assert_eq!(symbols.lookup(0x1010).unwrap().name, "b");
+        assert!(symbols.lookup(0x1030).is_none());
+        assert!(symbols.lookup(0xfff).is_none());
+        assert_eq!(symbols.range(), Some((0x1000, 0x1030)));
+    }
+}
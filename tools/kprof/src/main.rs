@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+mod elf;
+mod perf;
+mod pprof;
+mod stream;
+
+/*
+ * kprof converts the samples the kernel writes to its serial console
+ * into `perf script` text and pprof profiles, symbolized with the
+ * kernel ELF image.
+ */
+
+const USAGE: &str = "\
+Usage: kprof [OPTIONS] <serial log>
+
+Reads the sample records in a serial log of the kernel, '-' reads stdin.
+
+Options:
+    -k, --kernel <elf>    kernel image with symbols, defaults to the newest of
+                          target/x86_64-os/{debug,release}/perf_kernel
+    --perf <file>         write `perf script` text, '-' for stdout
+    --pprof <file>        write a pprof profile
+    -h, --help            print this help
+
+Without --perf and --pprof the `perf script` text goes to stdout.";
+
+struct Args {
+    log: PathBuf,
+    kernel: Option<PathBuf>,
+    perf: Option<PathBuf>,
+    pprof: Option<PathBuf>,
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    process::exit(2);
+}
+
+fn parse_args() -> Args {
+    let mut args = Args {
+        log: PathBuf::new(),
+        kernel: None,
+        perf: None,
+        pprof: None,
+    };
+    let mut log = None;
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        let mut value = || {
+            iter.next()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| usage_error(&format!("{} needs a value", arg)))
+        };
+        match arg.as_str() {
+            "-k" | "--kernel" => args.kernel = Some(value()),
+            "--perf" => args.perf = Some(value()),
+            "--pprof" => args.pprof = Some(value()),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') && arg != "-" => {
+                usage_error(&format!("Unknown option {}", arg))
+            }
+            _ if log.is_none() => log = Some(PathBuf::from(arg)),
+            _ => usage_error("Only one serial log can be read"),
+        }
+    }
+    args.log = log.unwrap_or_else(|| usage_error("Missing serial log"));
+    if args.perf.is_none() && args.pprof.is_none() {
+        args.perf = Some(PathBuf::from("-"));
+    }
+    args
+}
+
+/// Newest kernel build in target/ of the current or the kernel directory
+fn find_kernel() -> Option<PathBuf> {
+    ["target", "kernel/target"]
+        .iter()
+        .flat_map(|target| {
+            ["debug", "release"].iter().map(move |profile| {
+                Path::new(target)
+                    .join("x86_64-os")
+                    .join(profile)
+                    .join("perf_kernel")
+            })
+        })
+        .filter_map(|path| Some((path.metadata().ok()?.modified().ok()?, path)))
+        .max()
+        .map(|(_, path)| path)
+}
+
+fn create(path: &Path) -> io::Result<Box<dyn Write>> {
+    if path == Path::new("-") {
+        Ok(Box::new(io::stdout()))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let mut log = Vec::new();
+    if args.log == Path::new("-") {
+        io::stdin().read_to_end(&mut log)
+    } else {
+        File::open(&args.log).and_then(|mut f| f.read_to_end(&mut log))
+    }
+    .map_err(|err| format!("{}: {}", args.log.display(), err))?;
+
+    let kernel = args
+        .kernel
+        .or_else(find_kernel)
+        .ok_or("No kernel found in target/x86_64-os, pass it with --kernel")?;
+    let symbols = elf::Symbols::load(&kernel)?;
+
+    let capture = stream::parse(&log);
+    eprintln!(
+        "{} samples in {} records, {} broken records, {} symbols from {}",
+        capture.samples.len(),
+        capture.records,
+        capture.broken,
+        symbols.len(),
+        kernel.display()
+    );
+
+    if let Some(path) = &args.perf {
+        create(path)
+            .and_then(|mut out| {
+                perf::write_script(&mut out, &capture.samples, &symbols)?;
+                out.flush()
+            })
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+    }
+    if let Some(path) = &args.pprof {
+        let profile = pprof::encode(&capture.samples, &symbols);
+        create(path)
+            .and_then(|mut out| {
+                out.write_all(&profile)?;
+                out.flush()
+            })
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(err) = run(parse_args()) {
+        eprintln!("kprof: {}", err);
+        process::exit(1);
+    }
+}
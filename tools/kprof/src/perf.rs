@@ -0,0 +1,67 @@
+use crate::elf::Symbols;
+use crate::stream::{Event, Sample};
+use std::io::{self, Write};
+
+/*
+ * Output in the format of `perf script`, which stackcollapse-perf.pl,
+ * inferno, speedscope and the Firefox profiler understand:
+ *
+ *   perf_kernel     0/0     [001]     0.000012:          1 cpu-clock:
+ *           ffffffff8000a0f0 perf_kernel::foo+0x10 (perf_kernel)
+ *           ffffffff80001234 kernel_main+0x84 (perf_kernel)
+ *
+ * The kernel does not timestamp its samples, the time column
+ * counts the samples of each core in microseconds instead.
+ * IBS op samples carry their data address after the event name,
+ * like the addr field of perf.
+ */
+
+const COMM: &str = "perf_kernel";
+
+pub fn write_script(out: &mut dyn Write, samples: &[Sample], symbols: &Symbols) -> io::Result<()> {
+    let mut per_core: Vec<u64> = Vec::new();
+    for sample in samples {
+        let core = sample.core as usize;
+        if per_core.len() <= core {
+            per_core.resize(core + 1, 0);
+        }
+        let index = per_core[core];
+        per_core[core] += 1;
+
+        write!(
+            out,
+            "{:>15} {:>7} [{:03}] {:>5}.{:06}: {:>10} {}:",
+            COMM,
+            "0/0",
+            core,
+            index / 1_000_000,
+            index % 1_000_000,
+            1,
+            sample.event.name()
+        )?;
+        if let Event::IbsOp { data_addr, .. } = sample.event {
+            if data_addr != 0 {
+                write!(out, " {:16x}", data_addr)?;
+            }
+        }
+        writeln!(out)?;
+
+        for (i, addr) in sample.frames.iter().enumerate() {
+            // Return addresses point behind the call
+            let lookup = if i == 0 { *addr } else { addr.wrapping_sub(1) };
+            match symbols.lookup(lookup) {
+                Some(function) => writeln!(
+                    out,
+                    "\t{:16x} {}+{:#x} ({})",
+                    addr,
+                    function.name,
+                    addr - function.start,
+                    COMM
+                )?,
+                None => writeln!(out, "\t{:16x} [unknown] ({})", addr, COMM)?,
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,271 @@
+use crate::elf::Symbols;
+use crate::stream::{fetch, op, Event, Sample};
+use std::collections::HashMap;
+
+/*
+ * pprof profile, the uncompressed protobuf of
+ * https://github.com/google/pprof/blob/master/proto/profile.proto
+ * `pprof` reads it with and without gzip.
+ * Every kind of sample adds its own sample types, the values of
+ * the other kinds stay zero. IBS op samples count cache misses and
+ * latencies, `pprof -sample_index=dc_misses` shows the instructions
+ * that miss the data cache.
+ */
+
+/// Sample types of every kind, the first one counts the samples
+const STACK_TYPES: &[(&str, &str)] = &[("samples", "count")];
+const IBS_OP_TYPES: &[(&str, &str)] = &[
+    ("ibs_ops", "count"),
+    ("dc_misses", "count"),
+    ("l2_misses", "count"),
+    ("miss_latency", "cycles"),
+    ("tag_to_retire", "cycles"),
+];
+const IBS_FETCH_TYPES: &[(&str, &str)] = &[
+    ("ibs_fetches", "count"),
+    ("ic_misses", "count"),
+    ("fetch_latency", "cycles"),
+];
+
+const MAPPING_ID: u64 = 1;
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Appends a varint field, zero is the default and gets left out
+fn uint(out: &mut Vec<u8>, field: u64, value: u64) {
+    if value != 0 {
+        varint(out, field << 3);
+        varint(out, value);
+    }
+}
+
+/// Appends a length delimited field
+fn bytes(out: &mut Vec<u8>, field: u64, data: &[u8]) {
+    varint(out, field << 3 | 2);
+    varint(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+fn packed(out: &mut Vec<u8>, field: u64, values: &[u64]) {
+    let mut data = Vec::new();
+    values.iter().for_each(|v| varint(&mut data, *v));
+    bytes(out, field, &data);
+}
+
+#[derive(Default)]
+struct Builder {
+    profile: Vec<u8>,
+    strings: HashMap<String, u64>,
+    string_table: Vec<String>,
+    /// Function ids by start address
+    functions: HashMap<u64, u64>,
+    /// Location ids by address
+    locations: HashMap<u64, u64>,
+    /// Addresses covered by the mapping of the kernel
+    range: Option<(u64, u64)>,
+}
+
+impl Builder {
+    fn string(&mut self, s: &str) -> u64 {
+        if let Some(id) = self.strings.get(s) {
+            return *id;
+        }
+        let id = self.string_table.len() as u64;
+        self.strings.insert(s.to_string(), id);
+        self.string_table.push(s.to_string());
+        id
+    }
+
+    fn function(&mut self, symbols: &Symbols, addr: u64) -> Option<u64> {
+        let function = symbols.lookup(addr)?;
+        if let Some(id) = self.functions.get(&function.start) {
+            return Some(*id);
+        }
+        let id = self.functions.len() as u64 + 1;
+        self.functions.insert(function.start, id);
+
+        let name = self.string(&function.name);
+        let mut msg = Vec::new();
+        uint(&mut msg, 1, id);
+        uint(&mut msg, 2, name);
+        uint(&mut msg, 3, name);
+        bytes(&mut self.profile, 5, &msg);
+        Some(id)
+    }
+
+    fn location(&mut self, symbols: &Symbols, addr: u64) -> u64 {
+        if let Some(id) = self.locations.get(&addr) {
+            return *id;
+        }
+        let id = self.locations.len() as u64 + 1;
+        self.locations.insert(addr, id);
+
+        let function = self.function(symbols, addr);
+        let mut msg = Vec::new();
+        uint(&mut msg, 1, id);
+        if symbols
+            .range()
+            .map_or(false, |(start, end)| start <= addr && addr < end)
+        {
+            uint(&mut msg, 2, MAPPING_ID);
+        }
+        uint(&mut msg, 3, addr);
+        if let Some(function) = function {
+            let mut line = Vec::new();
+            uint(&mut line, 1, function);
+            bytes(&mut msg, 4, &line);
+        }
+        bytes(&mut self.profile, 4, &msg);
+        id
+    }
+
+    fn value_type(&mut self, field: u64, (kind, unit): (&str, &str)) {
+        let mut msg = Vec::new();
+        uint(&mut msg, 1, self.string(kind));
+        uint(&mut msg, 2, self.string(unit));
+        bytes(&mut self.profile, field, &msg);
+    }
+
+    fn label(&mut self, msg: &mut Vec<u8>, key: &str, text: Option<&str>, num: u64) {
+        let mut label = Vec::new();
+        uint(&mut label, 1, self.string(key));
+        if let Some(text) = text {
+            uint(&mut label, 2, self.string(text));
+        }
+        uint(&mut label, 3, num);
+        bytes(msg, 3, &label);
+    }
+}
+
+/// Values of a sample for its own sample types
+fn values(event: &Event) -> Vec<u64> {
+    let bit = |flags: u16, flag: u16| (flags & flag != 0) as u64;
+    match *event {
+        Event::Stack => vec![1],
+        Event::IbsOp {
+            tag_to_retire,
+            miss_latency,
+            flags,
+            ..
+        } => vec![
+            1,
+            bit(flags, op::DC_MISS),
+            bit(flags, op::L2_MISS),
+            miss_latency as u64,
+            tag_to_retire as u64,
+        ],
+        Event::IbsFetch { latency, flags } => vec![1, bit(flags, fetch::IC_MISS), latency as u64],
+    }
+}
+
+pub fn encode(samples: &[Sample], symbols: &Symbols) -> Vec<u8> {
+    let mut builder = Builder {
+        range: symbols.range(),
+        ..Builder::default()
+    };
+    builder.string("");
+
+    // Sample types of the kinds in the capture and where their values start
+    let has = |f: fn(&Event) -> bool| samples.iter().any(|s| f(&s.event));
+    let kinds = [
+        (has(|e| matches!(e, Event::Stack)), STACK_TYPES),
+        (has(|e| matches!(e, Event::IbsOp { .. })), IBS_OP_TYPES),
+        (
+            has(|e| matches!(e, Event::IbsFetch { .. })),
+            IBS_FETCH_TYPES,
+        ),
+    ];
+    let mut offsets = [0; 3];
+    let mut num_values = 0;
+    for (i, (present, types)) in kinds.iter().enumerate() {
+        offsets[i] = num_values;
+        if *present {
+            types.iter().for_each(|t| builder.value_type(1, *t));
+            num_values += types.len();
+        }
+    }
+
+    for sample in samples {
+        let offset = match sample.event {
+            Event::Stack => offsets[0],
+            Event::IbsOp { .. } => offsets[1],
+            Event::IbsFetch { .. } => offsets[2],
+        };
+        let mut sample_values = vec![0; num_values];
+        for (i, value) in values(&sample.event).into_iter().enumerate() {
+            sample_values[offset + i] = value;
+        }
+
+        // Return addresses point behind the call
+        let locations: Vec<u64> = sample
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| if i == 0 { *addr } else { addr.wrapping_sub(1) })
+            .map(|addr| builder.location(symbols, addr))
+            .collect();
+
+        let mut msg = Vec::new();
+        packed(&mut msg, 1, &locations);
+        packed(&mut msg, 2, &sample_values);
+        builder.label(&mut msg, "core", None, sample.core as u64);
+        if let Event::IbsOp {
+            data_addr, flags, ..
+        } = sample.event
+        {
+            if data_addr != 0 {
+                builder.label(&mut msg, "data_addr", None, data_addr);
+            }
+            if flags & op::LOAD != 0 {
+                builder.label(&mut msg, "access", Some("load"), 0);
+            } else if flags & op::STORE != 0 {
+                builder.label(&mut msg, "access", Some("store"), 0);
+            }
+        }
+        bytes(&mut builder.profile, 2, &msg);
+    }
+
+    if let Some((start, end)) = builder.range {
+        let mut mapping = Vec::new();
+        uint(&mut mapping, 1, MAPPING_ID);
+        uint(&mut mapping, 2, start);
+        uint(&mut mapping, 3, end);
+        uint(&mut mapping, 5, builder.string("perf_kernel"));
+        uint(&mut mapping, 7, 1);
+        bytes(&mut builder.profile, 3, &mapping);
+    }
+
+    let mut profile = std::mem::take(&mut builder.profile);
+    for s in &builder.string_table {
+        bytes(&mut profile, 6, s.as_bytes());
+    }
+    profile
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_encoding() {
+        let mut out = Vec::new();
+        varint(&mut out, 1);
+        varint(&mut out, 300);
+        varint(&mut out, u64::MAX);
+        assert_eq!(out[..3], [0x01, 0xac, 0x02]);
+        assert_eq!(out.len(), 3 + 10);
+        assert_eq!(out[12], 0x01);
+
+        let mut out = Vec::new();
+        uint(&mut out, 1, 0);
+        assert!(out.is_empty());
+        packed(&mut out, 2, &[1, 300]);
+        assert_eq!(out, [0x12, 3, 0x01, 0xac, 0x02]);
+    }
+}
@@ -0,0 +1,253 @@
+use std::convert::TryInto;
+
+/*
+ * Reader of the binary sample stream of the kernel,
+ * see kernel/src/profile/stream.rs for the format.
+ * The stream is embedded in the serial log, everything that
+ * is not a valid record gets skipped.
+ */
+
+pub const MAGIC: [u8; 4] = [0xff, b'P', b'K', b'S'];
+pub const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 16;
+
+const KIND_IBS_OP: u8 = 1;
+const KIND_IBS_FETCH: u8 = 2;
+const KIND_STACK: u8 = 3;
+
+/// Flags of an IBS op sample, same as ibs::OpSample in the kernel
+pub mod op {
+    pub const LOAD: u16 = 1 << 0;
+    pub const STORE: u16 = 1 << 1;
+    pub const DC_MISS: u16 = 1 << 2;
+    pub const L2_MISS: u16 = 1 << 3;
+}
+
+/// Flags of an IBS fetch sample, same as ibs::FetchSample in the kernel
+pub mod fetch {
+    pub const IC_MISS: u16 = 1 << 1;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Timer interrupt sample
+    Stack,
+    IbsOp {
+        data_addr: u64,
+        tag_to_retire: u16,
+        miss_latency: u16,
+        flags: u16,
+        data_src: u8,
+    },
+    IbsFetch {
+        latency: u16,
+        flags: u16,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Stack => "cpu-clock",
+            Event::IbsOp { .. } => "ibs_op",
+            Event::IbsFetch { .. } => "ibs_fetch",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub core: u16,
+    /// Innermost first, IBS samples only have the sampled rip
+    pub frames: Vec<u64>,
+    pub event: Event,
+}
+
+#[derive(Debug, Default)]
+pub struct Capture {
+    pub samples: Vec<Sample>,
+    pub records: usize,
+    /// Records with a bad checksum, unknown version or kind, or cut off
+    pub broken: usize,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Splits the body of a record into its samples
+fn split_samples(body: &[u8], count: usize, size: usize) -> Option<(Vec<&[u8]>, usize)> {
+    let mut samples = Vec::with_capacity(count);
+    let mut offset = 0;
+    for _ in 0..count {
+        let (start, len) = if size != 0 {
+            (offset, size)
+        } else {
+            if offset + 2 > body.len() {
+                return None;
+            }
+            (offset + 2, u16_at(body, offset) as usize)
+        };
+        if start + len > body.len() {
+            return None;
+        }
+        samples.push(&body[start..start + len]);
+        offset = start + len;
+    }
+    Some((samples, offset))
+}
+
+fn decode(kind: u8, core: u16, bytes: &[u8]) -> Option<Sample> {
+    let (frames, event) = match kind {
+        KIND_IBS_OP if bytes.len() >= 23 => (
+            vec![u64_at(bytes, 0)],
+            Event::IbsOp {
+                data_addr: u64_at(bytes, 8),
+                tag_to_retire: u16_at(bytes, 16),
+                miss_latency: u16_at(bytes, 18),
+                flags: u16_at(bytes, 20),
+                data_src: bytes[22],
+            },
+        ),
+        KIND_IBS_FETCH if bytes.len() >= 12 => (
+            vec![u64_at(bytes, 0)],
+            Event::IbsFetch {
+                latency: u16_at(bytes, 8),
+                flags: u16_at(bytes, 10),
+            },
+        ),
+        KIND_STACK if bytes.len() % 8 == 0 => (
+            (0..bytes.len())
+                .step_by(8)
+                .map(|i| u64_at(bytes, i))
+                .collect(),
+            Event::Stack,
+        ),
+        _ => return None,
+    };
+    Some(Sample {
+        core,
+        frames,
+        event,
+    })
+}
+
+/// Parses the record starting at `bytes[0]`, returns its
+/// samples and length or None if it is broken
+fn parse_record(bytes: &[u8]) -> Option<(Vec<Sample>, usize)> {
+    if bytes.len() < HEADER_SIZE || bytes[4] != VERSION {
+        return None;
+    }
+    let kind = bytes[5];
+    let core = u16_at(bytes, 6);
+    let count = u32_at(bytes, 8) as usize;
+    let size = u16_at(bytes, 12) as usize;
+
+    let body = &bytes[HEADER_SIZE..];
+    let (raw, len) = split_samples(body, count, size)?;
+    if len + 4 > body.len() {
+        return None;
+    }
+    let checksum = body[..len]
+        .iter()
+        .fold(0u32, |sum, byte| sum.wrapping_add(*byte as u32));
+    if checksum != u32_at(body, len) {
+        return None;
+    }
+
+    let samples = raw
+        .into_iter()
+        .map(|bytes| decode(kind, core, bytes))
+        .collect::<Option<Vec<_>>>()?;
+    Some((samples, HEADER_SIZE + len + 4))
+}
+
+/// Extracts all records from a serial log
+pub fn parse(bytes: &[u8]) -> Capture {
+    let mut capture = Capture::default();
+    let mut offset = 0;
+    while let Some(found) = bytes[offset..].windows(4).position(|w| w == MAGIC) {
+        let start = offset + found;
+        match parse_record(&bytes[start..]) {
+            Some((samples, len)) => {
+                capture.records += 1;
+                capture.samples.extend(samples);
+                offset = start + len;
+            }
+            None => {
+                capture.broken += 1;
+                offset = start + 1;
+            }
+        }
+    }
+    capture
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(kind: u8, size: u16, samples: &[&[u8]]) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&[VERSION, kind, 3, 0]);
+        out.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&[0, 0]);
+        let start = out.len();
+        for sample in samples {
+            if size == 0 {
+                out.extend_from_slice(&(sample.len() as u16).to_le_bytes());
+            }
+            out.extend_from_slice(sample);
+        }
+        let checksum = out[start..].iter().map(|b| *b as u32).sum::<u32>();
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn records_between_log_lines() {
+        let mut op = vec![0; 24];
+        op[..8].copy_from_slice(&0xffff_8000_0000_1000u64.to_le_bytes());
+        op[8..16].copy_from_slice(&0x2000u64.to_le_bytes());
+        op[20] = op::LOAD as u8 | op::DC_MISS as u8;
+        let stack: Vec<u8> = [0x10u64, 0x20]
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect();
+
+        let mut log = b"[INFO] booting\n".to_vec();
+        log.extend(record(KIND_IBS_OP, 24, &[&op]));
+        log.extend_from_slice(b"\nsome text\n");
+        let mut broken = record(KIND_STACK, 0, &[&stack]);
+        *broken.last_mut().unwrap() ^= 1;
+        log.extend(broken);
+        log.extend(record(KIND_STACK, 0, &[&stack, &stack[..8]]));
+
+        let capture = parse(&log);
+        assert_eq!(capture.records, 2);
+        assert_eq!(capture.broken, 1);
+        assert_eq!(capture.samples.len(), 3);
+        assert_eq!(capture.samples[0].frames, vec![0xffff_8000_0000_1000]);
+        assert_eq!(capture.samples[0].core, 3);
+        match capture.samples[0].event {
+            Event::IbsOp {
+                data_addr, flags, ..
+            } => {
+                assert_eq!(data_addr, 0x2000);
+                assert_eq!(flags, op::LOAD | op::DC_MISS);
+            }
+            _ => panic!("not an op sample"),
+        }
+        assert_eq!(capture.samples[1].frames, vec![0x10, 0x20]);
+        assert_eq!(capture.samples[2].frames, vec![0x10]);
+    }
+}